@@ -10,45 +10,49 @@ window.addEventListener('load', function() {
     });
   }
 
-  var RANKER_API_URL =
-    'https://api.ranker.com/lists/298553/items/85372114?include=votes,crowdRankedStats';
-  var ITEMS_PER_PAGE = 25;
+  // The stats are collected by website-backend, so the page doesn't depend on
+  // the uptime and the CORS headers of the Ranker API.
+  var LATEST_STATS_URL = 'http://localhost:8080/ranker/latest.json';
 
-  function percent(value) {
-    return (value * 100).toFixed(2);
+  function formatNumber(value) {
+    return value != null ? String(value) : '?';
+  }
+
+  function formatPercent(value) {
+    return value != null ? value.toFixed(2) : '?';
   }
 
   showSection('loading');
-  fetch(RANKER_API_URL)
+  fetch(LATEST_STATS_URL)
     .then(function(response) {
+      if (!response.ok) {
+        throw new Error('HTTP ' + response.status);
+      }
       return response.json();
     })
     .then(function(json) {
-      console.log('API response', json);
+      console.log('latest stats', json);
       showSection('stats');
 
-      var rank = json.rank;
-      var page = Math.ceil(rank / ITEMS_PER_PAGE);
-      getById('rank').textContent = rank;
-      getById('page').textContent = page;
+      getById('rank').textContent = json.data.rank;
+      getById('page').textContent = json.page;
       getById('pageLink').href =
         'https://www.ranker.com/crowdranked-list/the-best-movies-of-all-time?page=' +
-        page;
-
-      var upvotes = json.votes.upVotes;
-      var downvotes = json.votes.downVotes;
-      var allVotes = upvotes + downvotes;
-      getById('upvotes').textContent = upvotes;
-      getById('upvotesPercent').textContent = percent(upvotes / allVotes);
-      getById('downvotes').textContent = downvotes;
-      getById('downvotesPercent').textContent = percent(downvotes / allVotes);
-
-      var top5Reranks = json.crowdRankedStats.top5ListCount;
-      var reranks = json.crowdRankedStats.totalContributingListCount;
-      getById('top5Reranks').textContent = top5Reranks;
-      getById('reranks').textContent = reranks;
-      getById('top5ReranksPercent').textContent = percent(
-        top5Reranks / reranks,
+        json.page;
+
+      getById('upvotes').textContent = formatNumber(json.data.upvotes);
+      getById('upvotesPercent').textContent = formatPercent(
+        json.upvotes_percent,
+      );
+      getById('downvotes').textContent = formatNumber(json.data.downvotes);
+      getById('downvotesPercent').textContent = formatPercent(
+        json.downvotes_percent,
+      );
+
+      getById('top5Reranks').textContent = formatNumber(json.data.top5_reranks);
+      getById('reranks').textContent = formatNumber(json.data.reranks);
+      getById('top5ReranksPercent').textContent = formatPercent(
+        json.top5_reranks_percent,
       );
     })
     .catch(function(error) {
@@ -0,0 +1,268 @@
+use std::iter::Peekable;
+
+use crate::record::{Record, Timestamp};
+
+const MINUTE: i64 = 60;
+const HOUR: i64 = 60 * MINUTE;
+const DAY: i64 = 24 * HOUR;
+const WEEK: i64 = 7 * DAY;
+/// 1970-01-05 was the first Monday after the UNIX epoch.
+const FIRST_MONDAY: i64 = 4 * DAY;
+
+/// Duration of the buckets which records are grouped into, written as a
+/// number followed by a unit, e.g. `30m`, `1h`, `1d` or `1w`.
+#[derive(Debug, Clone, Copy)]
+pub struct BucketSize {
+  secs: i64,
+}
+
+impl BucketSize {
+  pub fn parse(s: &str) -> Option<Self> {
+    if s.len() < 2 {
+      return None;
+    }
+    let (count, unit) = s.split_at(s.len() - 1);
+    let count: i64 = count.parse().ok().filter(|&count| count > 0)?;
+    let unit_secs = match unit {
+      "m" => MINUTE,
+      "h" => HOUR,
+      "d" => DAY,
+      "w" => WEEK,
+      _ => return None,
+    };
+    Some(Self { secs: count.checked_mul(unit_secs)? })
+  }
+
+  /// Buckets are aligned to the UNIX epoch, except the ones measured in weeks,
+  /// which start on Mondays.
+  pub fn bucket_start(self, timestamp: &Timestamp) -> i64 {
+    let offset = if self.secs % WEEK == 0 { FIRST_MONDAY } else { 0 };
+    (timestamp.as_secs() - offset).div_euclid(self.secs) * self.secs + offset
+  }
+
+  /// Start of the bucket following the one which contains the timestamp.
+  pub fn bucket_end(self, timestamp: &Timestamp) -> i64 {
+    self.bucket_start(timestamp) + self.secs
+  }
+}
+
+pub struct Bucket {
+  pub start: Timestamp,
+  /// Number of records with data.
+  pub samples: u64,
+  /// Number of gap records.
+  pub gaps: u64,
+  /// Aggregates of every column, `None` if the column had no values.
+  pub columns: Vec<Option<Aggregate>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Aggregate {
+  pub min: u64,
+  pub max: u64,
+  pub first: u64,
+  pub last: u64,
+  sum: u128,
+  count: u64,
+}
+
+impl Aggregate {
+  fn new(value: u64) -> Self {
+    Self {
+      min: value,
+      max: value,
+      first: value,
+      last: value,
+      sum: u128::from(value),
+      count: 1,
+    }
+  }
+
+  fn push(&mut self, value: u64, reversed: bool) {
+    self.min = self.min.min(value);
+    self.max = self.max.max(value);
+    if reversed {
+      self.first = value;
+    } else {
+      self.last = value;
+    }
+    self.sum += u128::from(value);
+    self.count += 1;
+  }
+
+  pub fn mean(&self) -> f64 {
+    self.sum as f64 / self.count as f64
+  }
+}
+
+/// Groups consecutive records into buckets. `values` extracts values of the
+/// aggregated columns from a data point. `reversed` must be set if the records
+/// are iterated from the newest to the oldest, the buckets are returned in the
+/// same order as the records.
+pub fn aggregate_buckets<'a, T, I, F>(
+  records: I,
+  size: BucketSize,
+  reversed: bool,
+  values: F,
+) -> Buckets<'a, T, I, F>
+where
+  T: 'a,
+  I: Iterator<Item = &'a Record<T>>,
+  F: FnMut(&T, &mut Vec<Option<u64>>),
+{
+  Buckets {
+    records: records.peekable(),
+    size,
+    reversed,
+    values,
+    values_buf: vec![],
+  }
+}
+
+pub struct Buckets<'a, T: 'a, I: Iterator<Item = &'a Record<T>>, F> {
+  records: Peekable<I>,
+  size: BucketSize,
+  reversed: bool,
+  values: F,
+  values_buf: Vec<Option<u64>>,
+}
+
+impl<'a, T, I, F> Iterator for Buckets<'a, T, I, F>
+where
+  T: 'a,
+  I: Iterator<Item = &'a Record<T>>,
+  F: FnMut(&T, &mut Vec<Option<u64>>),
+{
+  type Item = Bucket;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    let start = self.size.bucket_start(&self.records.peek()?.timestamp);
+    let mut bucket = Bucket {
+      start: Timestamp::new(start),
+      samples: 0,
+      gaps: 0,
+      columns: vec![],
+    };
+
+    while let Some(record) = self.records.peek() {
+      if self.size.bucket_start(&record.timestamp) != start {
+        break;
+      }
+
+      match record.data() {
+        Some(data) => {
+          self.values_buf.clear();
+          (self.values)(data, &mut self.values_buf);
+          if bucket.columns.len() < self.values_buf.len() {
+            bucket.columns.resize(self.values_buf.len(), None);
+          }
+
+          for (column, value) in bucket.columns.iter_mut().zip(&self.values_buf)
+          {
+            if let Some(value) = *value {
+              match column {
+                Some(aggregate) => aggregate.push(value, self.reversed),
+                None => *column = Some(Aggregate::new(value)),
+              }
+            }
+          }
+          bucket.samples += 1;
+        }
+        None => bucket.gaps += 1,
+      }
+
+      self.records.next();
+    }
+
+    Some(bucket)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::record::{Gap, RecordValue};
+
+  #[test]
+  fn bucket_sizes_are_parsed() {
+    let secs = |s| BucketSize::parse(s).map(|size| size.secs);
+    assert_eq!(secs("30m"), Some(30 * MINUTE));
+    assert_eq!(secs("1h"), Some(HOUR));
+    assert_eq!(secs("7d"), Some(WEEK));
+    assert_eq!(secs("2w"), Some(2 * WEEK));
+    for invalid in &["", "h", "0h", "-1h", "1", "1s", "1.5h", "99999999999999w"]
+    {
+      assert_eq!(secs(invalid), None, "{}", invalid);
+    }
+  }
+
+  #[test]
+  fn buckets_are_aligned() {
+    let bucket = |size, timestamp| {
+      let size = BucketSize::parse(size).unwrap();
+      let timestamp = Timestamp::new(timestamp);
+      (size.bucket_start(&timestamp), size.bucket_end(&timestamp))
+    };
+    assert_eq!(bucket("1h", 0), (0, HOUR));
+    assert_eq!(bucket("1h", HOUR - 1), (0, HOUR));
+    assert_eq!(bucket("1h", HOUR), (HOUR, 2 * HOUR));
+    assert_eq!(bucket("15m", -1), (-15 * MINUTE, 0));
+    assert_eq!(bucket("1d", 1_560_434_400), (1_560_384_000, 1_560_470_400));
+
+    // 2019-06-13 was a Thursday, the week started on 2019-06-10
+    let monday = 1_560_124_800;
+    assert_eq!(bucket("1w", 1_560_434_400), (monday, monday + WEEK));
+    assert_eq!(bucket("1w", monday), (monday, monday + WEEK));
+    assert_eq!(bucket("1w", monday - 1), (monday - WEEK, monday));
+    assert_eq!(bucket("7d", monday - 1), (monday - WEEK, monday));
+    // the epoch was on a Thursday too
+    assert_eq!(bucket("1w", 0), (FIRST_MONDAY - WEEK, FIRST_MONDAY));
+    assert_eq!(bucket("2w", monday).0 % (2 * WEEK), FIRST_MONDAY);
+  }
+
+  #[test]
+  fn records_are_aggregated_in_both_directions() {
+    let data = |timestamp, values: &[Option<u64>]| Record {
+      timestamp: Timestamp::new(timestamp),
+      value: RecordValue::Data(values.to_vec()),
+    };
+    let gap = |timestamp| Record {
+      timestamp: Timestamp::new(timestamp),
+      value: RecordValue::Gap(Gap { reason: "down".to_owned() }),
+    };
+    let records = [
+      data(0, &[Some(4)]),
+      data(600, &[Some(1), Some(7)]),
+      gap(1200),
+      data(1800, &[Some(6), None]),
+      data(HOUR, &[None, Some(3)]),
+      gap(HOUR + 1),
+    ];
+    let size = BucketSize::parse("1h").unwrap();
+    let values = |data: &Vec<Option<u64>>, values: &mut Vec<Option<u64>>| {
+      values.extend(data)
+    };
+    // start, samples, gaps and min, max, first, last and mean of the columns
+    type Summary = (i64, u64, u64, Vec<Option<(u64, u64, u64, u64, f64)>>);
+    let summarize = |bucket: Bucket| -> Summary {
+      let columns = bucket.columns.iter().map(|column| {
+        column.map(|a| (a.min, a.max, a.first, a.last, a.mean()))
+      });
+      (bucket.start.as_secs(), bucket.samples, bucket.gaps, columns.collect())
+    };
+
+    let first_hour =
+      (0, 3, 1, vec![Some((1, 6, 4, 6, 11.0 / 3.0)), Some((7, 7, 7, 7, 7.0))]);
+    let second_hour = (HOUR, 1, 1, vec![None, Some((3, 3, 3, 3, 3.0))]);
+    let forward: Vec<Summary> =
+      aggregate_buckets(records.iter(), size, false, values)
+        .map(summarize)
+        .collect();
+    assert_eq!(forward, vec![first_hour.clone(), second_hour.clone()]);
+    let backward: Vec<Summary> =
+      aggregate_buckets(records.iter().rev(), size, true, values)
+        .map(summarize)
+        .collect();
+    assert_eq!(backward, vec![second_hour, first_hour]);
+  }
+}
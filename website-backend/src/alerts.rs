@@ -0,0 +1,638 @@
+use failure::{Fallible, ResultExt};
+use log::info;
+
+use futures::future::Shared;
+use futures::sync::mpsc;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::prelude::*;
+
+use hyper::Uri;
+use serde::Serialize;
+
+use crate::config::{AlertRule, WebhookConfig};
+use crate::database::Database;
+use crate::http::{self, HttpClient};
+use crate::record::{Record, Timestamp};
+use crate::shutdown::Shutdown;
+use crate::trackers::{with_retries, Columns, Miss, RetryPolicy};
+
+/// Sent to the webhooks as the body of the request.
+#[derive(Debug, Serialize)]
+pub struct Alert {
+  pub tracker: String,
+  pub rule: &'static str,
+  pub column: String,
+  pub timestamp: i64,
+  pub value: u64,
+  /// Data point which the value was compared to.
+  pub previous_timestamp: i64,
+  pub previous_value: u64,
+  pub message: String,
+}
+
+pub type AlertSender = mpsc::UnboundedSender<Alert>;
+pub type AlertReceiver = mpsc::UnboundedReceiver<Alert>;
+
+/// Rules of a single tracker.
+pub struct AlertRules {
+  pub tracker: String,
+  pub rules: Arc<Vec<AlertRule>>,
+  pub sender: AlertSender,
+}
+
+impl AlertRules {
+  /// Checks the rules against a new record, which is about to be pushed into
+  /// `db`, and returns the alerts which have fired.
+  pub fn check<T: Columns>(
+    &self,
+    db: &Database<T>,
+    record: &Record<T>,
+  ) -> Fallible<Vec<Alert>> {
+    if self.rules.is_empty() {
+      return Ok(vec![]);
+    }
+    let previous_records =
+      preceding_records(&self.rules, record, db.scan_back(None))?;
+    Ok(
+      self
+        .rules
+        .iter()
+        .filter_map(|rule| {
+          check_rule(rule, &self.tracker, &previous_records, record)
+        })
+        .collect(),
+    )
+  }
+
+  pub fn send(&self, alerts: Vec<Alert>) {
+    for alert in alerts {
+      info!("alert: {}", alert.message);
+      // the receiver is dropped only during the shutdown
+      let _ = self.sender.unbounded_send(alert);
+    }
+  }
+}
+
+/// Reads the records preceding `record` which the rules depend on, i.e. the
+/// last ones with values of the columns and the ones within the windows of
+/// the rules, so that the whole database isn't read for every record. Rules
+/// on the columns which `record` doesn't have a value of are skipped anyway.
+/// Returns the records in chronological order.
+fn preceding_records<T, I>(
+  rules: &[AlertRule],
+  record: &Record<T>,
+  scan: I,
+) -> Fallible<Vec<Record<T>>>
+where
+  T: Columns,
+  I: Iterator<Item = Fallible<Record<T>>>,
+{
+  let timestamp = record.timestamp.as_secs();
+  let mut pending: Vec<(&str, i64)> = rules
+    .iter()
+    .map(|rule| match rule {
+      AlertRule::Changed { column } | AlertRule::PageChanged { column, .. } => {
+        (column.as_str(), 0)
+      }
+      AlertRule::Increased { column, within, .. } => {
+        (column.as_str(), within.as_secs() as i64)
+      }
+    })
+    .filter(|&(column, _)| column_value(record, column).is_some())
+    .collect();
+  // records at least this new are within the windows
+  let mut oldest = pending
+    .iter()
+    .map(|&(_, within)| timestamp - within)
+    .min()
+    .unwrap_or(timestamp);
+
+  let mut records = vec![];
+  for previous in scan {
+    let previous = previous?;
+    let previous_timestamp = previous.timestamp.as_secs();
+    // the window of the `increased` rule is checked for the previous value
+    // too
+    pending.retain(|&(column, within)| {
+      if column_value(&previous, column).is_none() {
+        return true;
+      }
+      oldest = oldest.min(previous_timestamp - within);
+      false
+    });
+    if pending.is_empty() && previous_timestamp < oldest {
+      break;
+    }
+    records.push(previous);
+  }
+  records.reverse();
+  Ok(records)
+}
+
+/// Value of a column of a record, `None` for gaps and missing values.
+fn column_value<T: Columns>(record: &Record<T>, column: &str) -> Option<u64> {
+  let mut values = Vec::with_capacity(1);
+  record.data()?.column_values(&[column.to_owned()], &mut values);
+  values[0]
+}
+
+fn check_rule<T: Columns>(
+  rule: &AlertRule,
+  tracker: &str,
+  previous_records: &[Record<T>],
+  record: &Record<T>,
+) -> Option<Alert> {
+  let column = rule.column();
+  let rule_name = match rule {
+    AlertRule::Changed { .. } => "changed",
+    AlertRule::PageChanged { .. } => "pageChanged",
+    AlertRule::Increased { .. } => "increased",
+  };
+  let value = column_value(record, column)?;
+  let timestamp = record.timestamp.as_secs();
+
+  let ((previous_timestamp, previous_value), message) = match rule {
+    AlertRule::Changed { .. } => {
+      let previous = last_value(previous_records, column)?;
+      if previous.1 == value {
+        return None;
+      }
+      let message = format!(
+        "{} of '{}' changed from {} to {}",
+        column, tracker, previous.1, value
+      );
+      (previous, message)
+    }
+
+    AlertRule::PageChanged { page_size, .. } => {
+      let previous = last_value(previous_records, column)?;
+      let page = value.div_ceil(page_size.get());
+      let previous_page = previous.1.div_ceil(page_size.get());
+      if previous_page == page {
+        return None;
+      }
+      let message = format!(
+        "{} of '{}' moved from page {} to page {}",
+        column, tracker, previous_page, page
+      );
+      (previous, message)
+    }
+
+    AlertRule::Increased { by, within, .. } => {
+      let within = within.as_secs() as i64;
+      let minimum = window_minimum(previous_records, column, timestamp, within)
+        .filter(|&(_, minimum)| value.saturating_sub(minimum) > *by)?;
+
+      // the alert is sent once, when the increase exceeds the threshold
+      let previous_end = previous_records
+        .iter()
+        .rposition(|record| column_value(record, column).is_some());
+      if let Some(previous_end) = previous_end {
+        let previous_record = &previous_records[previous_end];
+        let previous_value = column_value(previous_record, column).unwrap();
+        let previous_minimum = window_minimum(
+          &previous_records[..previous_end],
+          column,
+          previous_record.timestamp.as_secs(),
+          within,
+        );
+        if previous_minimum.is_some_and(|(_, previous_minimum)| {
+          previous_value.saturating_sub(previous_minimum) > *by
+        }) {
+          return None;
+        }
+      }
+
+      let message = format!(
+        "{} of '{}' increased by {} within {} seconds",
+        column,
+        tracker,
+        value - minimum.1,
+        timestamp - minimum.0
+      );
+      (minimum, message)
+    }
+  };
+
+  Some(Alert {
+    tracker: tracker.to_owned(),
+    rule: rule_name,
+    column: column.to_owned(),
+    timestamp,
+    value,
+    previous_timestamp,
+    previous_value,
+    message,
+  })
+}
+
+/// Timestamp and value of the last record which has a value of the column.
+fn last_value<T: Columns>(
+  records: &[Record<T>],
+  column: &str,
+) -> Option<(i64, u64)> {
+  records.iter().rev().find_map(|record| {
+    Some((record.timestamp.as_secs(), column_value(record, column)?))
+  })
+}
+
+/// The smallest value of the column within `within` seconds before
+/// `timestamp`, the earliest one if there are several.
+fn window_minimum<T: Columns>(
+  records: &[Record<T>],
+  column: &str,
+  timestamp: i64,
+  within: i64,
+) -> Option<(i64, u64)> {
+  records
+    .iter()
+    .rev()
+    .take_while(|record| record.timestamp.as_secs() >= timestamp - within)
+    .filter_map(|record| {
+      Some((record.timestamp.as_secs(), column_value(record, column)?))
+    })
+    .min_by_key(|&(timestamp, value)| (value, timestamp))
+}
+
+/// File with an entry for every delivery of an alert to a webhook, one JSON
+/// object per line.
+pub struct DeliveryLog {
+  file: Mutex<File>,
+}
+
+#[derive(Serialize)]
+struct Delivery<'a> {
+  timestamp: i64,
+  url: String,
+  status: DeliveryStatus,
+  attempts: u32,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  error: Option<String>,
+  alert: &'a Alert,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+enum DeliveryStatus {
+  Delivered,
+  /// All attempts have failed.
+  Failed,
+  /// The server was shut down before the delivery has finished.
+  Cancelled,
+}
+
+impl DeliveryLog {
+  pub fn open(path: &Path) -> Fallible<Self> {
+    info!("opening file '{}'", path.display());
+    let file = OpenOptions::new()
+      .append(true)
+      .create(true)
+      .open(path)
+      .context("failed to open file")?;
+    Ok(Self { file: Mutex::new(file) })
+  }
+
+  fn append(&self, delivery: &Delivery) -> Fallible<()> {
+    let mut line = serde_json::to_vec(delivery)?;
+    line.push(b'\n');
+    // the whole line is written at once, so that the entries of concurrent
+    // deliveries aren't interleaved
+    self.file.lock().unwrap().write_all(&line)?;
+    Ok(())
+  }
+}
+
+struct Webhook {
+  url: Uri,
+  retry_policy: RetryPolicy,
+}
+
+/// Delivers the alerts sent by the trackers to every webhook.
+pub fn start(
+  alerts: AlertReceiver,
+  webhooks: Vec<WebhookConfig>,
+  delivery_log: DeliveryLog,
+  http_client: HttpClient,
+  shutdown: Shutdown,
+) -> impl Future<Item = (), Error = ()> {
+  let webhooks: Vec<Arc<Webhook>> = webhooks
+    .into_iter()
+    .map(|config| {
+      Arc::new(Webhook {
+        url: config.url,
+        retry_policy: RetryPolicy {
+          timeout: config.timeout,
+          retries: config.retries,
+          retry_delay: config.retry_delay,
+        },
+      })
+    })
+    .collect();
+  let delivery_log = Arc::new(delivery_log);
+  let shutdown = shutdown.shared();
+  let deliveries_shutdown = shutdown.clone();
+
+  alerts
+    .for_each(move |alert| {
+      let alert = Arc::new(alert);
+      for webhook in &webhooks {
+        tokio::spawn(deliver(
+          alert.clone(),
+          webhook.clone(),
+          delivery_log.clone(),
+          http_client.clone(),
+          deliveries_shutdown.clone(),
+        ));
+      }
+      Ok(())
+    })
+    .select(shutdown.then(|_| Ok(())))
+    .then(|_| Ok(()))
+}
+
+fn deliver(
+  alert: Arc<Alert>,
+  webhook: Arc<Webhook>,
+  delivery_log: Arc<DeliveryLog>,
+  http_client: HttpClient,
+  shutdown: Shared<Shutdown>,
+) -> impl Future<Item = (), Error = ()> {
+  let json = serde_json::to_vec(&*alert).unwrap();
+  let url = webhook.url.clone();
+  let attempts = Arc::new(AtomicU32::new(0));
+  let attempts_counter = attempts.clone();
+  let delivery = with_retries(webhook.retry_policy, move || {
+    attempts_counter.fetch_add(1, Ordering::SeqCst);
+    http::post_json(&http_client, url.clone(), json.clone())
+  });
+
+  delivery.select2(shutdown).then(move |r| {
+    let mut delivery = Delivery {
+      timestamp: Timestamp::now().as_secs(),
+      url: webhook.url.to_string(),
+      status: DeliveryStatus::Delivered,
+      attempts: attempts.load(Ordering::SeqCst),
+      error: None,
+      alert: &alert,
+    };
+    match r {
+      Ok(future::Either::A(_)) => {}
+      Err(future::Either::A((Miss { error, .. }, _))) => {
+        let causes: Vec<String> =
+          error.iter_chain().map(|cause| cause.to_string()).collect();
+        delivery.status = DeliveryStatus::Failed;
+        delivery.error = Some(causes.join(": "));
+        let context =
+          format!("failed to deliver an alert to '{}'", webhook.url);
+        log_error!(log::Level::Warn, &error.context(context));
+      }
+      Ok(future::Either::B(_)) | Err(future::Either::B(_)) => {
+        delivery.status = DeliveryStatus::Cancelled;
+      }
+    }
+
+    delivery_log.append(&delivery).map_err(|e| {
+      log_error!(log::Level::Error, &e.context("failed to log the delivery"))
+    })
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::config::HttpConfig;
+  use crate::record::RecordValue;
+  use crate::trackers::ranker::DataPoint;
+  use hyper::{Body, Response, Server, StatusCode};
+  use std::net::SocketAddr;
+  use std::num::NonZeroU64;
+  use std::path::PathBuf;
+  use std::time::Duration;
+  use tokio::runtime::Runtime;
+
+  fn record(timestamp: i64, rank: u64, upvotes: u64) -> Record<DataPoint> {
+    Record {
+      timestamp: Timestamp::new(timestamp),
+      value: RecordValue::Data(DataPoint {
+        rank,
+        upvotes: Some(upvotes),
+        downvotes: None,
+        reranks: None,
+        top5_reranks: None,
+      }),
+    }
+  }
+
+  /// Checks the rule after pushing every record and returns the messages of
+  /// the alerts.
+  fn fired(rule: AlertRule, records: &[Record<DataPoint>]) -> Vec<String> {
+    (0..records.len())
+      .filter_map(|i| check_rule(&rule, "ranker", &records[..i], &records[i]))
+      .map(|alert| alert.message)
+      .collect()
+  }
+
+  #[test]
+  fn rules_fire_on_changes() {
+    let records = vec![
+      record(0, 24, 100),
+      record(600, 24, 150),
+      record(1200, 25, 250),
+      record(1800, 26, 300),
+      record(2400, 26, 400),
+      record(6000, 26, 410),
+    ];
+
+    assert_eq!(
+      fired(AlertRule::Changed { column: "rank".to_owned() }, &records),
+      vec![
+        "rank of 'ranker' changed from 24 to 25",
+        "rank of 'ranker' changed from 25 to 26"
+      ],
+    );
+    assert_eq!(
+      fired(
+        AlertRule::PageChanged {
+          column: "rank".to_owned(),
+          page_size: NonZeroU64::new(25).unwrap(),
+        },
+        &records
+      ),
+      vec!["rank of 'ranker' moved from page 1 to page 2"],
+    );
+    assert_eq!(
+      fired(
+        AlertRule::Increased {
+          column: "upvotes".to_owned(),
+          by: 100,
+          within: Duration::from_secs(3600),
+        },
+        &records
+      ),
+      vec!["upvotes of 'ranker' increased by 150 within 1200 seconds"],
+    );
+  }
+
+  #[test]
+  fn gaps_dont_fire_alerts() {
+    let gap = Record {
+      timestamp: Timestamp::new(600),
+      value: RecordValue::Gap(crate::record::Gap { reason: String::new() }),
+    };
+    let records = vec![record(0, 1, 0), gap, record(1200, 2, 0)];
+    assert_eq!(
+      fired(AlertRule::Changed { column: "rank".to_owned() }, &records),
+      vec!["rank of 'ranker' changed from 1 to 2"],
+    );
+  }
+
+  #[test]
+  fn only_needed_records_are_read() {
+    let gap = |timestamp| Record {
+      timestamp: Timestamp::new(timestamp),
+      value: RecordValue::Gap(crate::record::Gap { reason: String::new() }),
+    };
+    let history = [
+      record(0, 5, 0),
+      record(300, 4, 10),
+      record(600, 3, 20),
+      gap(900),
+      record(1200, 3, 30),
+      gap(1500),
+    ];
+    let read = |rules: &[AlertRule]| -> Vec<i64> {
+      let scan = history.iter().rev().map(|record| Ok(record.clone()));
+      preceding_records(rules, &record(1800, 2, 40), scan)
+        .unwrap()
+        .iter()
+        .map(|record| record.timestamp.as_secs())
+        .collect()
+    };
+
+    let changed = AlertRule::Changed { column: "rank".to_owned() };
+    assert_eq!(read(&[]), Vec::<i64>::new());
+    assert_eq!(read(&[changed]), vec![1200, 1500]);
+    let increased = AlertRule::Increased {
+      column: "upvotes".to_owned(),
+      by: 10,
+      within: Duration::from_secs(600),
+    };
+    // the window of the previous value starts at 600
+    assert_eq!(read(&[increased]), vec![600, 900, 1200, 1500]);
+  }
+
+  /// Starts a local HTTP server which collects the bodies of the requests. It
+  /// responds with an error to the first `failures` requests.
+  fn start_sink(
+    runtime: &mut Runtime,
+    failures: usize,
+  ) -> (SocketAddr, Arc<Mutex<Vec<serde_json::Value>>>) {
+    let bodies = Arc::new(Mutex::new(vec![]));
+    let sink_bodies = bodies.clone();
+    let server =
+      Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(move || {
+        let bodies = sink_bodies.clone();
+        hyper::service::service_fn(move |req: hyper::Request<Body>| {
+          let bodies = bodies.clone();
+          req.into_body().concat2().map(move |body| {
+            let mut bodies = bodies.lock().unwrap();
+            bodies.push(serde_json::from_slice(&body).unwrap());
+            let mut res = Response::new(Body::empty());
+            if bodies.len() <= failures {
+              *res.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            }
+            res
+          })
+        })
+      });
+    let address = server.local_addr();
+    runtime.spawn(server.map_err(|_| ()));
+    (address, bodies)
+  }
+
+  fn temp_log_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!(
+      "alerts-{}-{}.jsonl",
+      name,
+      std::process::id()
+    ));
+    let _ = std::fs::remove_file(&path);
+    path
+  }
+
+  fn deliver_to_sink(
+    failures: usize,
+    retries: u32,
+  ) -> (Vec<serde_json::Value>, serde_json::Value) {
+    let mut runtime = Runtime::new().unwrap();
+    let (address, bodies) = start_sink(&mut runtime, failures);
+
+    let log_path = temp_log_path(&format!("{}-{}", failures, retries));
+    let webhook = Arc::new(Webhook {
+      url: format!("http://{}/alerts", address).parse().unwrap(),
+      retry_policy: RetryPolicy {
+        timeout: Duration::from_secs(5),
+        retries,
+        retry_delay: Duration::from_millis(0),
+      },
+    });
+    let alert = check_rule(
+      &AlertRule::Changed { column: "rank".to_owned() },
+      "ranker",
+      &[record(0, 1, 0)],
+      &record(600, 2, 0),
+    )
+    .unwrap();
+
+    let shutdown = Shutdown::new();
+    runtime
+      .block_on(deliver(
+        Arc::new(alert),
+        webhook,
+        Arc::new(DeliveryLog::open(&log_path).unwrap()),
+        http::new_client(&HttpConfig::default()).unwrap(),
+        shutdown.another().shared(),
+      ))
+      .unwrap();
+
+    let log = std::fs::read_to_string(&log_path).unwrap();
+    std::fs::remove_file(&log_path).unwrap();
+    let mut lines = log.lines();
+    let entry = serde_json::from_str(lines.next().unwrap()).unwrap();
+    assert_eq!(lines.next(), None);
+
+    let bodies = bodies.lock().unwrap().clone();
+    (bodies, entry)
+  }
+
+  #[test]
+  fn delivery_is_retried_and_logged() {
+    let (bodies, entry) = deliver_to_sink(2, 3);
+    assert_eq!(bodies.len(), 3);
+    assert_eq!(bodies[0], bodies[2]);
+    assert_eq!(bodies[0]["tracker"], "ranker");
+    assert_eq!(bodies[0]["rule"], "changed");
+    assert_eq!(bodies[0]["previous_value"], 1);
+    assert_eq!(bodies[0]["value"], 2);
+
+    assert_eq!(entry["status"], "delivered");
+    assert_eq!(entry["attempts"], 3);
+    assert_eq!(entry["alert"], bodies[0]);
+  }
+
+  #[test]
+  fn failed_delivery_is_logged() {
+    let (bodies, entry) = deliver_to_sink(5, 1);
+    assert_eq!(bodies.len(), 2);
+    assert_eq!(entry["status"], "failed");
+    assert_eq!(entry["attempts"], 2);
+    assert_eq!(
+      entry["error"],
+      "unsuccessful response status: 500 Internal Server Error"
+    );
+  }
+}
@@ -0,0 +1,511 @@
+//! Compact encoding of the records of a database. The file starts with a
+//! header, which is followed by a block for every record:
+//!
+//! ```text
+//! [payload length: u32 LE] [CRC-32 of the payload: u32 LE] [payload]
+//! ```
+//!
+//! Records are encoded relatively to the previous one, so the blocks can only
+//! be decoded in order. The payload consists of:
+//!
+//! 1. the kind of the record, see the `KIND_*` constants;
+//! 2. the difference with the previous timestamp;
+//! 3. for gaps, the reason;
+//! 4. for data points with a different shape (i.e. set of column names) than
+//!    the previous data point, the number of columns and their names;
+//! 5. for data points, a bitmap of the columns which have values, followed by
+//!    the differences of the values with the ones in the previous data point.
+//!
+//! Integers are encoded as LEB128 varints, the signed ones are zigzag-encoded
+//! first. Strings are prefixed with their length in bytes.
+
+use failure::{Fallible, ResultExt};
+use std::io::{BufRead, Read};
+
+use crate::record::{Gap, Record, RecordValue, Timestamp};
+use crate::trackers::Columns;
+
+/// Magic bytes followed by the version of the format.
+pub const HEADER: &[u8] = b"ALTB\x01";
+const BLOCK_HEADER_LEN: usize = 8;
+
+const KIND_GAP: u8 = 0;
+const KIND_DATA: u8 = 1;
+const KIND_DATA_WITH_SHAPE: u8 = 2;
+
+/// The state which the next record is encoded relatively to.
+#[derive(Debug, Clone, Default)]
+pub struct Encoder {
+  timestamp: i64,
+  names: Vec<String>,
+  values: Vec<Option<u64>>,
+}
+
+impl Encoder {
+  /// Appends a block with the record to `out`.
+  pub fn encode<T: Columns>(&mut self, record: &Record<T>, out: &mut Vec<u8>) {
+    let block_start = out.len();
+    out.extend_from_slice(&[0; BLOCK_HEADER_LEN]);
+
+    let timestamp = record.timestamp.as_secs();
+    let kind = match &record.value {
+      RecordValue::Gap(_) => KIND_GAP,
+      RecordValue::Data(data) => {
+        let names = data.column_names();
+        if names == self.names {
+          KIND_DATA
+        } else {
+          self.names = names;
+          self.values = vec![None; self.names.len()];
+          KIND_DATA_WITH_SHAPE
+        }
+      }
+    };
+    out.push(kind);
+    write_signed(out, timestamp.wrapping_sub(self.timestamp));
+    self.timestamp = timestamp;
+
+    match &record.value {
+      RecordValue::Gap(gap) => write_string(out, &gap.reason),
+      RecordValue::Data(data) => {
+        if kind == KIND_DATA_WITH_SHAPE {
+          write_unsigned(out, self.names.len() as u64);
+          for name in &self.names {
+            write_string(out, name);
+          }
+        }
+
+        let mut values = Vec::with_capacity(self.names.len());
+        data.column_values(&self.names, &mut values);
+
+        let bitmap_start = out.len();
+        out.resize(bitmap_start + values.len().div_ceil(8), 0);
+        for (index, value) in values.iter().enumerate() {
+          if value.is_some() {
+            out[bitmap_start + index / 8] |= 1 << (index % 8);
+          }
+        }
+
+        for (value, previous) in values.iter().zip(&self.values) {
+          if let Some(value) = value {
+            let delta = value.wrapping_sub(previous.unwrap_or(0)) as i64;
+            write_signed(out, delta);
+          }
+        }
+        self.values = values;
+      }
+    }
+
+    let payload_len = (out.len() - block_start - BLOCK_HEADER_LEN) as u32;
+    let checksum = crc32fast::hash(&out[block_start + BLOCK_HEADER_LEN..]);
+    out[block_start..block_start + 4]
+      .copy_from_slice(&payload_len.to_le_bytes());
+    out[block_start + 4..block_start + 8]
+      .copy_from_slice(&checksum.to_le_bytes());
+  }
+
+  fn decode<T: Columns>(&mut self, payload: &[u8]) -> Fallible<Record<T>> {
+    let mut reader = Reader(payload);
+
+    let kind = reader.byte()?;
+    let timestamp = self.timestamp.wrapping_add(reader.signed()?);
+    self.timestamp = timestamp;
+    let timestamp = Timestamp::new(timestamp);
+
+    let value = match kind {
+      KIND_GAP => RecordValue::Gap(Gap { reason: reader.string()? }),
+
+      KIND_DATA | KIND_DATA_WITH_SHAPE => {
+        if kind == KIND_DATA_WITH_SHAPE {
+          let len = reader.unsigned()? as usize;
+          self.names =
+            (0..len).map(|_| reader.string()).collect::<Fallible<_>>()?;
+          self.values = vec![None; len];
+        }
+
+        let bitmap = reader.bytes(self.names.len().div_ceil(8))?;
+        for (index, value) in self.values.iter_mut().enumerate() {
+          *value = if bitmap[index / 8] & (1 << (index % 8)) != 0 {
+            let delta = reader.signed()? as u64;
+            Some(value.unwrap_or(0).wrapping_add(delta))
+          } else {
+            None
+          };
+        }
+
+        let data = T::from_column_values(&self.names, &self.values)
+          .ok_or_else(|| failure::err_msg("invalid data point"))?;
+        RecordValue::Data(data)
+      }
+
+      _ => return Err(failure::format_err!("unknown record kind: {}", kind)),
+    };
+
+    if !reader.0.is_empty() {
+      return Err(failure::err_msg("trailing data after the record"));
+    }
+    Ok(Record { timestamp, value })
+  }
+}
+
+pub enum Block<T> {
+  Record(Record<T>),
+  /// The rest of the file is a partially written block, which happens if the
+  /// writing of the last block has been interrupted.
+  Torn(String),
+}
+
+/// Reads the blocks one by one, so the file doesn't have to be loaded into
+/// memory at once.
+pub struct Decoder<R> {
+  reader: R,
+  encoder: Encoder,
+  offset: u64,
+}
+
+impl<R: BufRead> Decoder<R> {
+  /// Starts at the header of the file.
+  pub fn new(reader: R) -> Self {
+    Self::resume(reader, 0, Encoder::default())
+  }
+
+  /// Starts at the block at `offset`, which has been reached with the state
+  /// of `encoder`.
+  pub fn resume(reader: R, offset: u64, encoder: Encoder) -> Self {
+    Self { reader, encoder, offset }
+  }
+
+  /// Offset of the next block, i.e. the length of the decoded blocks.
+  pub fn offset(&self) -> u64 {
+    self.offset
+  }
+
+  /// State which the next block is decoded relatively to.
+  pub fn encoder(&self) -> &Encoder {
+    &self.encoder
+  }
+
+  /// Returns `None` at the end of the file. Only the last block may be
+  /// invalid, either at the end of the file or followed only by zeros, the
+  /// ones before it are treated as errors.
+  pub fn next_block<T: Columns>(&mut self) -> Fallible<Option<Block<T>>> {
+    if self.offset == 0 {
+      let header = self.read_up_to(HEADER.len())?;
+      if header.is_empty() {
+        return Ok(None);
+      }
+      if header != HEADER {
+        if HEADER.starts_with(&header) {
+          return Ok(Some(Block::Torn("incomplete header".to_owned())));
+        }
+        return Err(failure::err_msg("invalid header"));
+      }
+      self.offset = HEADER.len() as u64;
+    }
+
+    let block_header = self.read_up_to(BLOCK_HEADER_LEN)?;
+    if block_header.is_empty() {
+      return Ok(None);
+    }
+    if block_header.len() < BLOCK_HEADER_LEN {
+      return Ok(Some(Block::Torn("incomplete block header".to_owned())));
+    }
+    // payloads start with the kind of the record, so an empty block can only
+    // come from the zeros which a file system may leave in place of the data
+    // appended right before a crash
+    if block_header.iter().all(|&byte| byte == 0) {
+      if self.rest_is_zeroed()? {
+        return Ok(Some(Block::Torn("zero-filled tail".to_owned())));
+      }
+      return Err(failure::format_err!(
+        "empty block at offset {}",
+        self.offset
+      ));
+    }
+
+    let mut len = [0; 4];
+    len.copy_from_slice(&block_header[..4]);
+    let len = u32::from_le_bytes(len) as usize;
+    let mut checksum = [0; 4];
+    checksum.copy_from_slice(&block_header[4..8]);
+    let checksum = u32::from_le_bytes(checksum);
+
+    let payload = self.read_up_to(len)?;
+    if payload.len() < len {
+      return Ok(Some(Block::Torn("incomplete block".to_owned())));
+    }
+    if crc32fast::hash(&payload) != checksum {
+      if self.rest_is_zeroed()? {
+        return Ok(Some(Block::Torn("checksum mismatch".to_owned())));
+      }
+      return Err(failure::format_err!(
+        "checksum mismatch in the block at offset {}",
+        self.offset
+      ));
+    }
+
+    let record = self.encoder.decode(&payload).with_context(|_| {
+      format!("failed to decode the block at offset {}", self.offset)
+    })?;
+    self.offset += (BLOCK_HEADER_LEN + len) as u64;
+    Ok(Some(Block::Record(record)))
+  }
+
+  /// Whether the rest of the file consists of zeros, i.e. the last block is
+  /// followed only by a zero-filled tail, if anything. Consumes the rest.
+  fn rest_is_zeroed(&mut self) -> Fallible<bool> {
+    loop {
+      let bytes = self.reader.fill_buf()?;
+      if bytes.is_empty() {
+        return Ok(true);
+      }
+      if bytes.iter().any(|&byte| byte != 0) {
+        return Ok(false);
+      }
+      let len = bytes.len();
+      self.reader.consume(len);
+    }
+  }
+
+  /// The length is read from the file, so the buffer grows only as much as
+  /// the file actually contains.
+  fn read_up_to(&mut self, len: usize) -> Fallible<Vec<u8>> {
+    let mut bytes = vec![];
+    (&mut self.reader).take(len as u64).read_to_end(&mut bytes)?;
+    Ok(bytes)
+  }
+}
+
+fn write_unsigned(out: &mut Vec<u8>, mut value: u64) {
+  while value >= 0x80 {
+    out.push(value as u8 | 0x80);
+    value >>= 7;
+  }
+  out.push(value as u8);
+}
+
+fn write_signed(out: &mut Vec<u8>, value: i64) {
+  write_unsigned(out, ((value << 1) ^ (value >> 63)) as u64);
+}
+
+fn write_string(out: &mut Vec<u8>, s: &str) {
+  write_unsigned(out, s.len() as u64);
+  out.extend_from_slice(s.as_bytes());
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+  fn bytes(&mut self, len: usize) -> Fallible<&'a [u8]> {
+    if self.0.len() < len {
+      return Err(failure::err_msg("unexpected end of the block"));
+    }
+    let (bytes, rest) = self.0.split_at(len);
+    self.0 = rest;
+    Ok(bytes)
+  }
+
+  fn byte(&mut self) -> Fallible<u8> {
+    Ok(self.bytes(1)?[0])
+  }
+
+  fn unsigned(&mut self) -> Fallible<u64> {
+    let mut value: u64 = 0;
+    for shift in (0..64).step_by(7) {
+      let byte = self.byte()?;
+      value |= u64::from(byte & 0x7F) << shift;
+      if byte & 0x80 == 0 {
+        return Ok(value);
+      }
+    }
+    Err(failure::err_msg("varint is too long"))
+  }
+
+  fn signed(&mut self) -> Fallible<i64> {
+    let value = self.unsigned()?;
+    Ok((value >> 1) as i64 ^ -((value & 1) as i64))
+  }
+
+  fn string(&mut self) -> Fallible<String> {
+    let len = self.unsigned()? as usize;
+    let bytes = self.bytes(len)?;
+    Ok(String::from_utf8(bytes.to_vec())?)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::trackers::{ranker, reddit};
+  use std::fmt::Debug;
+
+  struct Decoded<T> {
+    records: Vec<Record<T>>,
+    len: usize,
+    torn_block: Option<String>,
+  }
+
+  fn decode<T: Columns>(bytes: &[u8]) -> Fallible<Decoded<T>> {
+    let mut decoder = Decoder::new(bytes);
+    let mut records = vec![];
+    let mut torn_block = None;
+    while let Some(block) = decoder.next_block()? {
+      match block {
+        Block::Record(record) => records.push(record),
+        Block::Torn(reason) => {
+          torn_block = Some(reason);
+          break;
+        }
+      }
+    }
+    let len = decoder.offset() as usize;
+    Ok(Decoded { records, len, torn_block })
+  }
+
+  fn encode_all<T: Columns>(records: &[Record<T>]) -> Vec<u8> {
+    let mut encoder = Encoder::default();
+    let mut bytes = HEADER.to_vec();
+    for record in records {
+      encoder.encode(record, &mut bytes);
+    }
+    bytes
+  }
+
+  fn assert_round_trip<T: Columns + Debug + Eq>(records: Vec<Record<T>>) {
+    let bytes = encode_all(&records);
+    let decoded = decode::<T>(&bytes).unwrap();
+    assert!(decoded.torn_block.is_none());
+    assert_eq!(decoded.len, bytes.len());
+    assert_eq!(decoded.records.len(), records.len());
+    for (decoded, record) in decoded.records.iter().zip(&records) {
+      assert_eq!(decoded.timestamp.as_secs(), record.timestamp.as_secs());
+      assert_eq!(decoded.value, record.value);
+    }
+  }
+
+  fn ranker_record(timestamp: i64, rank: u64) -> Record<ranker::DataPoint> {
+    Record {
+      timestamp: Timestamp::new(timestamp),
+      value: RecordValue::Data(ranker::DataPoint {
+        rank,
+        upvotes: Some(1000 + timestamp as u64 / 300),
+        downvotes: Some(20),
+        reranks: None,
+        top5_reranks: Some(3),
+      }),
+    }
+  }
+
+  fn gap(timestamp: i64) -> Record<ranker::DataPoint> {
+    Record {
+      timestamp: Timestamp::new(timestamp),
+      value: RecordValue::Gap(Gap { reason: "API is down".to_owned() }),
+    }
+  }
+
+  #[test]
+  fn ranker_records_round_trip() {
+    assert_round_trip(vec![
+      gap(1_500_000_000),
+      ranker_record(1_500_000_300, 30),
+      ranker_record(1_500_000_600, 24),
+      gap(1_500_000_900),
+      ranker_record(1_500_001_200, 27),
+      ranker_record(1_500_001_100, 0),
+    ]);
+  }
+
+  #[test]
+  fn reddit_records_with_changing_shape_round_trip() {
+    let stats = |name: &str, subscribers| reddit::SubredditStats {
+      name: name.to_owned(),
+      subscribers,
+      accounts_active: subscribers / 100,
+    };
+    let record = |timestamp, stats| Record {
+      timestamp: Timestamp::new(timestamp),
+      value: RecordValue::Data(reddit::DataPoint(stats)),
+    };
+    assert_round_trip(vec![
+      record(600, vec![stats("a", 1000)]),
+      record(1200, vec![stats("a", 1200), stats("b", 50)]),
+      record(1800, vec![stats("a", 900), stats("b", 5000)]),
+      record(2400, vec![stats("b", 5100)]),
+    ]);
+  }
+
+  #[test]
+  fn torn_last_block_is_reported() {
+    let records = vec![ranker_record(300, 1), ranker_record(600, 2)];
+    let bytes = encode_all(&records);
+    let first_block_end = encode_all(&records[..1]).len();
+
+    for len in first_block_end + 1..bytes.len() {
+      let decoded = decode::<ranker::DataPoint>(&bytes[..len]).unwrap();
+      assert_eq!(decoded.records.len(), 1);
+      assert_eq!(decoded.len, first_block_end);
+      assert!(decoded.torn_block.is_some());
+    }
+
+    let mut corrupted = bytes.clone();
+    *corrupted.last_mut().unwrap() ^= 1;
+    let decoded = decode::<ranker::DataPoint>(&corrupted).unwrap();
+    assert_eq!(decoded.torn_block.as_deref(), Some("checksum mismatch"));
+
+    let mut corrupted = bytes.clone();
+    corrupted[first_block_end - 1] ^= 1;
+    assert!(decode::<ranker::DataPoint>(&corrupted).is_err());
+  }
+
+  #[test]
+  fn zero_filled_tail_is_reported_as_torn() {
+    let records = vec![ranker_record(300, 1), ranker_record(600, 2)];
+    let bytes = encode_all(&records);
+    let first_block_end = encode_all(&records[..1]).len();
+
+    let mut zeroed = bytes.clone();
+    zeroed.resize(bytes.len() + 4096, 0);
+    let decoded = decode::<ranker::DataPoint>(&zeroed).unwrap();
+    assert_eq!(decoded.records.len(), 2);
+    assert_eq!(decoded.len, bytes.len());
+    assert_eq!(decoded.torn_block.as_deref(), Some("zero-filled tail"));
+
+    // the second block was appended, but its payload wasn't written
+    let mut zeroed = bytes.clone();
+    for byte in &mut zeroed[first_block_end + BLOCK_HEADER_LEN..] {
+      *byte = 0;
+    }
+    zeroed.resize(bytes.len() + 100, 0);
+    let decoded = decode::<ranker::DataPoint>(&zeroed).unwrap();
+    assert_eq!(decoded.records.len(), 1);
+    assert_eq!(decoded.torn_block.as_deref(), Some("checksum mismatch"));
+
+    // zeros followed by more blocks are corruption, not a torn tail
+    let mut zeroed = bytes[..first_block_end].to_vec();
+    zeroed.extend_from_slice(&[0; BLOCK_HEADER_LEN]);
+    zeroed.extend_from_slice(&bytes[first_block_end..]);
+    assert!(decode::<ranker::DataPoint>(&zeroed).is_err());
+  }
+
+  #[test]
+  fn year_of_records_is_much_smaller_than_json() {
+    let records: Vec<_> = (0..365 * 24 * 12)
+      .map(|index| {
+        ranker_record(
+          1_500_000_000 + index * 300,
+          20 + index as u64 / 1000 % 10,
+        )
+      })
+      .collect();
+    let json_len: usize = records
+      .iter()
+      .map(|record| serde_json::to_string(record).unwrap().len() + 1)
+      .sum();
+    let bytes = encode_all(&records);
+    assert!(bytes.len() * 5 < json_len, "{} vs {}", bytes.len(), json_len);
+    assert_eq!(
+      decode::<ranker::DataPoint>(&bytes).unwrap().records.len(),
+      records.len()
+    );
+  }
+}
@@ -0,0 +1,150 @@
+use futures::sync::mpsc;
+use futures::{Async, Poll, Stream};
+use log::info;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+/// Number of records which can wait for a subscriber, a subscriber which
+/// falls further behind is disconnected.
+const SUBSCRIBER_BUFFER: usize = 100;
+
+/// A record serialized in the same way as in the database file.
+#[derive(Debug)]
+pub struct PushedRecord {
+  pub timestamp: i64,
+  pub json: String,
+}
+
+/// Delivers records pushed into a database to the subscribers of live
+/// updates. Subscribers which have gone away or which don't keep up with the
+/// pushed records are removed on the next push, their streams end after the
+/// buffered records, so they can subscribe again with a backfill.
+#[derive(Debug, Clone, Default)]
+pub struct Broadcast {
+  subscribers: Arc<Mutex<Vec<mpsc::Sender<Arc<PushedRecord>>>>>,
+}
+
+impl Broadcast {
+  pub fn subscribe(&self) -> mpsc::Receiver<Arc<PushedRecord>> {
+    let (sender, receiver) = mpsc::channel(SUBSCRIBER_BUFFER);
+    self.subscribers.lock().unwrap().push(sender);
+    receiver
+  }
+
+  pub fn send(&self, record: PushedRecord) {
+    let mut subscribers = self.subscribers.lock().unwrap();
+    if subscribers.is_empty() {
+      return;
+    }
+
+    let record = Arc::new(record);
+    subscribers.retain_mut(|subscriber| {
+      match subscriber.try_send(record.clone()) {
+        Ok(()) => true,
+        Err(error) => {
+          if error.is_full() {
+            info!("disconnecting a subscriber which fell behind");
+          }
+          false
+        }
+      }
+    });
+  }
+}
+
+/// Records missed by a subscriber followed by the live ones. Live records
+/// which have already been sent with the backfill are skipped, so the
+/// subscription must be made before the backfill is read from the database.
+pub struct Subscription {
+  backfill: VecDeque<PushedRecord>,
+  last_backfilled: Option<i64>,
+  records: mpsc::Receiver<Arc<PushedRecord>>,
+}
+
+impl Subscription {
+  pub fn new(
+    records: mpsc::Receiver<Arc<PushedRecord>>,
+    backfill: Vec<PushedRecord>,
+  ) -> Self {
+    Self {
+      last_backfilled: backfill.last().map(|record| record.timestamp),
+      backfill: backfill.into(),
+      records,
+    }
+  }
+}
+
+impl Stream for Subscription {
+  type Item = Arc<PushedRecord>;
+  type Error = ();
+
+  fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+    if let Some(record) = self.backfill.pop_front() {
+      return Ok(Async::Ready(Some(Arc::new(record))));
+    }
+
+    loop {
+      match self.records.poll()? {
+        Async::Ready(Some(record)) => {
+          if self.last_backfilled.is_none_or(|t| record.timestamp > t) {
+            return Ok(Async::Ready(Some(record)));
+          }
+        }
+        other => return Ok(other),
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use futures::Future;
+
+  fn record(timestamp: i64) -> PushedRecord {
+    PushedRecord { timestamp, json: timestamp.to_string() }
+  }
+
+  #[test]
+  fn lagging_subscribers_are_disconnected() {
+    let broadcast = Broadcast::default();
+    let lagging = broadcast.subscribe();
+    let mut keeping_up = broadcast.subscribe().wait();
+
+    let count = SUBSCRIBER_BUFFER as i64 * 2;
+    for timestamp in 0..count {
+      broadcast.send(record(timestamp));
+      let received = keeping_up.next().unwrap().unwrap();
+      assert_eq!(received.timestamp, timestamp);
+    }
+    assert_eq!(broadcast.subscribers.lock().unwrap().len(), 1);
+    drop(broadcast);
+
+    // the buffered records are still received before the stream ends
+    let received = lagging.collect().wait().unwrap();
+    let received: Vec<i64> =
+      received.iter().map(|record| record.timestamp).collect();
+    assert!(received.len() > SUBSCRIBER_BUFFER);
+    assert!(received.len() < count as usize);
+    assert_eq!(received, (0..received.len() as i64).collect::<Vec<_>>());
+    assert!(keeping_up.next().is_none());
+  }
+
+  #[test]
+  fn live_records_follow_the_backfill() {
+    let broadcast = Broadcast::default();
+    let records = broadcast.subscribe();
+    // records pushed while the backfill is read are sent only once
+    for timestamp in 2..5 {
+      broadcast.send(record(timestamp));
+    }
+    let subscription = Subscription::new(records, vec![record(1), record(3)]);
+    broadcast.send(record(5));
+    drop(broadcast);
+
+    let received = subscription.collect().wait().unwrap();
+    let received: Vec<i64> =
+      received.iter().map(|record| record.timestamp).collect();
+    assert_eq!(received, vec![1, 3, 4, 5]);
+  }
+}
@@ -1,46 +1,319 @@
+use failure::{Fallible, ResultExt};
 use log::info;
 
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
 
 use std::fs::File;
 use std::io::{self, BufReader};
 use std::path::{Path, PathBuf};
 
-// use hyper::Uri;
-use std::net::SocketAddr;
+use hyper::Uri;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::num::NonZeroU64;
 use std::time::Duration;
 
+use crate::aggregate::BucketSize;
+
+/// Schema of the configuration file, shared with the Node.js backend. See
+/// `config.example.json` for an example.
 #[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
+  pub database: DatabaseConfig,
   pub server: ServerConfig,
-  pub trackers: TrackersConfig,
+  #[serde(default)]
+  pub http: HttpConfig,
+  pub trackers: Vec<TrackerConfig>,
+  /// Endpoints which receive alerts of all trackers, see `AlertRule`.
+  #[serde(default)]
+  pub webhooks: Vec<WebhookConfig>,
 }
 
 impl Config {
-  pub fn read(path: &Path) -> Result<Self, io::Error> {
+  pub fn read(path: &Path) -> Fallible<Self> {
     info!("opening file '{}'", path.display());
-    let file = File::open(path)?;
+    let file = File::open(path).context("failed to open file")?;
     let reader = BufReader::new(file);
-    let config = serde_json::from_reader(reader)?;
+    let mut deserializer = serde_json::Deserializer::from_reader(reader);
+    let config = deserialize(&mut deserializer)?;
+    deserializer.end().context("trailing data after the config")?;
     Ok(config)
   }
 }
 
 #[derive(Deserialize)]
-pub struct ServerConfig {
-  pub address: SocketAddr,
+#[serde(deny_unknown_fields)]
+pub struct DatabaseConfig {
+  /// Directory where databases are stored, tracker databases are put into
+  /// its `trackers` subdirectory.
+  pub dir: PathBuf,
+  #[serde(default)]
+  pub sync: SyncMode,
+  /// Applies only to the databases stored in files, records in SQLite aren't
+  /// kept in memory anyway.
+  #[serde(default, rename = "residentRecords")]
+  pub resident_records: ResidentRecords,
+  /// Interval in seconds between the compactions of the databases of the
+  /// trackers which have retention rules.
+  #[serde(
+    default = "default_compaction_interval",
+    rename = "compactionInterval",
+    deserialize_with = "deserialize_seconds"
+  )]
+  pub compaction_interval: Duration,
+}
+
+/// When the records appended to the databases are flushed to the disk.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum SyncMode {
+  /// After every record, so that nothing is lost on a power cut.
+  #[default]
+  Always,
+  /// After every `n` records, up to `n - 1` of the latest ones may be lost.
+  Batched(u32),
+  /// Only when the OS decides to write the data back.
+  Never,
+}
+
+/// Which records of a database are kept in memory, the other ones are read
+/// from the file when they are requested.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ResidentRecords {
+  #[default]
+  All,
+  /// The newest `n` records, plus up to a few hundred which haven't been
+  /// evicted yet.
+  Newest(usize),
 }
 
 #[derive(Deserialize)]
-pub struct TrackersConfig {
-  pub ranker: TrackerConfig,
+#[serde(deny_unknown_fields)]
+pub struct ServerConfig {
+  pub hostname: String,
+  pub port: u16,
+}
+
+impl ServerConfig {
+  pub fn address(&self) -> io::Result<SocketAddr> {
+    (self.hostname.as_str(), self.port).to_socket_addrs()?.next().ok_or_else(
+      || {
+        io::Error::new(
+          io::ErrorKind::NotFound,
+          format!("hostname '{}' resolved to no addresses", self.hostname),
+        )
+      },
+    )
+  }
+}
+
+/// Settings of the HTTP client used by the trackers.
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct HttpConfig {
+  /// PEM files with additional root certificates to trust, e.g. of a
+  /// self-signed CA.
+  #[serde(default)]
+  pub ca_certificates: Vec<PathBuf>,
 }
 
 #[derive(Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
 pub struct TrackerConfig {
+  /// Name of the tracker type in the registry, see `trackers::TRACKER_TYPES`.
+  #[serde(rename = "type")]
+  pub type_name: String,
+  /// Unique ID of the tracker, also used as the name of its database file.
+  pub id: String,
+  /// Interval between requests in seconds.
   #[serde(deserialize_with = "deserialize_seconds")]
   pub request_interval: Duration,
-  pub database_file: PathBuf,
+  /// Timeout of a single request attempt in seconds.
+  #[serde(
+    default = "default_timeout",
+    deserialize_with = "deserialize_seconds"
+  )]
+  pub timeout: Duration,
+  /// Number of retries after a failed attempt before the tick is given up.
+  #[serde(default = "default_retries")]
+  pub retries: u32,
+  /// Delay before the first retry in seconds, it is doubled after every next
+  /// attempt and randomized to spread out the requests.
+  #[serde(
+    default = "default_retry_delay",
+    deserialize_with = "deserialize_seconds"
+  )]
+  pub retry_delay: Duration,
+  /// Options specific to the tracker type.
+  pub options: serde_json::Value,
+  /// Encoding of the database file of the tracker.
+  #[serde(default)]
+  pub storage: StorageFormat,
+  /// Rules which are checked after every new record of the tracker.
+  #[serde(default)]
+  pub alerts: Vec<AlertRule>,
+  /// How the old records are compacted, see `RetentionRule`. Records which
+  /// none of the rules apply to are kept as they are.
+  #[serde(default)]
+  pub retention: Vec<RetentionRule>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum StorageFormat {
+  /// A JSON object per line, the same as in the Node.js backend.
+  #[default]
+  Json,
+  /// Compact encoding described in the `binary` module.
+  Binary,
+  /// SQLite database with an index on the timestamps, the records aren't kept
+  /// in memory.
+  Sqlite,
+}
+
+impl StorageFormat {
+  pub fn extension(self) -> &'static str {
+    match self {
+      StorageFormat::Json => "json",
+      StorageFormat::Binary => "bin",
+      StorageFormat::Sqlite => "sqlite",
+    }
+  }
+}
+
+/// Condition on a column of the data points of a tracker, an alert is sent to
+/// the webhooks when it becomes true.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase", deny_unknown_fields)]
+pub enum AlertRule {
+  /// The value is different from the one in the previous data point.
+  Changed { column: String },
+  /// The value is on a different page than the one in the previous data
+  /// point, e.g. when the rank of an item moves to another page of a list.
+  PageChanged {
+    #[serde(default = "default_page_column")]
+    column: String,
+    #[serde(default = "default_page_size", rename = "pageSize")]
+    page_size: NonZeroU64,
+  },
+  /// The value has increased by more than `by` within `within` seconds.
+  Increased {
+    column: String,
+    by: u64,
+    #[serde(deserialize_with = "deserialize_seconds")]
+    within: Duration,
+  },
+}
+
+impl AlertRule {
+  pub fn column(&self) -> &str {
+    match self {
+      AlertRule::Changed { column }
+      | AlertRule::PageChanged { column, .. }
+      | AlertRule::Increased { column, .. } => column,
+    }
+  }
+}
+
+/// What happens to the records which are older than `after` seconds, until
+/// a rule with a greater `after` applies to them.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase", deny_unknown_fields)]
+pub enum RetentionRule {
+  /// A single record is kept as a sample of every bucket, the newest one with
+  /// data, or the newest gap if there is no data. The other records of the
+  /// bucket are dropped rather than aggregated, so e.g. the minimum of the
+  /// bucket is lost. Runs of unchanged values are then shortened to their
+  /// first and last records.
+  Sample {
+    #[serde(deserialize_with = "deserialize_seconds")]
+    after: Duration,
+    #[serde(deserialize_with = "deserialize_bucket_size")]
+    bucket: BucketSize,
+  },
+  /// The records are deleted.
+  Delete {
+    #[serde(deserialize_with = "deserialize_seconds")]
+    after: Duration,
+  },
+}
+
+impl RetentionRule {
+  pub fn after(&self) -> Duration {
+    match self {
+      RetentionRule::Sample { after, .. } => *after,
+      RetentionRule::Delete { after } => *after,
+    }
+  }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct WebhookConfig {
+  /// URL which alerts are sent to in `POST` requests with a JSON body.
+  #[serde(deserialize_with = "deserialize_uri")]
+  pub url: Uri,
+  /// Timeout of a single delivery attempt in seconds.
+  #[serde(
+    default = "default_timeout",
+    deserialize_with = "deserialize_seconds"
+  )]
+  pub timeout: Duration,
+  /// Number of retries after a failed attempt before the delivery is given up.
+  #[serde(default = "default_retries")]
+  pub retries: u32,
+  /// Delay before the first retry in seconds, backed off in the same way as
+  /// the retries of the trackers.
+  #[serde(
+    default = "default_retry_delay",
+    deserialize_with = "deserialize_seconds"
+  )]
+  pub retry_delay: Duration,
+}
+
+/// Deserializes a value of the config (or its part) and reports the path to
+/// the key which failed to parse.
+pub fn deserialize<'de, D, T>(deserializer: D) -> Fallible<T>
+where
+  D: serde::Deserializer<'de>,
+  T: Deserialize<'de>,
+{
+  serde_path_to_error::deserialize(deserializer).map_err(|e| {
+    let path = e.path().to_string();
+    failure::format_err!("error at key '{}': {}", path, e.into_inner())
+  })
+}
+
+pub fn from_value<T: DeserializeOwned>(
+  value: serde_json::Value,
+) -> Fallible<T> {
+  deserialize(value)
+}
+
+fn default_timeout() -> Duration {
+  Duration::from_secs(30)
+}
+
+fn default_retries() -> u32 {
+  3
+}
+
+fn default_retry_delay() -> Duration {
+  Duration::from_secs(5)
+}
+
+fn default_compaction_interval() -> Duration {
+  Duration::from_secs(24 * 60 * 60)
+}
+
+fn default_page_column() -> String {
+  "rank".to_owned()
+}
+
+fn default_page_size() -> NonZeroU64 {
+  NonZeroU64::new(crate::trackers::ranker::ITEMS_PER_PAGE).unwrap()
 }
 
 fn deserialize_seconds<'de, D>(deserializer: D) -> Result<Duration, D::Error>
@@ -51,10 +324,25 @@ where
   Ok(Duration::from_secs(secs))
 }
 
-// fn deserialize_uri<'de, D>(deserializer: D) -> Result<Uri, D::Error>
-// where
-//   D: serde::Deserializer<'de>,
-// {
-//   let s = String::deserialize(deserializer)?;
-//   s.parse().map_err(serde::de::Error::custom)
-// }
+fn deserialize_uri<'de, D>(deserializer: D) -> Result<Uri, D::Error>
+where
+  D: serde::Deserializer<'de>,
+{
+  let s = String::deserialize(deserializer)?;
+  s.parse().map_err(serde::de::Error::custom)
+}
+
+fn deserialize_bucket_size<'de, D>(
+  deserializer: D,
+) -> Result<BucketSize, D::Error>
+where
+  D: serde::Deserializer<'de>,
+{
+  let s = String::deserialize(deserializer)?;
+  BucketSize::parse(&s).ok_or_else(|| {
+    serde::de::Error::custom(format!(
+      "invalid bucket size '{}', expected a number followed by m, h, d or w",
+      s
+    ))
+  })
+}
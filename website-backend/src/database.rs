@@ -1,142 +1,350 @@
 use failure::{Fallible, ResultExt};
 use log::info;
 
-use std::fs::{File, OpenOptions};
-use std::io::{BufRead, BufReader, BufWriter, Seek, SeekFrom, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use serde::de::DeserializeOwned;
 use serde::ser::Serialize;
 use std::fmt::Debug;
 
-use crate::record::Record;
+use crate::broadcast::{Broadcast, PushedRecord};
+use crate::config::{ResidentRecords, StorageFormat, SyncMode};
+use crate::record::{Record, Timestamp};
+use crate::storage::{self, Rewrite, Storage};
+use crate::trackers::Columns;
 
-#[derive(Debug)]
+/// Number of records read at once by `ScanBack`.
+const SCAN_BACK_CHUNK: usize = 100;
+
+/// Records of a tracker, pushed ones are broadcast to the subscribers.
 pub struct Database<T> {
-  file: File,
-  records: Vec<Record<T>>,
+  storage: Box<dyn Storage<T>>,
+  broadcast: Broadcast,
+  path: PathBuf,
+  format: StorageFormat,
+  sync_mode: SyncMode,
+  resident_records: ResidentRecords,
 }
 
-impl<T> Database<T> {
-  pub fn records(&self) -> &[Record<T>] {
-    &self.records
+impl<T> Database<T>
+where
+  T: DeserializeOwned + Serialize + Columns + Clone + Debug + Send + Sync,
+  T: 'static,
+{
+  pub fn init(
+    path: &Path,
+    format: StorageFormat,
+    sync_mode: SyncMode,
+    resident_records: ResidentRecords,
+  ) -> Fallible<Self> {
+    Ok(Self {
+      storage: storage::open(path, format, sync_mode, resident_records)?,
+      broadcast: Broadcast::default(),
+      path: path.to_owned(),
+      format,
+      sync_mode,
+      resident_records,
+    })
   }
-}
 
-impl<T: DeserializeOwned + Serialize + Debug> Database<T> {
-  pub fn init(path: &Path) -> Fallible<Self> {
-    let file_exists = path.exists();
-
-    info!("opening file '{}'", path.display());
-    let file = OpenOptions::new()
-      .read(true)
-      .write(true)
-      .create(true)
-      .open(path)
-      .context("failed to open file")?;
-
-    let mut db = Self { file, records: vec![] };
-
-    if file_exists {
-      info!("reading data");
-      db.read()?;
-    } else {
-      info!("writing default data to the file");
-      db.write()?;
-    }
-
-    Ok(db)
+  /// Creates an empty copy of the storage, see `Rewrite`.
+  pub fn start_rewrite(&self) -> Fallible<Rewrite<T>> {
+    Rewrite::start(
+      &self.path,
+      self.format,
+      self.sync_mode,
+      self.resident_records,
+    )
   }
-}
-
-impl<T: DeserializeOwned> Database<T> {
-  pub fn read(&mut self) -> Fallible<()> {
-    self.file.seek(SeekFrom::Start(0))?;
-
-    self.records = vec![];
-
-    let mut reader = BufReader::new(&self.file);
-    let mut line_number = 1;
-    let mut line = String::with_capacity(128);
-    while reader.read_line(&mut line)? > 0 {
-      let record = serde_json::from_str(&line).with_context(|_| {
-        format!("failed to deserialize line {}: {:?}", line_number, line)
-      })?;
-      self.records.push(record);
-      line.clear();
-      line_number += 1;
-    }
 
-    info!("read {} records", self.records.len());
+  /// Replaces all of the records with the ones pushed into `rewrite`.
+  pub fn finish_rewrite(&mut self, rewrite: Rewrite<T>) -> Fallible<()> {
+    rewrite.finish(&mut self.storage)?;
+    info!("rewrote the database with {} records", self.storage.count()?);
     Ok(())
   }
 }
 
 impl<T: Serialize + Debug> Database<T> {
   pub fn push(&mut self, record: Record<T>) -> Fallible<()> {
-    self.file.seek(SeekFrom::End(0))?;
-
-    let mut writer = BufWriter::new(&self.file);
-
-    serde_json::to_writer(&mut writer, &record)
+    let json = serde_json::to_string(&record)
       .with_context(|_| format!("failed to serialize record {:?}", record))?;
-    writer.write_all(b"\n")?;
-    self.records.push(record);
+    let timestamp = record.timestamp.as_secs();
+
+    self.storage.push(record)?;
+    info!("pushed record #{}", self.storage.count()?);
 
-    info!("pushed record #{}", self.records.len());
+    self.broadcast.send(PushedRecord { timestamp, json });
     Ok(())
   }
+}
 
+impl<T> Database<T> {
+  /// Makes sure that all pushed records have been persisted.
   pub fn write(&mut self) -> Fallible<()> {
-    self.file.seek(SeekFrom::Start(0))?;
+    self.storage.write()
+  }
 
-    let mut writer = BufWriter::new(&self.file);
-    for record in &self.records {
-      serde_json::to_writer(&mut writer, &record)
-        .with_context(|_| format!("failed to serialize record {:?}", record))?;
-      writer.write_all(b"\n")?;
+  pub fn len(&self) -> Fallible<usize> {
+    self.storage.count()
+  }
+
+  /// Receives every record pushed into the database.
+  pub fn broadcast(&self) -> &Broadcast {
+    &self.broadcast
+  }
+
+  /// Returns records with timestamps in the inclusive range, see
+  /// `Storage::range`.
+  pub fn range(
+    &self,
+    from: Option<&Timestamp>,
+    to: Option<&Timestamp>,
+    reversed: bool,
+    limit: Option<usize>,
+  ) -> Fallible<Vec<Record<T>>> {
+    self.storage.range(from, to, reversed, limit)
+  }
+
+  pub fn latest(&self) -> Fallible<Option<Record<T>>> {
+    self.storage.latest()
+  }
+
+  /// Iterates over the records with timestamps up to `to`, from the newest
+  /// one. The records are read in chunks, so the scan can be stopped early
+  /// without reading the older ones.
+  pub fn scan_back(&self, to: Option<&Timestamp>) -> ScanBack<'_, T> {
+    ScanBack {
+      storage: &*self.storage,
+      to: to.cloned(),
+      skip: 0,
+      chunk: vec![],
+      finished: false,
     }
+  }
 
-    info!("written {} records", self.records.len());
-    Ok(())
+  /// The newest record with data and a timestamp up to `to`.
+  pub fn latest_data(
+    &self,
+    to: Option<&Timestamp>,
+  ) -> Fallible<Option<Record<T>>> {
+    for record in self.scan_back(to) {
+      let record = record?;
+      if record.data().is_some() {
+        return Ok(Some(record));
+      }
+    }
+    Ok(None)
   }
 }
 
-impl<T: Eq> Database<T> {
-  pub fn compress_records<F>(&self, mut callback: F)
-  where
-    F: FnMut(&Record<T>),
-  {
-    if self.records.is_empty() {
-      return;
+pub struct ScanBack<'a, T> {
+  storage: &'a dyn Storage<T>,
+  to: Option<Timestamp>,
+  /// Number of the records with the timestamp `to` which have already been
+  /// returned, several records may share a timestamp.
+  skip: usize,
+  /// The remaining records of the current chunk in chronological order.
+  chunk: Vec<Record<T>>,
+  finished: bool,
+}
+
+impl<'a, T> Iterator for ScanBack<'a, T> {
+  type Item = Fallible<Record<T>>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    if self.chunk.is_empty() && !self.finished {
+      let limit = self.skip + SCAN_BACK_CHUNK;
+      let mut chunk =
+        match self.storage.range(None, self.to.as_ref(), true, Some(limit)) {
+          Ok(chunk) => chunk,
+          Err(error) => {
+            self.finished = true;
+            return Some(Err(error));
+          }
+        };
+      self.finished = chunk.len() < limit;
+      // the newest records of the chunk have been returned by the previous one
+      chunk.drain(..self.skip.min(chunk.len()));
+
+      // the next chunk ends at the oldest record of this one
+      if let Some(oldest) = chunk.last() {
+        let oldest = oldest.timestamp.as_secs();
+        let same = (chunk.iter().rev())
+          .take_while(|record| record.timestamp.as_secs() == oldest)
+          .count();
+        if self.to.as_ref().map(Timestamp::as_secs) != Some(oldest) {
+          self.skip = 0;
+        }
+        self.skip += same;
+        self.to = Some(Timestamp::new(oldest));
+      }
+      chunk.reverse();
+      self.chunk = chunk;
     }
+    self.chunk.pop().map(Ok)
+  }
+}
+
+/// Drops records in the middle of runs of records with unchanged values, only
+/// the first and the last records of every run are kept. The result is the
+/// same for the reversed iterator, just in the reversed order.
+pub fn compress_records<'a, T, I>(records: I) -> CompressRecords<'a, T, I>
+where
+  T: Eq + 'a,
+  I: Iterator<Item = &'a Record<T>>,
+{
+  CompressRecords {
+    records,
+    prev_record: None,
+    prev_record_had_changes: true,
+    queued_record: None,
+  }
+}
+
+pub struct CompressRecords<'a, T, I> {
+  records: I,
+  prev_record: Option<&'a Record<T>>,
+  prev_record_had_changes: bool,
+  queued_record: Option<&'a Record<T>>,
+}
 
-    let first_record = &self.records[0];
-    callback(first_record);
+impl<'a, T, I> Iterator for CompressRecords<'a, T, I>
+where
+  T: Eq + 'a,
+  I: Iterator<Item = &'a Record<T>>,
+{
+  type Item = &'a Record<T>;
 
-    if self.records.len() == 1 {
-      return;
+  fn next(&mut self) -> Option<Self::Item> {
+    if let Some(record) = self.queued_record.take() {
+      return Some(record);
     }
 
-    let mut prev_record = first_record;
-    let mut prev_record_had_changes = true;
+    for record in &mut self.records {
+      let prev_record = match self.prev_record.replace(record) {
+        Some(prev_record) => prev_record,
+        None => return Some(record),
+      };
 
-    for record in &self.records[1..] {
-      if record.data != prev_record.data {
-        if !prev_record_had_changes {
-          callback(prev_record);
+      if record.value != prev_record.value {
+        let emit_prev_record = !self.prev_record_had_changes;
+        self.prev_record_had_changes = true;
+        if emit_prev_record {
+          self.queued_record = Some(record);
+          return Some(prev_record);
         }
-        prev_record_had_changes = true;
-        callback(record);
-      } else {
-        prev_record_had_changes = false;
+        return Some(record);
       }
 
-      prev_record = record;
+      self.prev_record_had_changes = false;
+    }
+
+    if !self.prev_record_had_changes {
+      self.prev_record_had_changes = true;
+      return self.prev_record;
     }
+    None
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::record::{Gap, RecordValue};
+  use crate::testing::TempPath;
+
+  #[test]
+  fn scan_back_reads_records_in_chunks() {
+    let path = TempPath::new("scan-back.sqlite");
+    let mut db = Database::<u64>::init(
+      &path,
+      StorageFormat::Sqlite,
+      SyncMode::Never,
+      ResidentRecords::All,
+    )
+    .unwrap();
+    let count = SCAN_BACK_CHUNK as i64 * 2 + 10;
+    for timestamp in 0..count {
+      let value = if timestamp < 5 {
+        RecordValue::Data(timestamp as u64)
+      } else {
+        RecordValue::Gap(Gap { reason: "down".to_owned() })
+      };
+      db.push(Record { timestamp: Timestamp::new(timestamp), value }).unwrap();
+    }
+
+    let timestamps: Vec<i64> = db
+      .scan_back(Some(&Timestamp::new(count - 2)))
+      .map(|record| record.unwrap().timestamp.as_secs())
+      .collect();
+    assert_eq!(timestamps, (0..count - 1).rev().collect::<Vec<i64>>());
+
+    let latest_data = db.latest_data(None).unwrap().unwrap();
+    assert_eq!(latest_data.timestamp.as_secs(), 4);
+    assert!(db.latest_data(Some(&Timestamp::new(-1))).unwrap().is_none());
+  }
+
+  #[test]
+  fn scan_back_returns_records_sharing_a_timestamp() {
+    let path = TempPath::new("scan-back-same-timestamps.json");
+    let mut db = Database::<u64>::init(
+      &path,
+      StorageFormat::Json,
+      SyncMode::Never,
+      ResidentRecords::All,
+    )
+    .unwrap();
+    // the chunks end in the middle of the groups of records, and one group is
+    // longer than a chunk
+    let mut timestamps: Vec<i64> = (0..250).map(|index| index / 3).collect();
+    timestamps.extend(vec![100; SCAN_BACK_CHUNK + 20]);
+    for (index, &timestamp) in timestamps.iter().enumerate() {
+      let value = RecordValue::Data(index as u64);
+      db.push(Record { timestamp: Timestamp::new(timestamp), value }).unwrap();
+    }
+
+    let values: Vec<u64> = db
+      .scan_back(None)
+      .map(|record| *record.unwrap().data().unwrap())
+      .collect();
+    assert_eq!(values, (0..timestamps.len() as u64).rev().collect::<Vec<_>>());
+  }
+
+  #[test]
+  fn compressed_records_keep_the_ends_of_unchanged_runs() {
+    let gap = || RecordValue::Gap(Gap { reason: "x".to_owned() });
+    let cases: &[(&[Option<u64>], &[i64])] = &[
+      (&[], &[]),
+      (&[Some(1)], &[0]),
+      (&[Some(1), Some(1)], &[0, 1]),
+      (&[Some(1), Some(1), Some(1)], &[0, 2]),
+      (&[Some(1), Some(2), Some(1)], &[0, 1, 2]),
+      (
+        &[Some(1), Some(1), Some(1), Some(2), Some(2), Some(3), Some(3)],
+        &[0, 2, 3, 4, 5, 6],
+      ),
+      (&[Some(1), None, None, None, Some(1), Some(1)], &[0, 1, 3, 4, 5]),
+    ];
+    for (values, expected) in cases {
+      let records: Vec<Record<u64>> = values
+        .iter()
+        .enumerate()
+        .map(|(index, value)| Record {
+          timestamp: Timestamp::new(index as i64),
+          value: value.map_or_else(gap, RecordValue::Data),
+        })
+        .collect();
+      let timestamps = |records: Vec<&Record<u64>>| -> Vec<i64> {
+        records.iter().map(|record| record.timestamp.as_secs()).collect()
+      };
 
-    if !prev_record_had_changes {
-      callback(prev_record);
+      let forward = timestamps(compress_records(records.iter()).collect());
+      assert_eq!(&forward[..], *expected, "{:?}", values);
+      let mut backward =
+        timestamps(compress_records(records.iter().rev()).collect());
+      backward.reverse();
+      assert_eq!(backward, forward, "{:?}", values);
     }
   }
 }
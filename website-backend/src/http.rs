@@ -1,12 +1,43 @@
-use failure::{Error, Fail};
+use failure::{Error, Fail, Fallible, ResultExt};
 use log::info;
 
-use hyper::{Body, Chunk, Request, Uri};
+use hyper::client::HttpConnector;
+use hyper::header::{self, HeaderValue};
+use hyper::{Body, Chunk, Method, Request, Uri};
+use hyper_tls::HttpsConnector;
 use tokio::prelude::*;
 
+use crate::config::HttpConfig;
+
 pub type JsonValue = serde_json::Value;
+pub type JsonMap = serde_json::Map<String, JsonValue>;
+
+pub type HttpClient = hyper::Client<HttpsConnector<HttpConnector>>;
+
+const DNS_WORKER_THREADS: usize = 4;
 
-pub type HttpClient = hyper::Client<hyper::client::HttpConnector>;
+/// Creates a client for both HTTP and HTTPS URLs which, in addition to the
+/// system roots, trusts the CA certificates listed in the config.
+pub fn new_client(config: &HttpConfig) -> Fallible<HttpClient> {
+  let mut tls = native_tls::TlsConnector::builder();
+  for path in &config.ca_certificates {
+    info!("loading CA certificate '{}'", path.display());
+    let pem = std::fs::read(path).with_context(|_| {
+      format!("failed to read CA certificate '{}'", path.display())
+    })?;
+    let certificate =
+      native_tls::Certificate::from_pem(&pem).with_context(|_| {
+        format!("invalid CA certificate '{}'", path.display())
+      })?;
+    tls.add_root_certificate(certificate);
+  }
+  let tls = tls.build().context("failed to create TLS connector")?;
+
+  let mut http = HttpConnector::new(DNS_WORKER_THREADS);
+  http.enforce_http(false);
+
+  Ok(hyper::Client::builder().build(HttpsConnector::from((http, tls))))
+}
 
 pub fn get_json<I>(
   client: &HttpClient,
@@ -17,6 +48,16 @@ where
 {
   let mut req = Request::new(Body::default());
   *req.uri_mut() = url;
+  request_json(client, req)
+}
+
+pub fn request_json<I>(
+  client: &HttpClient,
+  req: Request<Body>,
+) -> impl Future<Item = I, Error = Error>
+where
+  I: serde::de::DeserializeOwned,
+{
   request(client, req).map_err(|e| e.context("network error").into()).and_then(
     |body| {
       serde_json::from_slice(&body)
@@ -32,3 +73,107 @@ pub fn request(
   info!("sending a request to '{}'", req.uri());
   client.request(req).and_then(|res| res.into_body().concat2())
 }
+
+/// Sends a `POST` request with a JSON body, responses with an unsuccessful
+/// status are treated as errors.
+pub fn post_json(
+  client: &HttpClient,
+  url: Uri,
+  json: Vec<u8>,
+) -> impl Future<Item = (), Error = Error> {
+  let mut req = Request::new(Body::from(json));
+  *req.method_mut() = Method::POST;
+  *req.uri_mut() = url;
+  req
+    .headers_mut()
+    .insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+
+  info!("sending a request to '{}'", req.uri());
+  client.request(req).map_err(|e| e.context("network error").into()).and_then(
+    |res| {
+      let status = res.status();
+      if status.is_success() {
+        Ok(())
+      } else {
+        Err(failure::format_err!("unsuccessful response status: {}", status))
+      }
+    },
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use hyper::server::conn::Http;
+  use hyper::service::service_fn_ok;
+  use hyper::Response;
+  use std::net::SocketAddr;
+  use std::path::PathBuf;
+  use tokio::net::TcpListener;
+  use tokio::runtime::Runtime;
+
+  fn fixture(name: &str) -> PathBuf {
+    [env!("CARGO_MANIFEST_DIR"), "tests", "fixtures", "tls", name]
+      .iter()
+      .collect()
+  }
+
+  /// Starts a stand-in HTTPS server for `127.0.0.1` with a certificate signed
+  /// by the test CA, it responds to every request with the same JSON object.
+  fn start_server(runtime: &mut Runtime) -> SocketAddr {
+    let identity = native_tls::Identity::from_pkcs12(
+      &std::fs::read(fixture("localhost.p12")).unwrap(),
+      "test",
+    )
+    .unwrap();
+    let acceptor = tokio_tls::TlsAcceptor::from(
+      native_tls::TlsAcceptor::new(identity).unwrap(),
+    );
+
+    let listener =
+      TcpListener::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+    let address = listener.local_addr().unwrap();
+
+    let server = listener.incoming().map_err(|_| ()).for_each(move |socket| {
+      let connection =
+        acceptor.accept(socket).map_err(|_| ()).and_then(|stream| {
+          let service = service_fn_ok(|_req| {
+            Response::new(Body::from(r#"{"status":"ok"}"#))
+          });
+          Http::new().serve_connection(stream, service).map_err(|_| ())
+        });
+      tokio::spawn(connection);
+      Ok(())
+    });
+    runtime.spawn(server);
+
+    address
+  }
+
+  fn server_url(address: SocketAddr) -> Uri {
+    format!("https://127.0.0.1:{}/", address.port()).parse().unwrap()
+  }
+
+  #[test]
+  fn get_json_trusts_configured_ca() {
+    let mut runtime = Runtime::new().unwrap();
+    let address = start_server(&mut runtime);
+
+    let config = HttpConfig { ca_certificates: vec![fixture("ca.pem")] };
+    let client = new_client(&config).unwrap();
+    let json: JsonValue =
+      runtime.block_on(get_json(&client, server_url(address))).unwrap();
+    assert_eq!(json, serde_json::json!({ "status": "ok" }));
+  }
+
+  #[test]
+  fn get_json_rejects_unknown_ca() {
+    let mut runtime = Runtime::new().unwrap();
+    let address = start_server(&mut runtime);
+
+    let client = new_client(&HttpConfig::default()).unwrap();
+    let result: Result<JsonValue, Error> =
+      runtime.block_on(get_json(&client, server_url(address)));
+    assert!(result.is_err());
+  }
+}
@@ -7,31 +7,42 @@ macro_rules! log_error {
       module_path!(),
       file!(),
       line!(),
-    );
+    )
   };
 }
 
+mod aggregate;
+mod alerts;
+mod binary;
+mod broadcast;
 mod config;
 mod database;
 mod http;
+mod migrate;
+mod negotiate;
 mod record;
+mod retention;
 mod server;
 mod shutdown;
+mod stats;
+mod storage;
+#[cfg(test)]
+mod testing;
 mod trackers;
+mod websocket;
 
 use failure::{AsFail, Fail, Fallible, ResultExt};
 use log::info;
 
-use futures::sync::oneshot;
-use std::sync::{Arc, RwLock};
+use futures::sync::{mpsc, oneshot};
 use tokio::prelude::*;
 use tokio_signal::unix::{Signal, SIGINT, SIGTERM};
 
 use std::path::PathBuf;
 
 use crate::config::Config;
-use crate::database::Database;
 use crate::shutdown::Shutdown;
+use crate::trackers::Trackers;
 
 fn main() {
   env_logger::init();
@@ -42,48 +53,71 @@ fn main() {
 }
 
 fn run() -> Fallible<()> {
-  let config_path = std::env::args_os()
-    .nth(1)
-    .map_or(PathBuf::from("config.json"), PathBuf::from);
+  let mut args = std::env::args_os().skip(1).peekable();
+  if args.peek().is_some_and(|arg| arg == "migrate") {
+    args.next();
+    let args = migrate::MigrateArgs::parse(args)?;
+    trackers::migrate(&args).context("failed to migrate the database")?;
+    return Ok(());
+  }
+
+  let config_path =
+    args.next().map_or(PathBuf::from("config.json"), PathBuf::from);
   info!("loading config file '{}'", config_path.display());
   let config = Config::read(&config_path).context("failed to load config")?;
 
-  info!("initializing database");
-  let db = Database::init(&config.trackers.ranker.database_file)
-    .context("failed to initialize database")?;
-  let shared_db = Arc::new(RwLock::new(db));
+  let server_address =
+    config.server.address().context("failed to resolve server address")?;
+
+  let http_client = http::new_client(&config.http)
+    .context("failed to create the HTTP client")?;
+
+  info!("initializing trackers");
+  let trackers = Trackers::init(config.trackers, &config.database)
+    .context("failed to initialize trackers")?;
+
+  let delivery_log =
+    alerts::DeliveryLog::open(&config.database.dir.join("alerts.jsonl"))
+      .context("failed to open the alert delivery log")?;
 
   info!("starting tokio runtime");
   let mut runtime =
     tokio::runtime::Runtime::new().context("failed to start new Runtime")?;
 
   let shutdown = Shutdown::new();
-  let signals_future: oneshot::SpawnHandle<(), ()> =
-    oneshot::spawn(receive_signals(shutdown.another()), &runtime.executor());
-  let server_future: oneshot::SpawnHandle<(), ()> = oneshot::spawn(
-    server::start(config.server, shared_db.clone(), shutdown.another()),
+  let mut futures: Vec<oneshot::SpawnHandle<(), ()>> = vec![];
+  futures.push(oneshot::spawn(
+    receive_signals(shutdown.another()),
+    &runtime.executor(),
+  ));
+  futures.push(oneshot::spawn(
+    server::start(server_address, trackers.info(), shutdown.another()),
     &runtime.executor(),
-  );
-  let tracker_future: oneshot::SpawnHandle<(), ()> = oneshot::spawn(
-    trackers::start(
-      Box::new(trackers::ranker::RankerTracker::new()),
-      config.trackers.ranker.request_interval,
-      shared_db.clone(),
+  ));
+  let (alert_sender, alert_receiver) = mpsc::unbounded();
+  futures.push(oneshot::spawn(
+    alerts::start(
+      alert_receiver,
+      config.webhooks,
+      delivery_log,
+      http_client.clone(),
       shutdown.another(),
     ),
     &runtime.executor(),
-  );
+  ));
+  for tracker_future in trackers.start(&http_client, &alert_sender, &shutdown) {
+    futures.push(oneshot::spawn(tracker_future, &runtime.executor()));
+  }
 
-  let shutdown_result: Result<(), ()> = runtime
-    .block_on(signals_future.join3(server_future, tracker_future).map(|_| ()));
+  let shutdown_result: Result<(), ()> =
+    runtime.block_on(future::join_all(futures).map(|_| ()));
   runtime.shutdown_on_idle().wait().unwrap();
   if shutdown_result.is_err() {
     return Err(failure::err_msg("error in the async code, see logs above"));
   }
 
-  info!("synchronizing database before shutdown");
-  let mut db = shared_db.write().unwrap();
-  db.write()?;
+  info!("synchronizing databases before shutdown");
+  trackers.write_databases()?;
 
   Ok(())
 }
@@ -0,0 +1,675 @@
+//! The `migrate` subcommand, which converts a tracker database between the
+//! storage formats of this backend and the formats of the older tools.
+
+use failure::{Fallible, ResultExt};
+use log::{info, warn};
+
+use serde::de::DeserializeOwned;
+use serde::ser::Serialize;
+use std::ffi::OsString;
+use std::fmt::Debug;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::config::{ResidentRecords, StorageFormat, SyncMode};
+use crate::record::{Gap, Record, RecordValue, Timestamp};
+use crate::stats::{Format, TableWriter};
+use crate::storage::{self, Storage};
+use crate::trackers::Columns;
+
+pub const USAGE: &str = "\
+usage: backend migrate <tracker type> <input format> <input path> \
+<output format> <output path> [--columns <names>]
+
+formats:
+  json    JSON lines written by this backend
+  binary  binary format of this backend
+  sqlite  SQLite database of this backend
+  node    JSON lines written by `PushDatabase` of the Node.js backend
+  csv     CSV with a timestamp column, e.g. from the stats endpoint or from
+          `fetch.py`, the latter has no header, so the names of the other
+          columns must be given with `--columns`, separated by commas";
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum MigrationFormat {
+  Storage(StorageFormat),
+  Node,
+  Csv,
+}
+
+impl MigrationFormat {
+  fn parse(s: &str) -> Fallible<Self> {
+    Ok(match s {
+      "json" => MigrationFormat::Storage(StorageFormat::Json),
+      "binary" => MigrationFormat::Storage(StorageFormat::Binary),
+      "sqlite" => MigrationFormat::Storage(StorageFormat::Sqlite),
+      "node" => MigrationFormat::Node,
+      "csv" => MigrationFormat::Csv,
+      _ => return Err(failure::format_err!("unknown format: {}", s)),
+    })
+  }
+
+  /// Whether the records can be written in this format, the Node.js backend
+  /// doesn't have gaps.
+  fn keeps<T>(self, record: &Record<T>) -> bool {
+    self != MigrationFormat::Node || record.data().is_some()
+  }
+}
+
+pub struct MigrateArgs {
+  pub tracker_type: String,
+  pub input_format: MigrationFormat,
+  pub input_path: PathBuf,
+  pub output_format: MigrationFormat,
+  pub output_path: PathBuf,
+  /// Columns of a CSV file without a header.
+  pub columns: Option<Vec<String>>,
+}
+
+impl MigrateArgs {
+  pub fn parse<I: Iterator<Item = OsString>>(args: I) -> Fallible<Self> {
+    let mut positional = vec![];
+    let mut columns = None;
+    let mut args = args.map(|arg| {
+      arg.into_string().map_err(|arg| {
+        failure::format_err!("invalid argument: {}", arg.to_string_lossy())
+      })
+    });
+    while let Some(arg) = args.next() {
+      let arg = arg?;
+      if arg == "--columns" {
+        let names = args
+          .next()
+          .ok_or_else(|| failure::err_msg("missing value of --columns"))??;
+        columns = Some(names.split(',').map(str::to_owned).collect());
+      } else if arg.starts_with("--") {
+        return Err(failure::format_err!("unknown option: {}", arg));
+      } else {
+        positional.push(arg);
+      }
+    }
+
+    if positional.len() != 5 {
+      return Err(failure::err_msg(USAGE));
+    }
+    let mut positional = positional.into_iter();
+    let mut next = || positional.next().unwrap();
+    Ok(Self {
+      tracker_type: next(),
+      input_format: MigrationFormat::parse(&next())?,
+      input_path: PathBuf::from(next()),
+      output_format: MigrationFormat::parse(&next())?,
+      output_path: PathBuf::from(next()),
+      columns,
+    })
+  }
+}
+
+/// Number of records read from a storage at once.
+const READ_CHUNK: usize = 1000;
+
+/// The records of the files are streamed, so only a few of them are kept in
+/// memory.
+const RESIDENT_RECORDS: ResidentRecords = ResidentRecords::Newest(READ_CHUNK);
+
+/// Records read from a file one by one, so that the whole database doesn't
+/// have to be in memory at once.
+type Records<T> = Box<dyn Iterator<Item = Fallible<Record<T>>>>;
+
+/// Converts the database and reads the written file back to check that all
+/// records have been preserved. The input file is never modified, e.g. a
+/// partially written record at its end is skipped.
+pub fn run<T>(args: &MigrateArgs) -> Fallible<()>
+where
+  T: DeserializeOwned + Serialize + Columns + Clone + Eq + Debug + Send + Sync,
+  T: 'static,
+{
+  if !args.input_path.exists() {
+    return Err(failure::format_err!(
+      "input file '{}' doesn't exist",
+      args.input_path.display()
+    ));
+  }
+  if args.output_path.exists() {
+    return Err(failure::format_err!(
+      "output file '{}' already exists",
+      args.output_path.display()
+    ));
+  }
+
+  // the input is read again for every pass over it
+  let input = || -> Fallible<Records<T>> {
+    info!("reading '{}'", args.input_path.display());
+    let records =
+      read(args.input_format, &args.input_path, args.columns.as_deref())
+        .context("failed to read the input file")?;
+    Ok(Box::new(
+      records
+        .map(|record| Ok(record.context("failed to read the input file")?)),
+    ))
+  };
+
+  info!("writing '{}'", args.output_path.display());
+  write(args.output_format, &args.output_path, &input)
+    .context("failed to write the output file")?;
+
+  info!("verifying '{}'", args.output_path.display());
+  let mut skipped = 0;
+  let expected = input()?.filter(|record| match record {
+    Ok(record) if !args.output_format.keeps(record) => {
+      skipped += 1;
+      false
+    }
+    _ => true,
+  });
+  let written = read(args.output_format, &args.output_path, None)
+    .context("failed to read the output file back")?
+    .map(|record| Ok(record.context("failed to read the output file back")?));
+  let count = verify(expected, written)?;
+  if skipped > 0 {
+    warn!("skipped {} records which the output format can't store", skipped);
+  }
+  info!("migrated {} records", count);
+
+  Ok(())
+}
+
+fn read<T>(
+  format: MigrationFormat,
+  path: &Path,
+  columns: Option<&[String]>,
+) -> Fallible<Records<T>>
+where
+  T: DeserializeOwned + Serialize + Columns + Clone + Debug + Send + Sync,
+  T: 'static,
+{
+  match format {
+    MigrationFormat::Storage(format) => {
+      let storage = storage::open_read_only(path, format, RESIDENT_RECORDS)?;
+      Ok(read_storage(storage))
+    }
+    MigrationFormat::Node => read_node(path),
+    MigrationFormat::Csv => read_csv(path, columns),
+  }
+}
+
+/// `input` is called for every pass over the records, the CSV files need two
+/// of them to find out the columns first.
+fn write<T>(
+  format: MigrationFormat,
+  path: &Path,
+  input: &dyn Fn() -> Fallible<Records<T>>,
+) -> Fallible<()>
+where
+  T: DeserializeOwned + Serialize + Columns + Clone + Debug + Send + Sync,
+  T: 'static,
+{
+  match format {
+    MigrationFormat::Storage(format) => {
+      // the pushed records are synchronized at once by `write`
+      let mut storage = storage::open(
+        path,
+        format,
+        SyncMode::Batched(u32::MAX),
+        RESIDENT_RECORDS,
+      )?;
+      for record in input()? {
+        storage.push(record?)?;
+      }
+      storage.write()
+    }
+    MigrationFormat::Node => write_node(path, input()?),
+    MigrationFormat::Csv => write_csv(path, input),
+  }
+}
+
+/// Reads the records of a storage in chronological order, a chunk at a time.
+fn read_storage<T: 'static>(storage: Box<dyn Storage<T>>) -> Records<T> {
+  let mut from: Option<Timestamp> = None;
+  // number of the records with the timestamp `from` which have already been
+  // returned, several records may share a timestamp
+  let mut skip = 0;
+  let mut chunk = vec![].into_iter();
+  let mut finished = false;
+  Box::new(std::iter::from_fn(move || loop {
+    if let Some(record) = chunk.next() {
+      return Some(Ok(record));
+    }
+    if finished {
+      return None;
+    }
+
+    let limit = skip + READ_CHUNK;
+    let mut records =
+      match storage.range(from.as_ref(), None, false, Some(limit)) {
+        Ok(records) => records,
+        Err(error) => {
+          finished = true;
+          return Some(Err(error));
+        }
+      };
+    finished = records.len() < limit;
+    records.drain(..skip.min(records.len()));
+    // the next chunk starts at the newest record of this one
+    if let Some(newest) = records.last() {
+      let newest = newest.timestamp.as_secs();
+      let same = (records.iter().rev())
+        .take_while(|record| record.timestamp.as_secs() == newest)
+        .count();
+      if from.as_ref().map(Timestamp::as_secs) != Some(newest) {
+        skip = 0;
+      }
+      skip += same;
+      from = Some(Timestamp::new(newest));
+    }
+    chunk = records.into_iter();
+  }))
+}
+
+/// Compares the counts, timestamps and values of the records and returns the
+/// count. Gap reasons aren't compared because they are lost in CSV files.
+fn verify<T: Eq + Debug>(
+  expected: impl Iterator<Item = Fallible<Record<T>>>,
+  actual: impl Iterator<Item = Fallible<Record<T>>>,
+) -> Fallible<usize> {
+  let mut expected = expected.fuse();
+  let mut actual = actual.fuse();
+  for index in 1.. {
+    match (expected.next().transpose()?, actual.next().transpose()?) {
+      (None, None) => return Ok(index - 1),
+      (Some(_), None) | (None, Some(_)) => {
+        return Err(failure::format_err!(
+          "verification failed: the record counts differ, first extra \
+           record is #{}",
+          index
+        ));
+      }
+      (Some(expected), Some(actual)) => {
+        let equal = expected.timestamp.as_secs() == actual.timestamp.as_secs()
+          && expected.data() == actual.data();
+        if !equal {
+          return Err(failure::format_err!(
+            "verification failed: record #{} differs: expected {:?}, got {:?}",
+            index,
+            expected,
+            actual
+          ));
+        }
+      }
+    }
+  }
+  unreachable!()
+}
+
+fn create_file(path: &Path) -> Fallible<File> {
+  info!("creating file '{}'", path.display());
+  Ok(
+    OpenOptions::new()
+      .write(true)
+      .create_new(true)
+      .open(path)
+      .context("failed to create file")?,
+  )
+}
+
+/// Records of `PushDatabase` have the same structure, but the keys of the data
+/// points are in camelCase.
+fn read_node<T: DeserializeOwned + 'static>(
+  path: &Path,
+) -> Fallible<Records<T>> {
+  let reader = BufReader::new(File::open(path)?);
+  Ok(Box::new(reader.lines().enumerate().map(|(index, line)| {
+    let line = line?;
+    let parse = || -> Fallible<Record<T>> {
+      let mut json: serde_json::Value = serde_json::from_str(&line)?;
+      if let Some(data) = json.get_mut("data") {
+        rename_keys(data, &camel_to_snake_case);
+      }
+      Ok(serde_json::from_value(json)?)
+    };
+    let record = parse().with_context(|_| {
+      format!("failed to deserialize line {}: {:?}", index + 1, line)
+    })?;
+    Ok(record)
+  })))
+}
+
+fn write_node<T: Serialize>(path: &Path, records: Records<T>) -> Fallible<()> {
+  let file = create_file(path)?;
+  let mut writer = BufWriter::new(&file);
+  for record in records {
+    let record = record?;
+    let data = match record.data() {
+      Some(data) => data,
+      None => continue,
+    };
+    let mut data = serde_json::to_value(data)?;
+    rename_keys(&mut data, &snake_to_camel_case);
+    // the keys are in the same order as in the objects of `PushDatabase`
+    writeln!(
+      writer,
+      r#"{{"timestamp":{},"data":{}}}"#,
+      record.timestamp.as_secs(),
+      data
+    )?;
+  }
+  writer.flush()?;
+  drop(writer);
+  file.sync_all()?;
+  Ok(())
+}
+
+fn rename_keys(json: &mut serde_json::Value, rename: &dyn Fn(&str) -> String) {
+  match json {
+    serde_json::Value::Object(object) => {
+      *object = std::mem::take(object)
+        .into_iter()
+        .map(|(key, mut value)| {
+          rename_keys(&mut value, rename);
+          (rename(&key), value)
+        })
+        .collect();
+    }
+    serde_json::Value::Array(array) => {
+      for value in array {
+        rename_keys(value, rename);
+      }
+    }
+    _ => {}
+  }
+}
+
+fn camel_to_snake_case(s: &str) -> String {
+  let mut result = String::with_capacity(s.len() + 2);
+  for c in s.chars() {
+    if c.is_ascii_uppercase() {
+      result.push('_');
+      result.push(c.to_ascii_lowercase());
+    } else {
+      result.push(c);
+    }
+  }
+  result
+}
+
+fn snake_to_camel_case(s: &str) -> String {
+  let mut result = String::with_capacity(s.len());
+  let mut parts = s.split('_');
+  result.extend(parts.next());
+  for part in parts {
+    let mut chars = part.chars();
+    result.extend(chars.next().map(|c| c.to_ascii_uppercase()));
+    result.extend(chars);
+  }
+  result
+}
+
+/// Rows where all values are empty are read as gaps, like they are written
+/// by the stats endpoint, which also writes their reasons in the last column
+/// called `gap`.
+fn read_csv<T: Columns + 'static>(
+  path: &Path,
+  columns: Option<&[String]>,
+) -> Fallible<Records<T>> {
+  let reader = BufReader::new(File::open(path)?);
+  let mut lines = reader.lines().enumerate().peekable();
+
+  let mut names: Vec<String> = match columns {
+    Some(columns) => columns.to_vec(),
+    None => match lines.next() {
+      Some((_, header)) => {
+        let header = header?;
+        let mut names = header.trim_end().split(',').map(str::to_owned);
+        if names.next().as_deref() != Some("timestamp") {
+          return Err(failure::err_msg(
+            "CSV file has no header, the columns must be given with --columns",
+          ));
+        }
+        names.collect()
+      }
+      None => vec![],
+    },
+  };
+  names.retain(|name| !name.is_empty());
+  let has_gap_column = names.last().is_some_and(|name| name == "gap");
+  if has_gap_column {
+    names.pop();
+  }
+
+  let mut values = Vec::with_capacity(names.len());
+  Ok(Box::new(lines.filter_map(move |(index, line)| {
+    let line = match line {
+      Ok(line) => line,
+      Err(error) => return Some(Err(error.into())),
+    };
+    let line = line.trim_end();
+    if line.is_empty() {
+      return None;
+    }
+    let mut parse = || -> Fallible<Record<T>> {
+      let (line, reason) = if has_gap_column {
+        split_gap_column(line, names.len() + 1)
+          .ok_or_else(|| failure::err_msg("missing gap column"))?
+      } else {
+        (line, String::new())
+      };
+      let mut fields = line.split(',');
+      let timestamp = fields.next().unwrap();
+      let timestamp = Timestamp::parse(timestamp)
+        .ok_or_else(|| failure::format_err!("invalid timestamp"))?;
+
+      values.clear();
+      for field in fields {
+        values.push(if field.is_empty() { None } else { Some(field.parse()?) });
+      }
+      if values.len() != names.len() {
+        return Err(failure::format_err!(
+          "expected {} values, got {}",
+          names.len(),
+          values.len()
+        ));
+      }
+
+      let value = if values.iter().all(Option::is_none) {
+        RecordValue::Gap(Gap { reason })
+      } else {
+        RecordValue::Data(
+          T::from_column_values(&names, &values)
+            .ok_or_else(|| failure::err_msg("invalid data point"))?,
+        )
+      };
+      Ok(Record { timestamp, value })
+    };
+    let record = parse().with_context(|_| {
+      format!("failed to parse line {}: {:?}", index + 1, line)
+    });
+    Some(record.map_err(Into::into))
+  })))
+}
+
+/// Splits a line into the fields before the gap column and the reason, which
+/// may contain commas if it is quoted.
+fn split_gap_column(line: &str, fields: usize) -> Option<(&str, String)> {
+  let (index, _) = line.match_indices(',').nth(fields - 1)?;
+  let reason = &line[index + 1..];
+  let reason = match reason.strip_prefix('"').and_then(|r| r.strip_suffix('"'))
+  {
+    Some(quoted) => quoted.replace("\"\"", "\""),
+    None => reason.to_owned(),
+  };
+  Some((&line[..index], reason))
+}
+
+/// The columns of all data points are written, in the order they first
+/// appear in, so that none of the values are lost. They are collected in a
+/// separate pass over the records. The last column has the reasons of gaps,
+/// like in the tables of the stats endpoint.
+fn write_csv<T: Columns>(
+  path: &Path,
+  input: &dyn Fn() -> Fallible<Records<T>>,
+) -> Fallible<()> {
+  let mut names: Vec<String> = vec![];
+  for record in input()? {
+    let data = match record?.value {
+      RecordValue::Data(data) => data,
+      RecordValue::Gap(_) => continue,
+    };
+    for name in data.column_names() {
+      if !names.contains(&name) {
+        names.push(name);
+      }
+    }
+  }
+
+  let file = create_file(path)?;
+  let mut writer = BufWriter::new(&file);
+  let mut header = names.clone();
+  header.push("gap".to_owned());
+  let mut table = TableWriter::new(Format::Csv, "timestamp", &header);
+  let mut values = Vec::with_capacity(names.len());
+  for record in input()? {
+    let record = record?;
+    table.start_row(&record.timestamp);
+    values.clear();
+    match record.data() {
+      Some(data) => data.column_values(&names, &mut values),
+      None => values.resize(names.len(), None),
+    }
+    for &value in &values {
+      table.write_u64(value);
+    }
+    table.write_str(record.gap().map(|gap| gap.reason.as_str()));
+    table.end_row();
+    writer.write_all(&table.take_bytes())?;
+  }
+  table.finish();
+  writer.write_all(&table.take_bytes())?;
+  writer.flush()?;
+  drop(writer);
+  file.sync_all()?;
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::trackers::ranker;
+
+  #[test]
+  fn keys_are_renamed_between_cases() {
+    for &(snake, camel) in &[
+      ("top5_reranks", "top5Reranks"),
+      ("accounts_active", "accountsActive"),
+      ("rank", "rank"),
+    ] {
+      assert_eq!(snake_to_camel_case(snake), camel);
+      assert_eq!(camel_to_snake_case(camel), snake);
+    }
+  }
+
+  #[test]
+  fn records_round_trip_through_all_formats() {
+    let dir = std::env::temp_dir()
+      .join(format!("migrate-round-trip-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir(&dir).unwrap();
+
+    let csv = "2019-05-01 00:00:00,30,100,5,7,2\n\
+               2019-05-01 00:05:00,,,,,\n\
+               2019-05-01 00:10:00,29,110,5,,3\n";
+    std::fs::write(dir.join("fetch.csv"), csv).unwrap();
+
+    let steps = [
+      ("csv", "fetch.csv", "json", "ranker.json"),
+      ("json", "ranker.json", "binary", "ranker.bin"),
+      ("binary", "ranker.bin", "sqlite", "ranker.sqlite"),
+      ("sqlite", "ranker.sqlite", "csv", "ranker.csv"),
+      ("csv", "ranker.csv", "node", "ranker.node.json"),
+    ];
+    for &(input_format, input, output_format, output) in &steps {
+      let args = MigrateArgs {
+        tracker_type: "ranker".to_owned(),
+        input_format: MigrationFormat::parse(input_format).unwrap(),
+        input_path: dir.join(input),
+        output_format: MigrationFormat::parse(output_format).unwrap(),
+        output_path: dir.join(output),
+        columns: Some(
+          ["rank", "upvotes", "downvotes", "reranks", "top5_reranks"]
+            .iter()
+            .map(|&name| name.to_owned())
+            .collect(),
+        )
+        .filter(|_| input == "fetch.csv"),
+      };
+      run::<ranker::DataPoint>(&args).unwrap();
+    }
+
+    assert_eq!(
+      std::fs::read_to_string(dir.join("ranker.csv")).unwrap(),
+      format!(
+        "timestamp,rank,upvotes,downvotes,reranks,top5_reranks,gap\n{}",
+        csv.replace('\n', ",\n")
+      )
+    );
+    assert_eq!(
+      std::fs::read_to_string(dir.join("ranker.node.json")).unwrap(),
+      "{\"timestamp\":1556668800,\"data\":{\"downvotes\":5,\"rank\":30,\
+       \"reranks\":7,\"top5Reranks\":2,\"upvotes\":100}}\n\
+       {\"timestamp\":1556669400,\"data\":{\"downvotes\":5,\"rank\":29,\
+       \"top5Reranks\":3,\"upvotes\":110}}\n"
+    );
+
+    let path = dir.join("ranker.json").into_os_string();
+    let args =
+      vec!["ranker".into(), "json".into(), path.clone(), "json".into(), path];
+    let args = MigrateArgs::parse(args.into_iter()).unwrap();
+    assert!(run::<ranker::DataPoint>(&args).is_err());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn torn_input_is_read_without_modifying_it() {
+    let dir = std::env::temp_dir()
+      .join(format!("migrate-read-only-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir(&dir).unwrap();
+
+    let json = "{\"timestamp\":300,\"data\":{\"rank\":1}}\n\
+                {\"timestamp\":600,\"gap\":{\"reason\":\"down, \\\"twice\\\"\"}}\n\
+                {\"timestamp\":900,\"da";
+    std::fs::write(dir.join("ranker.json"), json).unwrap();
+
+    let steps = [
+      ("json", "ranker.json", "csv", "ranker.csv"),
+      ("csv", "ranker.csv", "json", "copy.json"),
+    ];
+    for &(input_format, input, output_format, output) in &steps {
+      let args = MigrateArgs {
+        tracker_type: "ranker".to_owned(),
+        input_format: MigrationFormat::parse(input_format).unwrap(),
+        input_path: dir.join(input),
+        output_format: MigrationFormat::parse(output_format).unwrap(),
+        output_path: dir.join(output),
+        columns: None,
+      };
+      run::<ranker::DataPoint>(&args).unwrap();
+    }
+
+    assert_eq!(std::fs::read_to_string(dir.join("ranker.json")).unwrap(), json);
+    assert_eq!(
+      std::fs::read_to_string(dir.join("ranker.csv")).unwrap(),
+      "timestamp,rank,upvotes,downvotes,reranks,top5_reranks,gap\n\
+       1970-01-01 00:05:00,1,,,,,\n\
+       1970-01-01 00:10:00,,,,,,\"down, \"\"twice\"\"\"\n"
+    );
+    // the reasons of gaps are kept in the CSV files
+    assert_eq!(
+      std::fs::read_to_string(dir.join("copy.json")).unwrap(),
+      json[..json.rfind('\n').unwrap() + 1]
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+}
@@ -0,0 +1,225 @@
+use hyper::header::HeaderValue;
+use std::io::{self, Write};
+
+/// Bodies smaller than this aren't compressed because the savings don't
+/// outweigh the overhead of the encoding.
+pub const MIN_COMPRESSED_SIZE: usize = 1024;
+
+/// Parses a header with a list of values weighted by the `q` parameter, such
+/// as `Accept` or `Accept-Encoding`. Values are lowercased, their other
+/// parameters are dropped. Values with an invalid weight are skipped.
+pub fn parse_quality_list(header: &HeaderValue) -> Vec<(String, f32)> {
+  let header = match header.to_str() {
+    Ok(header) => header,
+    Err(_) => return vec![],
+  };
+
+  header
+    .split(',')
+    .filter_map(|item| {
+      let mut parts = item.split(';').map(str::trim);
+      let value = parts.next().filter(|value| !value.is_empty())?;
+
+      let mut quality = 1.0;
+      for param in parts {
+        if let Some(q) =
+          param.strip_prefix("q=").or_else(|| param.strip_prefix("Q="))
+        {
+          quality = q.parse().ok().filter(|q| (0.0..=1.0).contains(q))?;
+        }
+      }
+      Some((value.to_ascii_lowercase(), quality))
+    })
+    .collect()
+}
+
+/// Finds the weight of `value` in a parsed quality list. `wildcards` are tried
+/// in order if the value itself isn't listed.
+pub fn quality_of(
+  list: &[(String, f32)],
+  value: &str,
+  wildcards: &[&str],
+) -> f32 {
+  std::iter::once(value)
+    .chain(wildcards.iter().cloned())
+    .find_map(|value| {
+      list.iter().find(|(listed, _)| listed == value).map(|&(_, q)| q)
+    })
+    .unwrap_or(0.0)
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ContentEncoding {
+  Identity,
+  Gzip,
+  Brotli,
+}
+
+impl ContentEncoding {
+  /// Picks the encoding with the highest weight in the `Accept-Encoding`
+  /// header, Brotli is preferred when the weights are equal. Falls back to
+  /// the identity encoding if neither of the compressed ones is accepted.
+  /// With `prefer_identity`, e.g. for small bodies, the identity encoding is
+  /// picked unless the header excludes it.
+  pub fn negotiate(
+    accept_encoding: Option<&HeaderValue>,
+    prefer_identity: bool,
+  ) -> Self {
+    let list = match accept_encoding {
+      Some(header) => parse_quality_list(header),
+      None => return ContentEncoding::Identity,
+    };
+    // the identity encoding is acceptable unless it is listed with q=0
+    let identity_listed =
+      list.iter().any(|(value, _)| value == "identity" || value == "*");
+    if prefer_identity
+      && (!identity_listed || quality_of(&list, "identity", &["*"]) > 0.0)
+    {
+      return ContentEncoding::Identity;
+    }
+
+    let mut best = (ContentEncoding::Identity, 0.0);
+    for &encoding in &[ContentEncoding::Brotli, ContentEncoding::Gzip] {
+      let quality = quality_of(&list, encoding.as_str(), &["*"]);
+      if quality > best.1 {
+        best = (encoding, quality);
+      }
+    }
+    best.0
+  }
+
+  pub fn as_str(self) -> &'static str {
+    match self {
+      ContentEncoding::Identity => "identity",
+      ContentEncoding::Gzip => "gzip",
+      ContentEncoding::Brotli => "br",
+    }
+  }
+
+  pub fn encoder(self) -> Encoder {
+    match self {
+      ContentEncoding::Identity => Encoder::Identity,
+      ContentEncoding::Gzip => Encoder::Gzip(flate2::write::GzEncoder::new(
+        vec![],
+        flate2::Compression::default(),
+      )),
+      // quality 5 compresses better than gzip while being about as fast
+      ContentEncoding::Brotli => Encoder::Brotli(Box::new(
+        brotli::CompressorWriter::new(vec![], 4096, 5, 22),
+      )),
+    }
+  }
+}
+
+/// Encodes a body which is written in chunks.
+pub enum Encoder {
+  Identity,
+  Gzip(flate2::write::GzEncoder<Vec<u8>>),
+  Brotli(Box<brotli::CompressorWriter<Vec<u8>>>),
+}
+
+impl Encoder {
+  /// Returns the encoded bytes which are ready to be sent, which may be none
+  /// because the encoders buffer their input.
+  pub fn write(&mut self, bytes: Vec<u8>) -> io::Result<Vec<u8>> {
+    let output = match self {
+      Encoder::Identity => return Ok(bytes),
+      Encoder::Gzip(encoder) => {
+        encoder.write_all(&bytes)?;
+        encoder.get_mut()
+      }
+      Encoder::Brotli(encoder) => {
+        encoder.write_all(&bytes)?;
+        encoder.get_mut()
+      }
+    };
+    Ok(std::mem::take(output))
+  }
+
+  /// Returns the rest of the encoded bytes.
+  pub fn finish(self) -> io::Result<Vec<u8>> {
+    match self {
+      Encoder::Identity => Ok(vec![]),
+      Encoder::Gzip(encoder) => encoder.finish(),
+      Encoder::Brotli(encoder) => Ok(encoder.into_inner()),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::stats::Format;
+
+  #[test]
+  fn quality_lists_are_parsed() {
+    let cases: &[(&str, &[(&str, f32)])] = &[
+      ("", &[]),
+      ("gzip", &[("gzip", 1.0)]),
+      ("GZip;q=0.5, br", &[("gzip", 0.5), ("br", 1.0)]),
+      (" text/csv ; charset=utf-8 ;Q=0.2 ", &[("text/csv", 0.2)]),
+      ("identity;q=0, *;q=0.1", &[("identity", 0.0), ("*", 0.1)]),
+      // invalid weights and empty items are skipped
+      ("gzip;q=2, br;q=x, ,deflate", &[("deflate", 1.0)]),
+    ];
+    for (header, expected) in cases {
+      let list = parse_quality_list(&HeaderValue::from_static(header));
+      let expected: Vec<(String, f32)> = expected
+        .iter()
+        .map(|&(value, quality)| (value.to_owned(), quality))
+        .collect();
+      assert_eq!(list, expected, "{}", header);
+    }
+  }
+
+  #[test]
+  fn content_encoding_is_negotiated() {
+    use ContentEncoding::{Brotli, Gzip, Identity};
+    // the header, the encoding of large bodies and of small ones
+    let cases = &[
+      (None, Identity, Identity),
+      (Some(""), Identity, Identity),
+      (Some("gzip"), Gzip, Identity),
+      (Some("gzip, br"), Brotli, Identity),
+      (Some("gzip;q=1, br;q=0.8"), Gzip, Identity),
+      (Some("br;q=0, *"), Gzip, Identity),
+      (Some("*;q=0.5"), Brotli, Identity),
+      (Some("deflate"), Identity, Identity),
+      (Some("identity;q=0, gzip"), Gzip, Gzip),
+      (Some("*;q=0, br"), Brotli, Brotli),
+      (Some("*;q=0, identity, br"), Brotli, Identity),
+      // nothing is acceptable, so the body isn't encoded
+      (Some("identity;q=0"), Identity, Identity),
+    ];
+    for &(header, large, small) in cases {
+      let header = header.map(HeaderValue::from_static);
+      let negotiate =
+        |small| ContentEncoding::negotiate(header.as_ref(), small);
+      assert_eq!(
+        (negotiate(false), negotiate(true)),
+        (large, small),
+        "{:?}",
+        header
+      );
+    }
+  }
+
+  #[test]
+  fn format_is_negotiated() {
+    let cases = &[
+      (None, Some(Format::Json)),
+      (Some("*/*"), Some(Format::Json)),
+      (Some("text/csv"), Some(Format::Csv)),
+      (Some("text/*"), Some(Format::Csv)),
+      (Some("text/*;q=0.5, text/tab-separated-values"), Some(Format::Tsv)),
+      (Some("application/json;q=0.1, */*;q=0.2"), Some(Format::Csv)),
+      (Some("text/html, application/json;q=0.9"), Some(Format::Json)),
+      (Some("text/html"), None),
+      (Some("*/*;q=0"), None),
+    ];
+    for &(header, expected) in cases {
+      let header = header.map(HeaderValue::from_static);
+      assert_eq!(Format::negotiate(header.as_ref()), expected, "{:?}", header);
+    }
+  }
+}
@@ -1,17 +1,54 @@
 use serde::{Deserialize, Serialize};
 use std::io::{self, Write};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub struct Record<T> {
   pub timestamp: Timestamp,
-  pub data: T,
+  #[serde(flatten)]
+  pub value: RecordValue<T>,
 }
 
+impl<T> Record<T> {
+  pub fn data(&self) -> Option<&T> {
+    match &self.value {
+      RecordValue::Data(data) => Some(data),
+      RecordValue::Gap(_) => None,
+    }
+  }
+
+  pub fn gap(&self) -> Option<&Gap> {
+    match &self.value {
+      RecordValue::Data(_) => None,
+      RecordValue::Gap(gap) => Some(gap),
+    }
+  }
+}
+
+/// Stored as either a `data` or a `gap` key next to the `timestamp`.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RecordValue<T> {
+  Data(T),
+  /// A tick which didn't produce a data point, e.g. because the API was down.
+  Gap(Gap),
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Gap {
+  pub reason: String,
+}
+
+#[derive(Clone, Eq, PartialEq)]
 pub struct Timestamp {
   secs: i64,
   tm: time::Tm,
 }
 
+/// Range of the timestamps accepted by `Timestamp::parse`, from 0000-01-01 to
+/// 9999-12-31T23:59:59Z. Values far outside of it can't be converted to a date.
+const MIN_PARSED_SECS: i64 = -62_167_219_200;
+const MAX_PARSED_SECS: i64 = 253_402_300_799;
+
 impl Timestamp {
   pub fn new(secs: i64) -> Self {
     Self { secs, tm: time::at_utc(time::Timespec::new(secs, 0)) }
@@ -25,6 +62,97 @@ impl Timestamp {
     self.secs
   }
 
+  /// Parses either a UNIX timestamp in seconds or a date and time in the
+  /// ISO 8601 format, e.g. `2019-05-01`, `2019-05-01T12:00:00Z` or
+  /// `2019-05-01T15:00+03:00`. Time without an offset is assumed to be in UTC.
+  /// Returns `None` for timestamps outside of the years 0000 to 9999.
+  pub fn parse(s: &str) -> Option<Self> {
+    fn checked(secs: i64) -> Option<Timestamp> {
+      if (MIN_PARSED_SECS..=MAX_PARSED_SECS).contains(&secs) {
+        Some(Timestamp::new(secs))
+      } else {
+        None
+      }
+    }
+
+    fn digits(s: &mut &str, len: usize) -> Option<i64> {
+      let digits = s.get(..len)?;
+      if !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+      }
+      *s = &s[len..];
+      digits.parse().ok()
+    }
+
+    fn skip(s: &mut &str, c: char) -> bool {
+      match s.strip_prefix(c) {
+        Some(rest) => {
+          *s = rest;
+          true
+        }
+        None => false,
+      }
+    }
+
+    if let Ok(secs) = s.parse() {
+      return checked(secs);
+    }
+
+    let mut s = s;
+    let year = digits(&mut s, 4)?;
+    if !skip(&mut s, '-') {
+      return None;
+    }
+    let month = digits(&mut s, 2)?;
+    if !skip(&mut s, '-') {
+      return None;
+    }
+    let day = digits(&mut s, 2)?;
+
+    let (mut hour, mut minute, mut second, mut utc_offset) = (0, 0, 0, 0);
+    if skip(&mut s, 'T') || skip(&mut s, ' ') {
+      hour = digits(&mut s, 2)?;
+      if !skip(&mut s, ':') {
+        return None;
+      }
+      minute = digits(&mut s, 2)?;
+      if skip(&mut s, ':') {
+        second = digits(&mut s, 2)?;
+      }
+
+      if !skip(&mut s, 'Z') {
+        let sign = if skip(&mut s, '+') {
+          1
+        } else if skip(&mut s, '-') {
+          -1
+        } else {
+          0
+        };
+        if sign != 0 {
+          let offset_hours = digits(&mut s, 2)?;
+          skip(&mut s, ':');
+          let offset_minutes = digits(&mut s, 2)?;
+          utc_offset = sign * (offset_hours * 60 * 60 + offset_minutes * 60);
+        }
+      }
+    }
+
+    let valid = s.is_empty()
+      && (1..=12).contains(&month)
+      && (1..=days_in_month(year, month)).contains(&day)
+      && hour < 24
+      && minute < 60
+      && second < 60;
+    if !valid {
+      return None;
+    }
+
+    let days = days_from_civil(year, month, day);
+    checked(
+      days * 24 * 60 * 60 + hour * 60 * 60 + minute * 60 + second - utc_offset,
+    )
+  }
+
   pub fn format_to<W: Write>(&self, mut wr: W) -> io::Result<()> {
     fn write_padded_i32<W: Write>(
       mut wr: W,
@@ -60,6 +188,40 @@ impl Timestamp {
 
     Ok(())
   }
+
+  /// Formats the timestamp as an HTTP date, e.g.
+  /// `Sun, 06 Nov 1994 08:49:37 GMT`.
+  pub fn to_http_date(&self) -> String {
+    self.tm.rfc822().to_string()
+  }
+
+  /// Parses an HTTP date in the preferred format, the obsolete ones aren't
+  /// supported.
+  pub fn parse_http_date(s: &str) -> Option<Self> {
+    let tm = time::strptime(s, "%a, %d %b %Y %T GMT").ok()?;
+    Some(Self::new(tm.to_timespec().sec))
+  }
+}
+
+/// Number of days since 1970-01-01 in the proleptic Gregorian calendar, see
+/// <http://howardhinnant.github.io/date_algorithms.html#days_from_civil>.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+  let year = if month <= 2 { year - 1 } else { year };
+  let era = if year >= 0 { year } else { year - 399 } / 400;
+  let year_of_era = year - era * 400;
+  let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+  let day_of_era =
+    year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+  era * 146_097 + day_of_era - 719_468
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+  match month {
+    2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+    2 => 28,
+    4 | 6 | 9 | 11 => 30,
+    _ => 31,
+  }
 }
 
 use std::fmt;
@@ -86,3 +248,74 @@ impl Serialize for Timestamp {
     self.secs.serialize(serializer)
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn timestamps_are_parsed() {
+    let cases = &[
+      ("1560000000", Some(1_560_000_000)),
+      ("-86400", Some(-86_400)),
+      ("1970-01-01", Some(0)),
+      ("2019-05-01", Some(1_556_668_800)),
+      ("2019-05-01T12:00:00Z", Some(1_556_712_000)),
+      ("2019-05-01 12:00", Some(1_556_712_000)),
+      ("2019-05-01T15:00+03:00", Some(1_556_712_000)),
+      ("2019-05-01T09:30:00-0230", Some(1_556_712_000)),
+      ("2019-12-31T23:59:59Z", Some(1_577_836_799)),
+      ("1969-12-31T23:59:59Z", Some(-1)),
+      ("2019-5-01", None),
+      ("2019-05-01T12", None),
+      ("2019-05-01T12:00:00X", None),
+      ("2019-05-01T24:00", None),
+      ("2019-05-01T12:60", None),
+      ("2019-13-01", None),
+      ("2019-00-01", None),
+      ("2019-05-00", None),
+      ("2019-05-32", None),
+      ("", None),
+      ("0000-01-01", Some(-62_167_219_200)),
+      ("9999-12-31T23:59:59Z", Some(253_402_300_799)),
+      ("9999-12-31T23:59:59-01:00", None),
+      ("253402300799", Some(253_402_300_799)),
+      ("253402300800", None),
+      ("-62167219201", None),
+      ("99999999999999999", None),
+      ("-9223372036854775808", None),
+    ];
+    for &(s, expected) in cases {
+      let secs = Timestamp::parse(s).map(|timestamp| timestamp.as_secs());
+      assert_eq!(secs, expected, "{}", s);
+    }
+  }
+
+  #[test]
+  fn days_are_checked_against_the_month_length() {
+    let cases = &[
+      ("2019-01-31", true),
+      ("2019-02-28", true),
+      ("2019-02-29", false),
+      ("2019-02-31", false),
+      ("2020-02-29", true),
+      ("2020-02-30", false),
+      ("1900-02-29", false),
+      ("2000-02-29", true),
+      ("2019-04-30", true),
+      ("2019-04-31", false),
+      ("2019-06-31", false),
+      ("2019-09-31", false),
+      ("2019-11-31", false),
+      ("2019-12-31", true),
+    ];
+    for &(s, valid) in cases {
+      assert_eq!(Timestamp::parse(s).is_some(), valid, "{}", s);
+    }
+    assert_eq!(
+      Timestamp::parse("2020-03-01").unwrap().as_secs()
+        - Timestamp::parse("2020-02-29").unwrap().as_secs(),
+      24 * 60 * 60
+    );
+  }
+}
@@ -0,0 +1,361 @@
+//! Compaction of the old records of the trackers according to their
+//! `RetentionRule`s. The compacted records and the newer ones are copied into
+//! a `Rewrite` in chunks without blocking the trackers, only the records
+//! pushed in the meantime are copied under the database lock for writing,
+//! just before the copy replaces the database. The compaction runs on a
+//! blocking thread of the runtime, since it reads and writes whole files.
+
+use failure::{Error, Fail, Fallible};
+use log::info;
+
+use serde::de::DeserializeOwned;
+use serde::ser::Serialize;
+use std::fmt::Debug;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::prelude::*;
+
+use crate::config::RetentionRule;
+use crate::database::{compress_records, Database};
+use crate::record::{Record, Timestamp};
+use crate::shutdown::Shutdown;
+use crate::trackers::Columns;
+
+/// Number of records read from the database at once.
+const CHUNK: usize = 1000;
+
+/// Compacts the database of a tracker every `interval`, starting right away.
+pub fn start<T>(
+  tracker: String,
+  shared_db: Arc<RwLock<Database<T>>>,
+  rules: Arc<Vec<RetentionRule>>,
+  interval: Duration,
+  shutdown: Shutdown,
+) -> impl Future<Item = (), Error = ()>
+where
+  T: DeserializeOwned + Serialize + Columns + Clone + Eq + Debug + Send + Sync,
+  T: 'static,
+{
+  tokio::timer::Interval::new(Instant::now(), interval)
+    .map_err(|e: tokio::timer::Error| Error::from(e.context("timer error")))
+    .for_each(move |_: Instant| {
+      info!("compacting the database of tracker '{}'", tracker);
+      let tracker = tracker.clone();
+      let shared_db = shared_db.clone();
+      let rules = rules.clone();
+      let now = Timestamp::now();
+      future::poll_fn(move || {
+        tokio_threadpool::blocking(|| compact(&shared_db, &rules, &now))
+      })
+      .map_err(|e| Error::from(e.context("no blocking thread available")))
+      .map(move |result| {
+        if let Err(error) = result {
+          let context =
+            format!("failed to compact the database of tracker '{}'", tracker);
+          log_error!(log::Level::Warn, &error.context(context));
+        }
+      })
+    })
+    .map_err(|e| log_error!(log::Level::Error, e.as_fail()))
+    .select(shutdown)
+    .then(|r: Result<((), _), ((), _)>| match r {
+      Ok(((), _)) => Ok(()),
+      Err(((), _)) => Err(()),
+    })
+}
+
+/// Applies the rules to the records which are old enough at `now`. The
+/// database is rewritten only if some records have been dropped. The
+/// compacted records are collected in memory, there is at most one for every
+/// bucket and record changing the value.
+pub fn compact<T>(
+  shared_db: &RwLock<Database<T>>,
+  rules: &[RetentionRule],
+  now: &Timestamp,
+) -> Fallible<()>
+where
+  T: DeserializeOwned + Serialize + Columns + Clone + Eq + Debug + Send + Sync,
+  T: 'static,
+{
+  let mut rules: Vec<&RetentionRule> = rules.iter().collect();
+  rules.sort_by_key(|rule| rule.after());
+  let youngest = match rules.first() {
+    Some(rule) => rule.after().as_secs() as i64,
+    None => return Ok(()),
+  };
+
+  let latest = match shared_db.read().unwrap().latest()? {
+    Some(record) => record.timestamp.as_secs(),
+    None => return Ok(()),
+  };
+  // records pushed during the compaction are newer than the latest one
+  let cutoff = Timestamp::new((now.as_secs() - youngest).min(latest - 1));
+
+  let mut sampler = Sampler {
+    rules: &rules,
+    now: now.as_secs(),
+    bucket: None,
+    records: vec![],
+    read: 0,
+  };
+  let mut to = cutoff.clone();
+  'chunks: loop {
+    let mut chunk =
+      shared_db.read().unwrap().range(None, Some(&to), true, Some(CHUNK))?;
+    let is_last = chunk.len() < CHUNK;
+    if !is_last {
+      // the records with the oldest timestamp may continue in the next chunk,
+      // so they are read again as a part of it
+      let oldest = chunk.last().unwrap().timestamp.as_secs();
+      chunk.retain(|record| record.timestamp.as_secs() != oldest);
+      if chunk.is_empty() {
+        return Err(failure::format_err!(
+          "more than {} records have the same timestamp {}",
+          CHUNK,
+          oldest
+        ));
+      }
+      to = Timestamp::new(oldest);
+    }
+
+    for record in chunk {
+      if !sampler.push(record) {
+        break 'chunks;
+      }
+    }
+    if is_last {
+      break;
+    }
+  }
+
+  // the deleted records are counted as read even if the reading stopped at
+  // the first one
+  let read = sampler.read;
+  let mut records: Vec<Record<T>> =
+    compress_records(sampler.records.iter()).cloned().collect();
+  if records.len() == read {
+    info!("nothing to compact in {} records up to {:?}", read, cutoff);
+    return Ok(());
+  }
+  records.reverse();
+  info!("compacted records up to {:?} into {} ones", cutoff, records.len());
+
+  let mut rewrite = shared_db.read().unwrap().start_rewrite()?;
+  for record in records {
+    rewrite.push(record)?;
+  }
+
+  // the newer records are copied from the oldest one, the first `skip` ones
+  // from `from` have already been copied
+  let mut from = Timestamp::new(cutoff.as_secs() + 1);
+  let mut skip = 0;
+  loop {
+    let limit = skip + CHUNK;
+    let chunk =
+      shared_db.read().unwrap().range(Some(&from), None, false, Some(limit))?;
+    if chunk.len() < limit {
+      // the rest is copied together with the records pushed in the meantime
+      break;
+    }
+    let newest = chunk.last().unwrap().timestamp.as_secs();
+    let newest_count = chunk
+      .iter()
+      .rev()
+      .take_while(|record| record.timestamp.as_secs() == newest)
+      .count();
+    for record in chunk.into_iter().skip(skip) {
+      rewrite.push(record)?;
+    }
+    // the chunk ends with all of the records with the timestamp `newest` if
+    // it starts with them
+    from = Timestamp::new(newest);
+    skip = newest_count;
+  }
+
+  let mut db = shared_db.write().unwrap();
+  for record in db.range(Some(&from), None, false, None)?.into_iter().skip(skip)
+  {
+    rewrite.push(record)?;
+  }
+  db.finish_rewrite(rewrite)
+}
+
+/// Thins out the records which are pushed from the newest to the oldest one.
+struct Sampler<'a, T> {
+  /// Sorted by the age which they apply after.
+  rules: &'a [&'a RetentionRule],
+  now: i64,
+  /// The rule and the start of the bucket of the last kept record.
+  bucket: Option<(usize, i64)>,
+  /// The kept records, the newest ones first.
+  records: Vec<Record<T>>,
+  /// Number of the pushed records.
+  read: usize,
+}
+
+impl<'a, T> Sampler<'a, T> {
+  /// Returns `false` if all of the older records are going to be deleted, so
+  /// they don't have to be read.
+  fn push(&mut self, record: Record<T>) -> bool {
+    self.read += 1;
+    let age = self.now - record.timestamp.as_secs();
+    let rule_index = match self
+      .rules
+      .iter()
+      .rposition(|rule| rule.after().as_secs() as i64 <= age)
+    {
+      Some(index) => index,
+      None => {
+        self.records.push(record);
+        return true;
+      }
+    };
+
+    match self.rules[rule_index] {
+      RetentionRule::Sample { bucket, .. } => {
+        let bucket = Some((rule_index, bucket.bucket_start(&record.timestamp)));
+        if bucket != self.bucket {
+          self.bucket = bucket;
+          self.records.push(record);
+        } else if record.data().is_some() {
+          // the newest record of the bucket is kept unless it is a gap
+          let kept = self.records.last_mut().unwrap();
+          if kept.data().is_none() {
+            *kept = record;
+          }
+        }
+        true
+      }
+      RetentionRule::Delete { .. } => rule_index + 1 < self.rules.len(),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::aggregate::BucketSize;
+  use crate::config::{ResidentRecords, StorageFormat, SyncMode};
+  use crate::record::{Gap, RecordValue};
+  use crate::testing::TempPath;
+
+  const HOUR: i64 = 60 * 60;
+  const DAY: i64 = 24 * HOUR;
+
+  fn rules() -> Vec<RetentionRule> {
+    vec![
+      RetentionRule::Delete { after: Duration::from_secs(10 * DAY as u64) },
+      RetentionRule::Sample {
+        after: Duration::from_secs(DAY as u64),
+        bucket: BucketSize::parse("1h").unwrap(),
+      },
+      RetentionRule::Sample {
+        after: Duration::from_secs(3 * DAY as u64),
+        bucket: BucketSize::parse("1d").unwrap(),
+      },
+    ]
+  }
+
+  #[test]
+  fn old_records_are_compacted() {
+    let now = 12 * DAY;
+    // a record every 5 minutes, the value changes every 4 hours and there
+    // is an hour of gaps every day, the raw records of the last day are
+    // copied in chunks, so there are more of them and pairs of them share a
+    // timestamp
+    let raw_timestamps =
+      (now - DAY + 1..now).step_by(45).flat_map(|t| vec![t, t]);
+    let records: Vec<Record<u64>> = (0..=(now - DAY) / 300)
+      .map(|index| index * 300)
+      .chain(raw_timestamps)
+      .map(|timestamp| {
+        let value = if timestamp % DAY >= 23 * HOUR {
+          RecordValue::Gap(Gap { reason: "down".to_owned() })
+        } else {
+          RecordValue::Data((timestamp / (4 * HOUR)) as u64)
+        };
+        Record { timestamp: Timestamp::new(timestamp), value }
+      })
+      .collect();
+
+    for &format in
+      &[StorageFormat::Json, StorageFormat::Binary, StorageFormat::Sqlite]
+    {
+      let path = TempPath::new(&format!("retention.{}", format.extension()));
+      let mut db = Database::<u64>::init(
+        &path,
+        format,
+        SyncMode::Never,
+        ResidentRecords::Newest(100),
+      )
+      .unwrap();
+      for record in &records {
+        db.push(record.clone()).unwrap();
+      }
+      let shared_db = RwLock::new(db);
+
+      compact(&shared_db, &rules(), &Timestamp::new(now)).unwrap();
+      let compacted = shared_db.read().unwrap().range(None, None, false, None);
+      let compacted = compacted.unwrap();
+
+      // the raw records are untouched
+      let raw: Vec<&Record<u64>> = compacted
+        .iter()
+        .filter(|record| record.timestamp.as_secs() > now - DAY)
+        .collect();
+      let expected: Vec<&Record<u64>> = records
+        .iter()
+        .filter(|record| record.timestamp.as_secs() > now - DAY)
+        .collect();
+      assert_eq!(raw, expected);
+
+      let timestamps: Vec<i64> = compacted
+        .iter()
+        .map(|record| record.timestamp.as_secs())
+        .filter(|&timestamp| timestamp <= now - DAY)
+        .collect();
+      let minutes_55 = 55 * 60;
+      // the newest record with data of every day older than 3 days, the
+      // records older than 10 days are deleted
+      let mut expected: Vec<i64> =
+        (2..9).map(|day| day * DAY + 22 * HOUR + minutes_55).collect();
+      expected.push(9 * DAY);
+      // the newest record of every hour, but only the first and the last
+      // ones of every value
+      for day in 9..11 {
+        for first_hour in (0..24).step_by(4) {
+          let last_hour = if first_hour == 20 { 22 } else { first_hour + 3 };
+          if day != 9 || first_hour != 0 {
+            expected.push(day * DAY + first_hour * HOUR + minutes_55);
+          }
+          expected.push(day * DAY + last_hour * HOUR + minutes_55);
+        }
+        // the newest gap of the hour without data
+        expected.push(day * DAY + 23 * HOUR + minutes_55);
+      }
+      expected.push(11 * DAY);
+      assert_eq!(timestamps, expected);
+
+      // the compaction is idempotent and pushing still works
+      compact(&shared_db, &rules(), &Timestamp::new(now)).unwrap();
+      let mut db = shared_db.into_inner().unwrap();
+      assert_eq!(db.range(None, None, false, None).unwrap(), compacted);
+      let record =
+        Record { timestamp: Timestamp::new(now), value: RecordValue::Data(1) };
+      db.push(record.clone()).unwrap();
+      db.write().unwrap();
+      drop(db);
+
+      let db = Database::<u64>::init(
+        &path,
+        format,
+        SyncMode::Never,
+        ResidentRecords::Newest(100),
+      )
+      .unwrap();
+      let mut reopened = db.range(None, None, false, None).unwrap();
+      assert_eq!(reopened.pop(), Some(record));
+      assert_eq!(reopened, compacted);
+    }
+  }
+}
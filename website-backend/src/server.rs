@@ -1,39 +1,57 @@
 use failure::{AsFail, Error, Fallible};
 use log::info;
 
-use std::sync::{Arc, RwLock};
+use futures::future::Shared;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::prelude::*;
+use tokio::timer::Interval;
 
-use hyper::header::{self, HeaderValue};
+use hyper::header::{self, HeaderMap, HeaderValue};
 use hyper::server::conn::AddrStream;
 use hyper::service::{make_service_fn, Service};
 use hyper::{Body, Method, Request, Response, StatusCode};
 use std::net::SocketAddr;
 
-use crate::database::Database;
+use serde::Serialize;
+
+use crate::broadcast::{PushedRecord, Subscription};
+use crate::negotiate::{ContentEncoding, Encoder, MIN_COMPRESSED_SIZE};
+use crate::record::Timestamp;
 use crate::shutdown::Shutdown;
-use crate::trackers::ranker;
+use crate::stats::{
+  DatabaseVersion, Format, StatsCursor, StatsDatabase, StatsQuery,
+};
+use crate::trackers::TrackerInfo;
+use crate::websocket;
 
 type HttpRequest = Request<Body>;
 type HttpResponse = Response<Body>;
 
 pub fn start(
-  config: crate::config::ServerConfig,
-  shared_db: Arc<RwLock<Database<ranker::DataPoint>>>,
+  address: SocketAddr,
+  trackers: Vec<TrackerInfo>,
   shutdown: Shutdown,
 ) -> impl Future<Item = (), Error = ()> {
-  info!("starting on {}", config.address);
+  info!("starting on {}", address);
 
+  let trackers = Arc::new(trackers);
+  // event streams are closed on shutdown, otherwise the graceful shutdown
+  // would wait for them forever
+  let shutdown = shutdown.shared();
+  let handler_shutdown = shutdown.clone();
   let make_service = make_service_fn(move |socket: &AddrStream| {
     future::ok::<Handler, Error>(Handler {
       remote_addr: socket.remote_addr(),
-      shared_db: shared_db.clone(),
+      trackers: trackers.clone(),
+      shutdown: handler_shutdown.clone(),
     })
   });
 
-  let server = hyper::Server::bind(&config.address)
+  let server = hyper::Server::bind(&address)
     .serve(make_service)
-    .with_graceful_shutdown(shutdown);
+    .with_graceful_shutdown(shutdown.then(|_| Ok::<(), ()>(())));
   server.map_err(|e| log_error!(log::Level::Error, e.as_fail())).then(|r| {
     info!("stopping");
     r
@@ -42,7 +60,8 @@ pub fn start(
 
 pub struct Handler {
   remote_addr: SocketAddr,
-  shared_db: Arc<RwLock<Database<ranker::DataPoint>>>,
+  trackers: Arc<Vec<TrackerInfo>>,
+  shutdown: Shared<Shutdown>,
 }
 
 impl Service for Handler {
@@ -52,12 +71,12 @@ impl Service for Handler {
   type Future =
     Box<dyn Future<Item = Response<Self::ResBody>, Error = Self::Error> + Send>;
 
-  fn call(&mut self, req: Request<Self::ReqBody>) -> Self::Future {
-    use std::time::{Duration, Instant};
+  fn call(&mut self, mut req: Request<Self::ReqBody>) -> Self::Future {
     let start_time = Instant::now();
 
-    let method = req.method();
-    let uri = req.uri();
+    // cloned because the body of the request is taken by WebSocket upgrades
+    let method = &req.method().clone();
+    let uri = &req.uri().clone();
     let version = req.version();
     let headers = req.headers();
 
@@ -88,12 +107,29 @@ impl Service for Handler {
       }
 
       let handler_result: Fallible<_> = match &path_segments[..] {
-        ["ranker", "stats.json"] => route! {
-          GET => self.get_json_stats(&req),
+        ["trackers"] => route! {
+          GET => self.get_trackers(),
+        },
+        ["ws"] => route! {
+          GET => self.get_websocket(&mut req),
+        },
+        [id, "events"] => route! {
+          GET => self.get_events(id, &req),
+        },
+        [id, "latest.json"] => route! {
+          GET => self.get_latest(id),
         },
-        ["ranker", "stats.csv"] => route! {
-          GET => self.get_csv_stats(&req),
+        [id, "stats"] => route! {
+          GET => self.get_stats(id, &req, None),
         },
+        [id, file] => {
+          match file.strip_prefix("stats.").and_then(Format::from_extension) {
+            Some(format) => route! {
+              GET => self.get_stats(id, &req, Some(format)),
+            },
+            None => Ok(simple_status_response(StatusCode::NOT_FOUND)),
+          }
+        }
         _ => Ok(simple_status_response(StatusCode::NOT_FOUND)),
       };
 
@@ -127,68 +163,550 @@ fn simple_status_response(status: StatusCode) -> Response<Body> {
   res
 }
 
+fn json_response(json_bytes: Vec<u8>) -> Response<Body> {
+  let mut res = Response::new(Body::from(json_bytes));
+  res
+    .headers_mut()
+    .insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+  res
+}
+
+fn bad_request_response(message: String) -> Response<Body> {
+  let mut res = Response::new(Body::from(message));
+  *res.status_mut() = StatusCode::BAD_REQUEST;
+  res
+    .headers_mut()
+    .insert(header::CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+  res
+}
+
 impl Handler {
-  fn get_json_stats(&mut self, _req: &HttpRequest) -> Fallible<HttpResponse> {
-    let db = self.shared_db.read().unwrap();
-
-    let mut json_bytes: Vec<u8> = vec![];
-    json_bytes.push(b'[');
-
-    db.compress_records(|record| {
-      json_bytes.push(b'[');
-      itoa::write(&mut json_bytes, record.timestamp.as_secs()).unwrap();
-      json_bytes.push(b',');
-      itoa::write(&mut json_bytes, record.data.rank).unwrap();
-      json_bytes.push(b',');
-      itoa::write(&mut json_bytes, record.data.upvotes).unwrap();
-      json_bytes.push(b',');
-      itoa::write(&mut json_bytes, record.data.downvotes).unwrap();
-      json_bytes.push(b',');
-      itoa::write(&mut json_bytes, record.data.reranks).unwrap();
-      json_bytes.push(b',');
-      itoa::write(&mut json_bytes, record.data.top5_reranks).unwrap();
-      json_bytes.push(b']');
-      json_bytes.push(b',');
-    });
-
-    json_bytes.pop();
-    json_bytes.push(b']');
-
-    let mut res = Response::new(Body::from(json_bytes));
-    res.headers_mut().insert(
+  fn tracker(&self, id: &str) -> Option<&TrackerInfo> {
+    self.trackers.iter().find(|tracker| tracker.id == id)
+  }
+
+  fn get_trackers(&mut self) -> Fallible<HttpResponse> {
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct TrackerEntry<'a> {
+      id: &'a str,
+      #[serde(rename = "type")]
+      type_name: &'a str,
+      /// In seconds, like in the config.
+      request_interval: u64,
+      records: usize,
+    }
+
+    let entries = self
+      .trackers
+      .iter()
+      .map(|tracker| {
+        Ok(TrackerEntry {
+          id: &tracker.id,
+          type_name: &tracker.type_name,
+          request_interval: tracker.request_interval.as_secs(),
+          records: tracker.database.record_count()?,
+        })
+      })
+      .collect::<Fallible<Vec<TrackerEntry>>>()?;
+    Ok(json_response(serde_json::to_vec(&entries)?))
+  }
+
+  /// The format is chosen with the `Accept` header if the path doesn't have
+  /// an extension. Large bodies are compressed if the client accepts that.
+  fn get_stats(
+    &mut self,
+    id: &str,
+    req: &HttpRequest,
+    format: Option<Format>,
+  ) -> Fallible<HttpResponse> {
+    let tracker = match self.tracker(id) {
+      Some(tracker) => tracker,
+      None => return Ok(simple_status_response(StatusCode::NOT_FOUND)),
+    };
+    let headers = req.headers();
+    let (format, vary) = match format {
+      Some(format) => (format, "Accept-Encoding"),
+      None => match Format::negotiate(headers.get(header::ACCEPT)) {
+        Some(format) => (format, "Accept, Accept-Encoding"),
+        None => return Ok(simple_status_response(StatusCode::NOT_ACCEPTABLE)),
+      },
+    };
+    let query = match StatsQuery::parse(req.uri().query().unwrap_or("")) {
+      Ok(query) => query,
+      Err(message) => return Ok(bad_request_response(message)),
+    };
+
+    let version = tracker.database.version()?;
+    // a weak tag because the compressed bodies share it
+    let etag = format!(
+      "W/\"{}-{}-{}\"",
+      version.records,
+      version.last_timestamp.as_ref().map_or(0, Timestamp::as_secs),
+      format.extension(),
+    );
+    let last_modified =
+      version.last_timestamp.as_ref().map(Timestamp::to_http_date);
+
+    let mut res = if is_not_modified(headers, &etag, &version) {
+      simple_status_response(StatusCode::NOT_MODIFIED)
+    } else {
+      stats_body(tracker, headers, query, format)?
+    };
+
+    let res_headers = res.headers_mut();
+    res_headers.insert(header::ETAG, HeaderValue::from_str(&etag)?);
+    if let Some(last_modified) = last_modified {
+      res_headers
+        .insert(header::LAST_MODIFIED, HeaderValue::from_str(&last_modified)?);
+    }
+    res_headers.insert(header::VARY, HeaderValue::from_static(vary));
+    Ok(res)
+  }
+
+  /// Streams records as Server-Sent Events as soon as they are pushed. The
+  /// `Last-Event-ID` header, which contains the timestamp of the last
+  /// received record, is used to send the records missed while reconnecting.
+  fn get_events(
+    &mut self,
+    id: &str,
+    req: &HttpRequest,
+  ) -> Fallible<HttpResponse> {
+    let tracker = match self.tracker(id) {
+      Some(tracker) => tracker,
+      None => return Ok(simple_status_response(StatusCode::NOT_FOUND)),
+    };
+
+    let last_event_id: Option<i64> = req
+      .headers()
+      .get("last-event-id")
+      .and_then(|value| value.to_str().ok())
+      .and_then(|value| value.trim().parse().ok());
+
+    let stream = EventStream {
+      records: tracker.subscribe(last_event_id)?,
+      keep_alive: Interval::new(
+        Instant::now() + KEEP_ALIVE_INTERVAL,
+        KEEP_ALIVE_INTERVAL,
+      ),
+      shutdown: self.shutdown.clone(),
+    };
+
+    let mut res = Response::new(Body::wrap_stream(stream));
+    let res_headers = res.headers_mut();
+    res_headers.insert(
       header::CONTENT_TYPE,
-      HeaderValue::from_static("application/json"),
+      HeaderValue::from_static("text/event-stream"),
     );
+    res_headers
+      .insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
     Ok(res)
   }
 
-  fn get_csv_stats(&mut self, _req: &HttpRequest) -> Fallible<HttpResponse> {
-    let db = self.shared_db.read().unwrap();
+  /// Upgrades the connection to the WebSocket protocol, the messages are
+  /// described in the `websocket` module.
+  fn get_websocket(&mut self, req: &mut HttpRequest) -> Fallible<HttpResponse> {
+    let accept_key = match websocket::accept_key(req.headers()) {
+      Some(accept_key) => accept_key,
+      None => {
+        return Ok(bad_request_response(
+          "expected a WebSocket handshake".to_owned(),
+        ))
+      }
+    };
+
+    let remote_addr = self.remote_addr;
+    let trackers = self.trackers.clone();
+    let shutdown = self.shutdown.clone();
+    let body = std::mem::replace(req.body_mut(), Body::empty());
+    tokio::spawn(
+      body
+        .on_upgrade()
+        .map_err(|error| {
+          log_error!(
+            log::Level::Warn,
+            &Error::from(error).context("WebSocket upgrade error")
+          );
+        })
+        .and_then(move |upgraded| {
+          websocket::serve(upgraded, remote_addr, trackers, shutdown)
+        }),
+    );
 
-    let mut csv_bytes: Vec<u8> = vec![];
-    csv_bytes.extend_from_slice(
-      b"timestamp,rank,upvotes,downvotes,reranks,top5_reranks\n",
+    let mut res = simple_status_response(StatusCode::SWITCHING_PROTOCOLS);
+    let res_headers = res.headers_mut();
+    res_headers.insert(header::UPGRADE, HeaderValue::from_static("websocket"));
+    res_headers.insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+    res_headers.insert(
+      header::SEC_WEBSOCKET_ACCEPT,
+      HeaderValue::from_str(&accept_key)?,
     );
+    Ok(res)
+  }
+
+  fn get_latest(&mut self, id: &str) -> Fallible<HttpResponse> {
+    let tracker = match self.tracker(id) {
+      Some(tracker) => tracker,
+      None => return Ok(simple_status_response(StatusCode::NOT_FOUND)),
+    };
+    let json_bytes = match tracker.database.latest_json()? {
+      Some(json_bytes) => json_bytes,
+      None => return Ok(simple_status_response(StatusCode::NOT_FOUND)),
+    };
 
-    db.compress_records(|record| {
-      record.timestamp.format_to(&mut csv_bytes).unwrap();
-      csv_bytes.push(b',');
-      itoa::write(&mut csv_bytes, record.data.rank).unwrap();
-      csv_bytes.push(b',');
-      itoa::write(&mut csv_bytes, record.data.upvotes).unwrap();
-      csv_bytes.push(b',');
-      itoa::write(&mut csv_bytes, record.data.downvotes).unwrap();
-      csv_bytes.push(b',');
-      itoa::write(&mut csv_bytes, record.data.reranks).unwrap();
-      csv_bytes.push(b',');
-      itoa::write(&mut csv_bytes, record.data.top5_reranks).unwrap();
-      csv_bytes.push(b'\n');
-    });
-
-    let mut res = Response::new(Body::from(csv_bytes));
-    res
-      .headers_mut()
-      .insert(header::CONTENT_TYPE, HeaderValue::from_static("text/csv"));
+    let mut res = json_response(json_bytes);
+    // pages which show the stats are served from a different origin
+    res.headers_mut().insert(
+      header::ACCESS_CONTROL_ALLOW_ORIGIN,
+      HeaderValue::from_static("*"),
+    );
     Ok(res)
   }
 }
+
+/// Streams the stats, large bodies are compressed if the client accepts that.
+/// The first chunk is written right away to find out whether the body is large.
+fn stats_body(
+  tracker: &TrackerInfo,
+  headers: &HeaderMap,
+  query: StatsQuery,
+  format: Format,
+) -> Fallible<HttpResponse> {
+  let database = tracker.database.clone();
+  let mut cursor = database.stats_cursor(query, format)?;
+  let mut first_chunk = database.write_stats_chunk(&mut cursor)?;
+  let next_chunk = database.write_stats_chunk(&mut cursor)?;
+  let finished = next_chunk.is_empty();
+  first_chunk.extend_from_slice(&next_chunk);
+
+  let is_small = finished && first_chunk.len() < MIN_COMPRESSED_SIZE;
+  let encoding =
+    ContentEncoding::negotiate(headers.get(header::ACCEPT_ENCODING), is_small);
+  let mut encoder = encoding.encoder();
+  let first_chunk = encoder.write(first_chunk)?;
+
+  let stream = StatsStream {
+    database,
+    cursor,
+    encoder: Some(encoder),
+    first_chunk: Some(first_chunk),
+  };
+  let mut res = Response::new(Body::wrap_stream(stream));
+  let res_headers = res.headers_mut();
+  res_headers.insert(
+    header::CONTENT_TYPE,
+    HeaderValue::from_static(format.content_type()),
+  );
+  if encoding != ContentEncoding::Identity {
+    res_headers.insert(
+      header::CONTENT_ENCODING,
+      HeaderValue::from_static(encoding.as_str()),
+    );
+  }
+  Ok(res)
+}
+
+/// Body of a stats response, the database is locked only while a chunk is
+/// written, so the trackers don't wait for slow clients.
+struct StatsStream {
+  database: Arc<dyn StatsDatabase>,
+  cursor: StatsCursor,
+  /// Taken when the stream ends.
+  encoder: Option<Encoder>,
+  first_chunk: Option<Vec<u8>>,
+}
+
+impl Stream for StatsStream {
+  type Item = Vec<u8>;
+  type Error = io::Error;
+
+  fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+    if let Some(chunk) = self.first_chunk.take() {
+      if !chunk.is_empty() {
+        return Ok(Async::Ready(Some(chunk)));
+      }
+    }
+
+    while let Some(encoder) = &mut self.encoder {
+      let chunk = self
+        .database
+        .write_stats_chunk(&mut self.cursor)
+        .map_err(|error| io::Error::other(error.compat()))?;
+      let chunk = if chunk.is_empty() {
+        self.encoder.take().unwrap().finish()?
+      } else {
+        encoder.write(chunk)?
+      };
+      if !chunk.is_empty() {
+        return Ok(Async::Ready(Some(chunk)));
+      }
+    }
+    Ok(Async::Ready(None))
+  }
+}
+
+/// Comments are sent periodically so that proxies don't close idle streams.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Body of an event stream, ends when the server is shut down.
+struct EventStream {
+  records: Subscription,
+  keep_alive: Interval,
+  shutdown: Shared<Shutdown>,
+}
+
+impl EventStream {
+  fn event(record: &PushedRecord) -> Vec<u8> {
+    format!(
+      "id: {}\nevent: record\ndata: {}\n\n",
+      record.timestamp, record.json
+    )
+    .into_bytes()
+  }
+}
+
+impl Stream for EventStream {
+  type Item = Vec<u8>;
+  type Error = io::Error;
+
+  fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+    match self.shutdown.poll() {
+      Ok(Async::NotReady) => {}
+      _ => return Ok(Async::Ready(None)),
+    }
+
+    match self.records.poll() {
+      Ok(Async::Ready(Some(record))) => {
+        return Ok(Async::Ready(Some(Self::event(&record))));
+      }
+      // the database is gone, so there will be no more records
+      Ok(Async::Ready(None)) | Err(()) => return Ok(Async::Ready(None)),
+      Ok(Async::NotReady) => {}
+    }
+
+    match self.keep_alive.poll() {
+      Ok(Async::Ready(_)) => {
+        Ok(Async::Ready(Some(b": keep-alive\n\n".to_vec())))
+      }
+      Ok(Async::NotReady) => Ok(Async::NotReady),
+      Err(error) => Err(io::Error::other(error)),
+    }
+  }
+}
+
+/// Checks the conditional request headers, `If-None-Match` takes precedence
+/// over `If-Modified-Since` as required by RFC 7232.
+fn is_not_modified(
+  headers: &HeaderMap,
+  etag: &str,
+  version: &DatabaseVersion,
+) -> bool {
+  if let Some(if_none_match) = headers.get(header::IF_NONE_MATCH) {
+    let if_none_match = if_none_match.to_str().unwrap_or("");
+    let opaque_tag = |tag: &str| tag.trim().trim_start_matches("W/").to_owned();
+    let etag = opaque_tag(etag);
+    return if_none_match
+      .split(',')
+      .any(|tag| tag.trim() == "*" || opaque_tag(tag) == etag);
+  }
+
+  let if_modified_since = headers
+    .get(header::IF_MODIFIED_SINCE)
+    .and_then(|value| value.to_str().ok())
+    .and_then(Timestamp::parse_http_date);
+  match (if_modified_since, &version.last_timestamp) {
+    (Some(since), Some(last)) => last.as_secs() <= since.as_secs(),
+    _ => false,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::database::Database;
+  use crate::record::{Record, RecordValue};
+  use crate::testing::{temp_database, TempPath};
+  use std::sync::RwLock;
+  use tokio::runtime::current_thread::Runtime;
+
+  /// A server with the tracker `test`, which has a record with the value 1
+  /// at every timestamp.
+  struct TestServer {
+    handler: Handler,
+    shared_db: Arc<RwLock<Database<u64>>>,
+    /// Dropped to shut the server down.
+    shutdown: Option<Shutdown>,
+    /// Declared last, so that the file is removed after the database is closed.
+    _path: TempPath,
+  }
+
+  impl TestServer {
+    fn new(name: &str, timestamps: &[i64]) -> Self {
+      let (path, db) = temp_database(&format!("server-{}", name));
+      let shared_db = Arc::new(RwLock::new(db));
+      let tracker = TrackerInfo {
+        id: "test".to_owned(),
+        type_name: "test".to_owned(),
+        request_interval: Duration::from_secs(60),
+        broadcast: shared_db.read().unwrap().broadcast().clone(),
+        database: shared_db.clone(),
+      };
+      let shutdown = Shutdown::new();
+      let handler = Handler {
+        remote_addr: ([127, 0, 0, 1], 0).into(),
+        trackers: Arc::new(vec![tracker]),
+        shutdown: shutdown.another().shared(),
+      };
+      let server =
+        Self { handler, shared_db, shutdown: Some(shutdown), _path: path };
+      for &timestamp in timestamps {
+        server.push(timestamp);
+      }
+      server
+    }
+
+    fn push(&self, timestamp: i64) {
+      let value = RecordValue::Data(1);
+      let record = Record { timestamp: Timestamp::new(timestamp), value };
+      self.shared_db.write().unwrap().push(record).unwrap();
+    }
+
+    fn get(&mut self, path: &str, headers: &[(&str, &str)]) -> HttpResponse {
+      let mut req = Request::get(path);
+      for (name, value) in headers {
+        req.header(*name, *value);
+      }
+      self.handler.call(req.body(Body::empty()).unwrap()).wait().unwrap()
+    }
+  }
+
+  fn http_date(timestamp: i64) -> String {
+    Timestamp::new(timestamp).to_http_date()
+  }
+
+  #[test]
+  fn unchanged_stats_are_not_modified() {
+    let mut server = TestServer::new("etag", &[100, 200, 300]);
+    let res = server.get("/test/stats.json", &[]);
+    assert_eq!(res.status(), StatusCode::OK);
+    let etag = r#"W/"3-300-json""#;
+    assert_eq!(res.headers()[header::ETAG], etag);
+    assert_eq!(res.headers()[header::LAST_MODIFIED], http_date(300).as_str());
+    assert_eq!(res.headers()[header::VARY], "Accept-Encoding");
+
+    // weak comparison, the tag is also matched in a list or as a wildcard
+    for &if_none_match in
+      &[etag, r#""3-300-json""#, r#""other", W/"3-300-json""#, "*"]
+    {
+      let res =
+        server.get("/test/stats.json", &[("If-None-Match", if_none_match)]);
+      assert_eq!(res.status(), StatusCode::NOT_MODIFIED, "{}", if_none_match);
+      assert_eq!(res.headers()[header::ETAG], etag);
+    }
+    // the tag depends on the format
+    let res = server.get("/test/stats.csv", &[("If-None-Match", etag)]);
+    assert_eq!(res.status(), StatusCode::OK);
+
+    server.push(400);
+    let res = server.get("/test/stats.json", &[("If-None-Match", etag)]);
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers()[header::ETAG], r#"W/"4-400-json""#);
+  }
+
+  #[test]
+  fn if_none_match_takes_precedence_over_if_modified_since() {
+    let mut server = TestServer::new("modified", &[100, 200, 300]);
+    let mut status = |headers: &[(&str, &str)]| {
+      server.get("/test/stats.json", headers).status()
+    };
+
+    let (modified, not_modified) = (StatusCode::OK, StatusCode::NOT_MODIFIED);
+    let (earlier, last) = (http_date(299), http_date(300));
+    assert_eq!(status(&[("If-Modified-Since", &last)]), not_modified);
+    assert_eq!(status(&[("If-Modified-Since", &http_date(500))]), not_modified);
+    assert_eq!(status(&[("If-Modified-Since", &earlier)]), modified);
+    assert_eq!(status(&[("If-Modified-Since", "yesterday")]), modified);
+
+    let other_tag = ("If-None-Match", r#"W/"2-200-json""#);
+    assert_eq!(status(&[other_tag, ("If-Modified-Since", &last)]), modified);
+    let tag = ("If-None-Match", r#"W/"3-300-json""#);
+    assert_eq!(status(&[tag, ("If-Modified-Since", &earlier)]), not_modified);
+  }
+
+  /// Reads `count` chunks of the body, or all of them if it ends earlier.
+  fn read_chunks(
+    runtime: &mut Runtime,
+    body: &mut Body,
+    count: u64,
+  ) -> Vec<String> {
+    let chunks = runtime.block_on(body.by_ref().take(count).collect());
+    let chunks = chunks.unwrap().into_iter();
+    chunks.map(|chunk| String::from_utf8(chunk.to_vec()).unwrap()).collect()
+  }
+
+  fn event(timestamp: i64) -> String {
+    format!(
+      "id: {}\nevent: record\ndata: {{\"timestamp\":{},\"data\":1}}\n\n",
+      timestamp, timestamp
+    )
+  }
+
+  #[test]
+  fn events_are_backfilled_from_the_last_event_id() {
+    let mut runtime = Runtime::new().unwrap();
+    let mut server = TestServer::new("backfill", &[100, 200, 300]);
+
+    let res = server.get("/test/events", &[("Last-Event-ID", "100")]);
+    assert_eq!(res.headers()[header::CONTENT_TYPE], "text/event-stream");
+    server.push(400);
+    let mut body = res.into_body();
+    let events = read_chunks(&mut runtime, &mut body, 3);
+    assert_eq!(events, vec![event(200), event(300), event(400)]);
+
+    // without the header, or with an ID after all records, only the live
+    // records are sent
+    let res = server.get("/test/events", &[]);
+    let max_id = i64::MAX.to_string();
+    let after_all = server.get("/test/events", &[("Last-Event-ID", &max_id)]);
+    server.push(500);
+    let mut live = res.into_body();
+    assert_eq!(read_chunks(&mut runtime, &mut live, 1), vec![event(500)]);
+    let mut after_all = after_all.into_body();
+    assert_eq!(read_chunks(&mut runtime, &mut after_all, 1), vec![event(500)]);
+    assert_eq!(read_chunks(&mut runtime, &mut body, 1), vec![event(500)]);
+
+    // the streams end on shutdown
+    server.shutdown = None;
+    assert!(read_chunks(&mut runtime, &mut body, 1).is_empty());
+    assert!(read_chunks(&mut runtime, &mut live, 1).is_empty());
+  }
+
+  #[test]
+  fn events_are_kept_alive() {
+    let mut runtime = Runtime::new().unwrap();
+    let server = TestServer::new("keep-alive", &[100]);
+
+    let shutdown = Shutdown::new();
+    let tracker = &server.handler.trackers[0];
+    let interval = Duration::from_millis(10);
+    let mut stream = EventStream {
+      records: tracker.subscribe(Some(0)).unwrap(),
+      keep_alive: Interval::new(Instant::now() + interval, interval),
+      shutdown: shutdown.another().shared(),
+    };
+    let mut read = |count| {
+      let chunks = runtime.block_on(stream.by_ref().take(count).collect());
+      let chunks = chunks.unwrap().into_iter();
+      let chunks = chunks.map(|chunk| String::from_utf8(chunk).unwrap());
+      chunks.collect::<Vec<String>>()
+    };
+
+    // records are sent before the keep-alive comments
+    let keep_alive = ": keep-alive\n\n".to_owned();
+    assert_eq!(read(2), vec![event(100), keep_alive.clone()]);
+    server.push(200);
+    assert_eq!(read(3), vec![event(200), keep_alive.clone(), keep_alive]);
+    drop(shutdown);
+    assert!(read(1).is_empty());
+  }
+}
@@ -0,0 +1,943 @@
+use failure::Fallible;
+use hyper::header::HeaderValue;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::sync::RwLock;
+
+use crate::aggregate::{aggregate_buckets, BucketSize};
+use crate::broadcast::PushedRecord;
+use crate::database::{compress_records, Database};
+use crate::http::JsonMap;
+use crate::negotiate::{parse_quality_list, quality_of};
+use crate::record::{Record, Timestamp};
+use crate::trackers::Columns;
+
+/// Number of records serialized at once when a table is written in chunks,
+/// the database is locked only while a single chunk is written.
+const RECORDS_PER_CHUNK: usize = 1000;
+
+/// Query parameters of the stats endpoints.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StatsQuery {
+  #[serde(default, deserialize_with = "deserialize_timestamp")]
+  from: Option<Timestamp>,
+  #[serde(default, deserialize_with = "deserialize_timestamp")]
+  to: Option<Timestamp>,
+  limit: Option<usize>,
+  #[serde(default)]
+  order: Order,
+  #[serde(default, deserialize_with = "deserialize_bucket_size")]
+  bucket: Option<BucketSize>,
+}
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Order {
+  #[default]
+  Asc,
+  Desc,
+}
+
+impl StatsQuery {
+  pub fn parse(query: &str) -> Result<Self, String> {
+    serde_urlencoded::from_str(query)
+      .map_err(|e| format!("invalid query parameters: {}", e))
+  }
+
+  fn limit_reached(&self, rows: usize) -> bool {
+    self.limit.is_some_and(|limit| rows >= limit)
+  }
+}
+
+fn deserialize_bucket_size<'de, D>(
+  deserializer: D,
+) -> Result<Option<BucketSize>, D::Error>
+where
+  D: serde::Deserializer<'de>,
+{
+  let s = String::deserialize(deserializer)?;
+  BucketSize::parse(&s).map(Some).ok_or_else(|| {
+    serde::de::Error::custom(format!(
+      "invalid bucket size '{}', expected a number followed by m, h, d or w",
+      s
+    ))
+  })
+}
+
+fn deserialize_timestamp<'de, D>(
+  deserializer: D,
+) -> Result<Option<Timestamp>, D::Error>
+where
+  D: serde::Deserializer<'de>,
+{
+  let s = String::deserialize(deserializer)?;
+  Timestamp::parse(&s).map(Some).ok_or_else(|| {
+    serde::de::Error::custom(format!(
+      "invalid timestamp '{}', expected UNIX seconds or ISO 8601",
+      s
+    ))
+  })
+}
+
+/// Changes every time a record is pushed into the database.
+pub struct DatabaseVersion {
+  pub records: usize,
+  pub last_timestamp: Option<Timestamp>,
+}
+
+/// Database of a tracker with the type of data points erased, so that the
+/// stats of all trackers are served by the same handlers.
+pub trait StatsDatabase: Send + Sync {
+  fn record_count(&self) -> Fallible<usize>;
+
+  fn version(&self) -> Fallible<DatabaseVersion>;
+
+  /// Prepares writing of the records selected by the query as a table with
+  /// the columns of all data points in the time range.
+  fn stats_cursor(
+    &self,
+    query: StatsQuery,
+    format: Format,
+  ) -> Fallible<StatsCursor>;
+
+  /// Writes the next chunk of the table, returns an empty chunk only once the
+  /// whole table has been written.
+  fn write_stats_chunk(&self, cursor: &mut StatsCursor) -> Fallible<Vec<u8>>;
+
+  /// Serializes the records newer than the timestamp, in the same way as the
+  /// pushed ones are broadcast.
+  fn serialize_records_since(
+    &self,
+    timestamp: i64,
+  ) -> Fallible<Vec<PushedRecord>>;
+
+  /// Serializes the newest data point for the `latest.json` endpoint, `None`
+  /// if the database has no data points yet.
+  fn latest_json(&self) -> Fallible<Option<Vec<u8>>>;
+}
+
+impl<T> StatsDatabase for RwLock<Database<T>>
+where
+  T: Columns + Eq + Serialize + Send + Sync + 'static,
+{
+  fn record_count(&self) -> Fallible<usize> {
+    self.read().unwrap().len()
+  }
+
+  fn version(&self) -> Fallible<DatabaseVersion> {
+    let db = self.read().unwrap();
+    Ok(DatabaseVersion {
+      records: db.len()?,
+      last_timestamp: db.latest()?.map(|record| record.timestamp),
+    })
+  }
+
+  fn stats_cursor(
+    &self,
+    query: StatsQuery,
+    format: Format,
+  ) -> Fallible<StatsCursor> {
+    StatsCursor::new(&self.read().unwrap(), query, format)
+  }
+
+  fn write_stats_chunk(&self, cursor: &mut StatsCursor) -> Fallible<Vec<u8>> {
+    // a chunk of records in the middle of an unchanged run produces no rows,
+    // the database is still locked only while a single chunk is written
+    while !cursor.finished && cursor.table.bytes.is_empty() {
+      cursor.write_chunk(&self.read().unwrap())?;
+    }
+    Ok(cursor.table.take_bytes())
+  }
+
+  fn serialize_records_since(
+    &self,
+    timestamp: i64,
+  ) -> Fallible<Vec<PushedRecord>> {
+    let from = match timestamp.checked_add(1) {
+      Some(from) => Timestamp::new(from),
+      None => return Ok(vec![]),
+    };
+    let records = self.read().unwrap().range(Some(&from), None, false, None)?;
+    Ok(
+      records
+        .iter()
+        .filter_map(|record| {
+          Some(PushedRecord {
+            timestamp: record.timestamp.as_secs(),
+            json: serde_json::to_string(record).ok()?,
+          })
+        })
+        .collect(),
+    )
+  }
+
+  fn latest_json(&self) -> Fallible<Option<Vec<u8>>> {
+    let db = self.read().unwrap();
+    match Latest::new(&db)? {
+      Some(latest) => Ok(Some(serde_json::to_vec(&latest)?)),
+      None => Ok(None),
+    }
+  }
+}
+
+/// Newest data point with the stats derived from it by the tracker, and the
+/// changes since the data points from some time ago.
+#[derive(Serialize)]
+struct Latest<T> {
+  #[serde(flatten)]
+  record: Record<T>,
+  #[serde(flatten)]
+  derived_stats: JsonMap,
+  deltas: Deltas,
+}
+
+#[derive(Serialize)]
+struct Deltas {
+  #[serde(rename = "1h")]
+  hour: Option<Delta>,
+  #[serde(rename = "24h")]
+  day: Option<Delta>,
+  #[serde(rename = "7d")]
+  week: Option<Delta>,
+}
+
+/// Differences between the values of the columns of the latest data point and
+/// the one at `timestamp`, which is the newest one at least the given
+/// duration older.
+#[derive(Serialize)]
+struct Delta {
+  timestamp: i64,
+  #[serde(flatten)]
+  columns: BTreeMap<String, Option<i64>>,
+}
+
+impl<T: Columns> Latest<T> {
+  fn new(db: &Database<T>) -> Fallible<Option<Self>> {
+    let record = match db.latest_data(None)? {
+      Some(record) => record,
+      None => return Ok(None),
+    };
+    let data = record.data().unwrap();
+    let names = data.column_names();
+    let mut values = vec![];
+    data.column_values(&names, &mut values);
+
+    let delta = |secs: i64| -> Fallible<Option<Delta>> {
+      let before = Timestamp::new(record.timestamp.as_secs() - secs);
+      let old_record = match db.latest_data(Some(&before))? {
+        Some(old_record) => old_record,
+        None => return Ok(None),
+      };
+      let mut old_values = vec![];
+      old_record.data().unwrap().column_values(&names, &mut old_values);
+
+      let columns = (names.iter().zip(values.iter().zip(&old_values)))
+        .map(|(name, (&new, &old))| {
+          (name.clone(), new.and_then(|new| Some(new as i64 - old? as i64)))
+        })
+        .collect();
+      Ok(Some(Delta { timestamp: old_record.timestamp.as_secs(), columns }))
+    };
+
+    let derived_stats = data.derived_stats();
+    let deltas = Deltas {
+      hour: delta(60 * 60)?,
+      day: delta(24 * 60 * 60)?,
+      week: delta(7 * 24 * 60 * 60)?,
+    };
+    Ok(Some(Self { record, derived_stats, deltas }))
+  }
+}
+
+/// Progress of writing a table, kept between the chunks. The end of the time
+/// range is fixed when the cursor is created, so records pushed in the
+/// meantime don't shift the rows which are yet to be written.
+pub struct StatsCursor {
+  query: StatsQuery,
+  names: Vec<String>,
+  table: TableWriter,
+  /// Timestamp of the last record which has been written, or aggregated into
+  /// a bucket which has been written.
+  position: Option<i64>,
+  /// Number of the written records with the timestamp of the position,
+  /// several records may share a timestamp. Buckets always contain all of
+  /// them, so it is tracked only for the rows of records.
+  written_at_position: usize,
+  finished: bool,
+}
+
+impl StatsCursor {
+  fn new<T: Columns>(
+    db: &Database<T>,
+    mut query: StatsQuery,
+    format: Format,
+  ) -> Fallible<Self> {
+    let from = query.from.as_ref().map_or(i64::MIN, Timestamp::as_secs);
+    let names =
+      column_union(db.scan_back(query.to.as_ref()).take_while(|record| {
+        record.as_ref().map_or(true, |r| r.timestamp.as_secs() >= from)
+      }))?;
+
+    let table = if query.bucket.is_some() {
+      let mut header = vec!["samples".to_owned(), "gaps".to_owned()];
+      for name in &names {
+        for aggregate in &["min", "max", "first", "last", "mean"] {
+          header.push(format!("{}_{}", name, aggregate));
+        }
+      }
+      TableWriter::new(format, "bucket", &header)
+    } else {
+      let mut header = names.clone();
+      header.push("gap".to_owned());
+      TableWriter::new(format, "timestamp", &header)
+    };
+
+    let finished = match db.latest()? {
+      Some(last) => {
+        let last = last.timestamp.as_secs();
+        if query.to.as_ref().is_none_or(|to| to.as_secs() > last) {
+          query.to = Some(Timestamp::new(last));
+        }
+        false
+      }
+      None => true,
+    };
+
+    let mut cursor = Self {
+      query,
+      names,
+      table,
+      position: None,
+      written_at_position: 0,
+      finished,
+    };
+    if cursor.finished {
+      cursor.table.finish();
+    }
+    Ok(cursor)
+  }
+
+  /// Writes rows for up to `RECORDS_PER_CHUNK` records following the current
+  /// position. The order and the limit are applied to the rows, so e.g.
+  /// `order=desc&limit=N` returns the newest N records or buckets.
+  fn write_chunk<T: Columns + Eq>(&mut self, db: &Database<T>) -> Fallible<()> {
+    let reversed = match self.query.order {
+      Order::Asc => false,
+      Order::Desc => true,
+    };
+
+    let (consumed, last_consumed, remaining) = match self.query.bucket {
+      Some(bucket_size) => self.write_buckets(db, bucket_size, reversed)?,
+      None => self.write_records(db, reversed)?,
+    };
+
+    if let Some(last_consumed) = last_consumed {
+      self.position = Some(last_consumed);
+    }
+    let limit_reached = self.query.limit_reached(self.table.rows);
+    if consumed == 0 || !remaining || limit_reached {
+      self.finished = true;
+      self.table.finish();
+    }
+    Ok(())
+  }
+
+  /// Bounds of the records which haven't been written yet, the records at the
+  /// current position are included if `with_position` is set.
+  fn unwritten_range(
+    &self,
+    reversed: bool,
+    with_position: bool,
+  ) -> (Option<Timestamp>, Option<Timestamp>) {
+    let query = &self.query;
+    let (mut from, mut to) = (query.from.clone(), query.to.clone());
+    if let Some(position) = self.position {
+      let skip = if with_position { 0 } else { 1 };
+      if reversed {
+        to = Some(Timestamp::new(position - skip));
+      } else {
+        from = Some(Timestamp::new(position + skip));
+      }
+    }
+    (from, to)
+  }
+
+  /// Returns the number of consumed records, the timestamp of the last one
+  /// and whether any records remain. Buckets aren't split between the chunks,
+  /// so the records of the last bucket are read even past the chunk size.
+  fn write_buckets<T: Columns>(
+    &mut self,
+    db: &Database<T>,
+    bucket_size: BucketSize,
+    reversed: bool,
+  ) -> Fallible<(usize, Option<i64>, bool)> {
+    let (from, to) = self.unwritten_range(reversed, false);
+    let mut records =
+      db.range(from.as_ref(), to.as_ref(), reversed, Some(RECORDS_PER_CHUNK))?;
+    let remaining = records.len() == RECORDS_PER_CHUNK;
+    if let Some(last) = records.last().filter(|_| remaining) {
+      let start = bucket_size.bucket_start(&last.timestamp);
+      let end = bucket_size.bucket_end(&last.timestamp) - 1;
+      let bucket_from = from.map_or(start, |from| from.as_secs().max(start));
+      let bucket_to = to.map_or(end, |to| to.as_secs().min(end));
+      // the whole last bucket is read again, the chunk may have ended in the
+      // middle of the records sharing a timestamp
+      let in_last_bucket = (records.iter().rev())
+        .take_while(|record| {
+          bucket_size.bucket_start(&record.timestamp) == start
+        })
+        .count();
+      records.truncate(records.len() - in_last_bucket);
+      records.extend(db.range(
+        Some(&Timestamp::new(bucket_from)),
+        Some(&Timestamp::new(bucket_to)),
+        reversed,
+        None,
+      )?);
+    }
+
+    let names = &self.names;
+    let values = |data: &T, values: &mut Vec<Option<u64>>| {
+      data.column_values(names, values)
+    };
+    let buckets =
+      aggregate_buckets(records.iter(), bucket_size, reversed, values);
+
+    let table = &mut self.table;
+    let mut consumed = 0;
+    for bucket in buckets {
+      table.start_row(&bucket.start);
+      table.write_u64(Some(bucket.samples));
+      table.write_u64(Some(bucket.gaps));
+      for index in 0..names.len() {
+        match bucket.columns.get(index).and_then(Option::as_ref) {
+          Some(aggregate) => {
+            table.write_u64(Some(aggregate.min));
+            table.write_u64(Some(aggregate.max));
+            table.write_u64(Some(aggregate.first));
+            table.write_u64(Some(aggregate.last));
+            table.write_f64(Some(aggregate.mean()));
+          }
+          None => {
+            for _ in 0..4 {
+              table.write_u64(None);
+            }
+            table.write_f64(None);
+          }
+        }
+      }
+      table.end_row();
+
+      consumed += (bucket.samples + bucket.gaps) as usize;
+      if self.query.limit_reached(table.rows) {
+        break;
+      }
+    }
+
+    let last_consumed =
+      consumed.checked_sub(1).map(|i| records[i].timestamp.as_secs());
+    Ok((consumed, last_consumed, remaining))
+  }
+
+  /// Writes up to `RECORDS_PER_CHUNK` compressed records, the records right
+  /// outside of the chunk are read as well because they decide whether the
+  /// records at the edges are in the middle of an unchanged run. Returns the
+  /// number of consumed records, the timestamp of the last one and whether
+  /// any records remain, the writing is finished anyway if the limit is
+  /// reached.
+  fn write_records<T: Columns + Eq>(
+    &mut self,
+    db: &Database<T>,
+    reversed: bool,
+  ) -> Fallible<(usize, Option<i64>, bool)> {
+    let (from, to) = self.unwritten_range(reversed, true);
+    let written = self.written_at_position;
+    let limit = RECORDS_PER_CHUNK + 1 + written;
+    let mut window =
+      db.range(from.as_ref(), to.as_ref(), reversed, Some(limit))?;
+    let remaining = window.len() == limit;
+    // the last written record is kept before the chunk, it has been written
+    // by the previous chunk
+    let written_before = (window.iter().take(written))
+      .take_while(|record| Some(record.timestamp.as_secs()) == self.position)
+      .count();
+    window.drain(..written_before.saturating_sub(1));
+    let has_before = written_before > 0;
+
+    let chunk_start = (has_before as usize).min(window.len());
+    let chunk_end = if remaining { window.len() - 1 } else { window.len() };
+    let chunk = &window[chunk_start..chunk_end];
+    let is_context = |record: &Record<T>| {
+      !chunk.as_ptr_range().contains(&(record as *const Record<T>))
+    };
+
+    let mut values: Vec<Option<u64>> = vec![];
+    for record in compress_records(window.iter()) {
+      if is_context(record) {
+        continue;
+      }
+      if self.query.limit_reached(self.table.rows) {
+        break;
+      }
+
+      self.table.start_row(&record.timestamp);
+      values.clear();
+      match record.data() {
+        Some(data) => data.column_values(&self.names, &mut values),
+        // the values of gaps are empty, their reason is in the last column
+        None => values.resize(self.names.len(), None),
+      }
+      for &value in &values {
+        self.table.write_u64(value);
+      }
+      self.table.write_str(record.gap().map(|gap| gap.reason.as_str()));
+      self.table.end_row();
+    }
+
+    let last_consumed = chunk.last().map(|record| record.timestamp.as_secs());
+    if let Some(last_consumed) = last_consumed {
+      let same = (chunk.iter().rev())
+        .take_while(|record| record.timestamp.as_secs() == last_consumed)
+        .count();
+      if self.position != Some(last_consumed) || same < chunk.len() {
+        self.written_at_position = 0;
+      }
+      self.written_at_position += same;
+    }
+    Ok((chunk.len(), last_consumed, remaining))
+  }
+}
+
+/// Names of the columns of the data points, in the order of the first one
+/// followed by the columns which only the later ones have.
+fn column_union<T, I>(records: I) -> Fallible<Vec<String>>
+where
+  T: Columns,
+  I: Iterator<Item = Fallible<Record<T>>>,
+{
+  let mut names: Vec<String> = vec![];
+  for record in records {
+    if let Some(data) = record?.data() {
+      for name in data.column_names() {
+        if !names.contains(&name) {
+          names.push(name);
+        }
+      }
+    }
+  }
+  Ok(names)
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Format {
+  Json,
+  Csv,
+  Tsv,
+}
+
+impl Format {
+  pub fn from_extension(extension: &str) -> Option<Self> {
+    match extension {
+      "json" => Some(Format::Json),
+      "csv" => Some(Format::Csv),
+      "tsv" => Some(Format::Tsv),
+      _ => None,
+    }
+  }
+
+  /// Picks the format with the highest weight in the `Accept` header, JSON is
+  /// used if the header is missing. Returns `None` if none of the formats is
+  /// acceptable.
+  pub fn negotiate(accept: Option<&HeaderValue>) -> Option<Self> {
+    let list = match accept {
+      Some(header) => parse_quality_list(header),
+      None => return Some(Format::Json),
+    };
+
+    let mut best = None;
+    let mut best_quality = 0.0;
+    for &format in &[Format::Json, Format::Csv, Format::Tsv] {
+      let content_type = format.content_type();
+      let (media_type, _) = content_type.split_at(content_type.find('/')?);
+      let quality =
+        quality_of(&list, content_type, &[&format!("{}/*", media_type), "*/*"]);
+      if quality > best_quality {
+        best = Some(format);
+        best_quality = quality;
+      }
+    }
+    best
+  }
+
+  pub fn extension(self) -> &'static str {
+    match self {
+      Format::Json => "json",
+      Format::Csv => "csv",
+      Format::Tsv => "tsv",
+    }
+  }
+
+  pub fn content_type(self) -> &'static str {
+    match self {
+      Format::Json => "application/json",
+      Format::Csv => "text/csv",
+      Format::Tsv => "text/tab-separated-values",
+    }
+  }
+}
+
+/// Writes a table row by row. JSON tables are objects with the `columns` and
+/// an array of `rows` where missing values are nulls, CSV and TSV tables start
+/// with a header and have formatted timestamps.
+pub struct TableWriter {
+  format: Format,
+  bytes: Vec<u8>,
+  rows: usize,
+}
+
+impl TableWriter {
+  pub fn new(format: Format, first_column: &str, columns: &[String]) -> Self {
+    let mut table = Self { format, bytes: vec![], rows: 0 };
+    match format {
+      Format::Json => {
+        table.bytes.extend_from_slice(b"{\"columns\":");
+        let names: Vec<&str> = std::iter::once(first_column)
+          .chain(columns.iter().map(String::as_str))
+          .collect();
+        serde_json::to_writer(&mut table.bytes, &names).unwrap();
+        table.bytes.extend_from_slice(b",\"rows\":[");
+      }
+      Format::Csv | Format::Tsv => {
+        table.bytes.extend_from_slice(first_column.as_bytes());
+        for column in columns {
+          table.bytes.push(table.separator());
+          table.bytes.extend_from_slice(column.as_bytes());
+        }
+        table.bytes.push(b'\n');
+      }
+    }
+    table
+  }
+
+  fn separator(&self) -> u8 {
+    match self.format {
+      Format::Tsv => b'\t',
+      _ => b',',
+    }
+  }
+
+  pub fn start_row(&mut self, timestamp: &Timestamp) {
+    match self.format {
+      Format::Json => {
+        if self.rows > 0 {
+          self.bytes.push(b',');
+        }
+        self.bytes.push(b'[');
+        itoa::write(&mut self.bytes, timestamp.as_secs()).unwrap();
+      }
+      Format::Csv | Format::Tsv => {
+        timestamp.format_to(&mut self.bytes).unwrap();
+      }
+    }
+    self.rows += 1;
+  }
+
+  pub fn write_u64(&mut self, value: Option<u64>) {
+    self.bytes.push(self.separator());
+    match value {
+      Some(value) => {
+        itoa::write(&mut self.bytes, value).unwrap();
+      }
+      None => self.write_none(),
+    }
+  }
+
+  /// Floats are rounded to 3 decimal places.
+  pub fn write_f64(&mut self, value: Option<f64>) {
+    self.bytes.push(self.separator());
+    match value {
+      Some(value) => {
+        let value = (value * 1000.0).round() / 1000.0;
+        write!(self.bytes, "{}", value).unwrap();
+      }
+      None => self.write_none(),
+    }
+  }
+
+  /// CSV fields are quoted if necessary, TSV can't contain tabs and line
+  /// breaks at all, so they are replaced with spaces.
+  pub fn write_str(&mut self, value: Option<&str>) {
+    self.bytes.push(self.separator());
+    match (value, self.format) {
+      (Some(value), Format::Json) => {
+        serde_json::to_writer(&mut self.bytes, value).unwrap();
+      }
+      (Some(value), Format::Csv) => write_csv_field(&mut self.bytes, value),
+      (Some(value), Format::Tsv) => {
+        self.bytes.extend(value.bytes().map(|byte| match byte {
+          b'\t' | b'\n' | b'\r' => b' ',
+          _ => byte,
+        }));
+      }
+      (None, _) => self.write_none(),
+    }
+  }
+
+  fn write_none(&mut self) {
+    if self.format == Format::Json {
+      self.bytes.extend_from_slice(b"null");
+    }
+  }
+
+  pub fn end_row(&mut self) {
+    match self.format {
+      Format::Json => self.bytes.push(b']'),
+      Format::Csv | Format::Tsv => self.bytes.push(b'\n'),
+    }
+  }
+
+  pub fn finish(&mut self) {
+    if self.format == Format::Json {
+      self.bytes.extend_from_slice(b"]}");
+    }
+  }
+
+  /// Returns the bytes written since the last call.
+  pub fn take_bytes(&mut self) -> Vec<u8> {
+    std::mem::take(&mut self.bytes)
+  }
+}
+
+/// Quotes the field if it contains a separator, a quote or a line break.
+fn write_csv_field(bytes: &mut Vec<u8>, field: &str) {
+  if !field.contains(&[',', '"', '\n', '\r'][..]) {
+    bytes.extend_from_slice(field.as_bytes());
+    return;
+  }
+  bytes.push(b'"');
+  bytes.extend_from_slice(field.replace('"', "\"\"").as_bytes());
+  bytes.push(b'"');
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::http::JsonValue;
+  use crate::record::{Gap, RecordValue};
+  use crate::testing::{temp_database, TempPath};
+  use crate::trackers::ranker;
+
+  /// Data point with a value of every column listed in it.
+  #[derive(Clone)]
+  struct Point(&'static [(&'static str, u64)]);
+
+  impl Columns for Point {
+    fn column_names(&self) -> Vec<String> {
+      self.0.iter().map(|&(name, _)| name.to_owned()).collect()
+    }
+
+    fn column_values(&self, names: &[String], values: &mut Vec<Option<u64>>) {
+      values.extend(names.iter().map(|name| {
+        let column = self.0.iter().find(|&&(column, _)| column == name)?;
+        Some(column.1)
+      }));
+    }
+
+    /// The points are only written to JSON databases.
+    fn from_column_values(_: &[String], _: &[Option<u64>]) -> Option<Self> {
+      None
+    }
+  }
+
+  #[test]
+  fn latest_stats_have_derived_fields_and_deltas() {
+    let (_path, db) = temp_database("stats-latest");
+    let shared_db = RwLock::new(db);
+    assert!(shared_db.latest_json().unwrap().is_none());
+
+    let data = |rank, upvotes, downvotes, reranks, top5_reranks| {
+      RecordValue::Data(ranker::DataPoint {
+        rank,
+        upvotes,
+        downvotes,
+        reranks,
+        top5_reranks,
+      })
+    };
+    let gap = RecordValue::Gap(Gap { reason: "down".to_owned() });
+    let values = vec![
+      (0, data(30, Some(10), Some(10), Some(4), Some(1))),
+      (3000, data(28, Some(12), Some(10), Some(4), Some(1))),
+      (7000, gap),
+      (7200, data(26, Some(15), Some(5), None, None)),
+    ];
+    for (timestamp, value) in values {
+      let record = Record { timestamp: Timestamp::new(timestamp), value };
+      shared_db.write().unwrap().push(record).unwrap();
+    }
+
+    let latest = shared_db.latest_json().unwrap().unwrap();
+    let latest: serde_json::Value = serde_json::from_slice(&latest).unwrap();
+    assert_eq!(
+      latest,
+      serde_json::json!({
+        "timestamp": 7200,
+        "data": { "rank": 26, "upvotes": 15, "downvotes": 5 },
+        "page": 2,
+        "upvotes_percent": 75.0,
+        "downvotes_percent": 25.0,
+        "top5_reranks_percent": null,
+        "deltas": {
+          "1h": {
+            "timestamp": 3000,
+            "rank": -2,
+            "upvotes": 3,
+            "downvotes": -5,
+            "reranks": null,
+            "top5_reranks": null,
+          },
+          "24h": null,
+          "7d": null,
+        },
+      }),
+    );
+  }
+
+  fn record(timestamp: i64, value: RecordValue<Point>) -> Record<Point> {
+    Record { timestamp: Timestamp::new(timestamp), value }
+  }
+
+  #[test]
+  fn columns_of_all_data_points_are_written() {
+    let gap = || RecordValue::Gap(Gap { reason: "down".to_owned() });
+    let records = [
+      record(0, RecordValue::Data(Point(&[("a", 1), ("c", 5)]))),
+      record(1, gap()),
+      record(2, RecordValue::Data(Point(&[("b", 2), ("a", 3)]))),
+      record(3, gap()),
+    ];
+    let union = |records: &[Record<Point>]| {
+      column_union(records.iter().rev().cloned().map(Ok)).unwrap()
+    };
+    assert_eq!(union(&records), vec!["b", "a", "c"]);
+    assert!(union(&records[1..2]).is_empty());
+  }
+
+  #[test]
+  fn tables_have_headers_and_gap_reasons() {
+    let reason = "timed out,\t\"twice\"";
+    let table = |format| {
+      let columns = vec!["a".to_owned(), "gap".to_owned()];
+      let mut table = TableWriter::new(format, "timestamp", &columns);
+      table.start_row(&Timestamp::new(0));
+      table.write_u64(Some(1));
+      table.write_str(None);
+      table.end_row();
+      table.start_row(&Timestamp::new(60));
+      table.write_u64(None);
+      table.write_str(Some(reason));
+      table.end_row();
+      table.finish();
+      String::from_utf8(table.bytes).unwrap()
+    };
+
+    assert_eq!(
+      table(Format::Json),
+      r#"{"columns":["timestamp","a","gap"],"rows":[[0,1,null],[60,null,"timed out,\t\"twice\""]]}"#,
+    );
+    assert_eq!(
+      table(Format::Csv),
+      "timestamp,a,gap\n\
+       1970-01-01 00:00:00,1,\n\
+       1970-01-01 00:01:00,,\"timed out,\t\"\"twice\"\"\"\n",
+    );
+    assert_eq!(
+      table(Format::Tsv),
+      "timestamp\ta\tgap\n\
+       1970-01-01 00:00:00\t1\t\n\
+       1970-01-01 00:01:00\t\ttimed out, \"twice\"\n",
+    );
+
+    let mut empty = TableWriter::new(Format::Json, "timestamp", &[]);
+    empty.finish();
+    let empty = String::from_utf8(empty.bytes).unwrap();
+    assert_eq!(empty, r#"{"columns":["timestamp"],"rows":[]}"#);
+  }
+
+  fn database(
+    name: &str,
+    values: &[(i64, u64)],
+  ) -> (TempPath, RwLock<Database<u64>>) {
+    let (path, mut db) = temp_database(&format!("stats-{}", name));
+    for &(timestamp, value) in values {
+      let value = RecordValue::Data(value);
+      db.push(Record { timestamp: Timestamp::new(timestamp), value }).unwrap();
+    }
+    (path, RwLock::new(db))
+  }
+
+  /// Writes the whole table chunk by chunk, like the stats endpoints do, and
+  /// returns its rows.
+  fn write_table(db: &RwLock<Database<u64>>, query: &str) -> Vec<JsonValue> {
+    let query = StatsQuery::parse(query).unwrap();
+    let mut cursor = db.stats_cursor(query, Format::Json).unwrap();
+    let mut body = vec![];
+    loop {
+      let chunk = db.write_stats_chunk(&mut cursor).unwrap();
+      if chunk.is_empty() {
+        break;
+      }
+      body.extend_from_slice(&chunk);
+    }
+    let mut table: JsonValue = serde_json::from_slice(&body).unwrap();
+    serde_json::from_value(table["rows"].take()).unwrap()
+  }
+
+  #[test]
+  fn chunks_inside_unchanged_runs_dont_end_the_table() {
+    let mut values: Vec<(i64, u64)> =
+      (0..4000).map(|index| (1000 + index * 300, 1)).collect();
+    values.push((1000 + 4000 * 300, 2));
+    let (_path, db) = database("unchanged-run", &values);
+
+    let rows = vec![
+      serde_json::json!([1000, 1, null]),
+      serde_json::json!([1_200_700, 1, null]),
+      serde_json::json!([1_201_000, 2, null]),
+    ];
+    assert_eq!(write_table(&db, ""), rows);
+    let mut reversed = rows;
+    reversed.reverse();
+    assert_eq!(write_table(&db, "order=desc"), reversed);
+  }
+
+  #[test]
+  fn records_sharing_a_timestamp_are_split_between_chunks() {
+    // the groups of records with the same timestamp cross the chunk bounds
+    let values: Vec<(i64, u64)> =
+      (0..2500).map(|index| (index / 3, index as u64)).collect();
+    let (_path, db) = database("same-timestamps", &values);
+
+    let rows: Vec<JsonValue> = values
+      .iter()
+      .map(|&(timestamp, value)| serde_json::json!([timestamp, value, null]))
+      .collect();
+    assert_eq!(write_table(&db, ""), rows);
+    let mut reversed = rows;
+    reversed.reverse();
+    assert_eq!(write_table(&db, "order=desc"), reversed);
+
+    for query in &["bucket=1m", "bucket=1m&order=desc"] {
+      let buckets = write_table(&db, query);
+      let samples: u64 =
+        buckets.iter().map(|bucket| bucket[1].as_u64().unwrap()).sum();
+      assert_eq!(samples, values.len() as u64);
+      let mut starts: Vec<i64> =
+        buckets.iter().map(|bucket| bucket[0].as_i64().unwrap()).collect();
+      starts.dedup();
+      assert_eq!(starts.len(), buckets.len());
+    }
+  }
+}
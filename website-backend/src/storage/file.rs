@@ -0,0 +1,855 @@
+use failure::{Fail, Fallible, ResultExt};
+use log::{info, warn};
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use serde::de::DeserializeOwned;
+use serde::ser::Serialize;
+use std::fmt::Debug;
+
+use super::{sync_parent_dir, Storage};
+use crate::binary::{self, Block};
+use crate::config::{ResidentRecords, StorageFormat, SyncMode};
+use crate::record::{Record, Timestamp};
+use crate::trackers::Columns;
+
+/// Number of records between the entries of the index, records are also
+/// evicted from memory in groups of this size.
+const INDEX_INTERVAL: usize = 256;
+
+/// Appends the pushed records to a file, either as JSON lines or in the binary
+/// format. The newest records are kept in memory, the older ones are read
+/// from the file with the help of a sparse index of their positions.
+#[derive(Debug)]
+pub struct FileStorage<T> {
+  path: PathBuf,
+  file: File,
+  /// Records which are kept in memory, i.e. all of them or the newest ones.
+  records: Vec<Record<T>>,
+  /// Number of the oldest records which have been evicted from memory, it is
+  /// always a multiple of `INDEX_INTERVAL`.
+  evicted: usize,
+  /// Entry for every `INDEX_INTERVAL`-th record, starting with the first one.
+  index: Vec<IndexEntry>,
+  resident_records: ResidentRecords,
+  encoding: Encoding,
+  sync_mode: SyncMode,
+  /// Number of records pushed since the file was last synchronized.
+  unsynced_records: u32,
+  /// Whether the records in the file differ from the ones in memory, in which
+  /// case the file must be rewritten.
+  dirty: bool,
+  /// The file is never modified, see `open_read_only`.
+  read_only: bool,
+}
+
+#[derive(Debug)]
+enum Encoding {
+  Json,
+  /// Holds the state for encoding the next pushed record.
+  Binary(binary::Encoder),
+}
+
+#[derive(Debug)]
+struct IndexEntry {
+  timestamp: i64,
+  offset: u64,
+  /// State of the binary encoding before the record, the records can't be
+  /// decoded from the middle of the file without it.
+  encoder: Option<binary::Encoder>,
+}
+
+impl<T: DeserializeOwned + Serialize + Columns + Debug> FileStorage<T> {
+  pub fn open(
+    path: &Path,
+    format: StorageFormat,
+    sync_mode: SyncMode,
+    resident_records: ResidentRecords,
+  ) -> Fallible<Self> {
+    let file_exists = path.exists();
+
+    let file = open_file(path)?;
+    let mut storage =
+      Self::new(path, file, format, sync_mode, resident_records);
+    storage.dirty = !file_exists;
+
+    if file_exists {
+      info!("reading data");
+      storage.read()?;
+    }
+    if storage.dirty {
+      info!("writing default data to the file");
+      storage.write_file()?;
+    }
+
+    Ok(storage)
+  }
+
+  /// Opens an existing file without modifying it, e.g. to read the records of
+  /// a tracker while the server isn't running. A partially written record at
+  /// the end is skipped instead of being truncated.
+  pub fn open_read_only(
+    path: &Path,
+    format: StorageFormat,
+    resident_records: ResidentRecords,
+  ) -> Fallible<Self> {
+    info!("opening file '{}' read-only", path.display());
+    let file = File::open(path).context("failed to open file")?;
+
+    let mut storage =
+      Self::new(path, file, format, SyncMode::Never, resident_records);
+    storage.read_only = true;
+    info!("reading data");
+    storage.read()?;
+
+    Ok(storage)
+  }
+
+  fn new(
+    path: &Path,
+    file: File,
+    format: StorageFormat,
+    sync_mode: SyncMode,
+    resident_records: ResidentRecords,
+  ) -> Self {
+    Self {
+      path: path.to_owned(),
+      file,
+      records: vec![],
+      evicted: 0,
+      index: vec![],
+      resident_records,
+      encoding: match format {
+        StorageFormat::Binary => Encoding::Binary(binary::Encoder::default()),
+        _ => Encoding::Json,
+      },
+      sync_mode,
+      unsynced_records: 0,
+      dirty: false,
+      read_only: false,
+    }
+  }
+}
+
+impl<T> FileStorage<T> {
+  fn len(&self) -> usize {
+    self.evicted + self.records.len()
+  }
+
+  /// Adds a record which is at `offset` in the file, `encoder` is the state
+  /// of the binary encoding before it.
+  fn load(
+    &mut self,
+    record: Record<T>,
+    offset: u64,
+    encoder: impl FnOnce() -> Option<binary::Encoder>,
+  ) {
+    if self.len().is_multiple_of(INDEX_INTERVAL) {
+      self.index.push(IndexEntry {
+        timestamp: record.timestamp.as_secs(),
+        offset,
+        encoder: encoder(),
+      });
+    }
+    self.records.push(record);
+    self.evict();
+  }
+
+  /// Evicts the oldest groups of records which don't have to be kept in
+  /// memory, their index entries must already exist.
+  fn evict(&mut self) {
+    if let ResidentRecords::Newest(newest) = self.resident_records {
+      // at least one record is kept, so that the latest one is in memory
+      while self.records.len() >= newest.max(1) + INDEX_INTERVAL {
+        self.records.drain(..INDEX_INTERVAL);
+        self.evicted += INDEX_INTERVAL;
+      }
+    }
+  }
+}
+
+impl<T: DeserializeOwned + Columns> FileStorage<T> {
+  /// A crash in the middle of a push may leave a partially written record at
+  /// the end of the file. Such a record is truncated instead of failing (or
+  /// just skipped if the storage is read-only), but invalid records before
+  /// it are still treated as errors.
+  fn read(&mut self) -> Fallible<()> {
+    self.file.seek(SeekFrom::Start(0))?;
+
+    self.records = vec![];
+    self.evicted = 0;
+    self.index = vec![];
+
+    match self.encoding {
+      Encoding::Json => self.read_json()?,
+      Encoding::Binary(_) => self.read_binary()?,
+    }
+
+    info!(
+      "read {} records, {} of them are kept in memory",
+      self.len(),
+      self.records.len()
+    );
+    Ok(())
+  }
+
+  fn read_binary(&mut self) -> Fallible<()> {
+    if self.file.metadata()?.len() == 0 {
+      // the header hasn't been written yet
+      self.dirty = true;
+      return Ok(());
+    }
+
+    let file = self.file.try_clone()?;
+    let mut decoder = binary::Decoder::new(BufReader::new(file));
+    loop {
+      let offset = decoder.offset();
+      // the state is cloned only for the records which get an index entry
+      let encoder = self
+        .len()
+        .is_multiple_of(INDEX_INTERVAL)
+        .then(|| decoder.encoder().clone());
+      match decoder.next_block()? {
+        Some(Block::Record(record)) => self.load(record, offset, || encoder),
+        Some(Block::Torn(reason)) => {
+          warn!(
+            "{} a partially written record at offset {} ({})",
+            if self.read_only { "skipping" } else { "truncating" },
+            offset,
+            reason
+          );
+          if !self.read_only {
+            self.file.set_len(offset)?;
+            self.file.sync_all()?;
+            self.dirty = offset == 0;
+          }
+          break;
+        }
+        None => break,
+      }
+    }
+
+    self.encoding = Encoding::Binary(decoder.encoder().clone());
+    Ok(())
+  }
+
+  fn read_json(&mut self) -> Fallible<()> {
+    let mut reader = BufReader::new(self.file.try_clone()?);
+    let mut line_number = 1;
+    let mut line_start: u64 = 0;
+    let mut line = Vec::with_capacity(128);
+    let mut missing_newline = false;
+    while reader.read_until(b'\n', &mut line)? > 0 {
+      let line_len = line.len() as u64;
+      match serde_json::from_slice(&line) {
+        Ok(record) => {
+          self.load(record, line_start, || None);
+          missing_newline = line.last() != Some(&b'\n');
+        }
+        Err(error) => {
+          let is_last_line = reader.fill_buf()?.is_empty();
+          if !is_last_line {
+            return Err(
+              error
+                .context(format!(
+                  "failed to deserialize line {}: {:?}",
+                  line_number,
+                  String::from_utf8_lossy(&line)
+                ))
+                .into(),
+            );
+          }
+
+          warn!(
+            "{} a partially written record on line {}: {:?} ({})",
+            if self.read_only { "skipping" } else { "truncating" },
+            line_number,
+            String::from_utf8_lossy(&line),
+            error
+          );
+          if self.read_only {
+            break;
+          }
+          drop(reader);
+          self.file.set_len(line_start)?;
+          self.file.sync_all()?;
+          break;
+        }
+      }
+      line_start += line_len;
+      line.clear();
+      line_number += 1;
+    }
+
+    if missing_newline && !self.read_only {
+      // the record is complete, the crash happened right before the newline
+      // was written
+      self.file.seek(SeekFrom::End(0))?;
+      self.file.write_all(b"\n")?;
+      self.file.sync_all()?;
+    }
+
+    Ok(())
+  }
+
+  /// Reads the evicted records in the inclusive range into `out`, in the same
+  /// way as `Storage::range`. Only the groups of records between the index
+  /// entries around the range are read.
+  fn read_evicted(
+    &self,
+    from: Option<&Timestamp>,
+    to: Option<&Timestamp>,
+    reversed: bool,
+    limit: usize,
+    out: &mut Vec<Record<T>>,
+  ) -> Fallible<()> {
+    let from = from.map_or(i64::MIN, Timestamp::as_secs);
+    let to = to.map_or(i64::MAX, Timestamp::as_secs);
+    let entries = &self.index[..self.evicted / INDEX_INTERVAL];
+    if entries.is_empty() || out.len() >= limit {
+      return Ok(());
+    }
+    let mut reader = BufReader::new(File::open(&self.path)?);
+    let in_range =
+      |record: &Record<T>| (from..=to).contains(&record.timestamp.as_secs());
+
+    if reversed {
+      let end = entries.partition_point(|entry| entry.timestamp <= to);
+      for entry in entries[..end].iter().rev() {
+        let records = self.read_group(&mut reader, entry)?;
+        for record in records.into_iter().rev().filter(in_range) {
+          out.push(record);
+          if out.len() >= limit {
+            return Ok(());
+          }
+        }
+        if entry.timestamp < from {
+          break;
+        }
+      }
+    } else {
+      // records in the range may start in the middle of the preceding group
+      let start = entries
+        .partition_point(|entry| entry.timestamp < from)
+        .saturating_sub(1);
+      for entry in &entries[start..] {
+        if entry.timestamp > to {
+          break;
+        }
+        let records = self.read_group(&mut reader, entry)?;
+        for record in records.into_iter().filter(in_range) {
+          out.push(record);
+          if out.len() >= limit {
+            return Ok(());
+          }
+        }
+      }
+    }
+    Ok(())
+  }
+
+  /// Reads `INDEX_INTERVAL` records starting at the entry, only the groups of
+  /// evicted records are complete.
+  fn read_group(
+    &self,
+    reader: &mut BufReader<File>,
+    entry: &IndexEntry,
+  ) -> Fallible<Vec<Record<T>>> {
+    reader.seek(SeekFrom::Start(entry.offset))?;
+    let mut records = Vec::with_capacity(INDEX_INTERVAL);
+
+    match &entry.encoder {
+      None => {
+        let mut line = Vec::with_capacity(128);
+        while records.len() < INDEX_INTERVAL {
+          line.clear();
+          if reader.read_until(b'\n', &mut line)? == 0 {
+            break;
+          }
+          records.push(serde_json::from_slice(&line).with_context(|_| {
+            format!(
+              "failed to deserialize an evicted record: {:?}",
+              String::from_utf8_lossy(&line)
+            )
+          })?);
+        }
+      }
+      Some(encoder) => {
+        let mut decoder =
+          binary::Decoder::resume(reader, entry.offset, encoder.clone());
+        while records.len() < INDEX_INTERVAL {
+          match decoder.next_block()? {
+            Some(Block::Record(record)) => records.push(record),
+            Some(Block::Torn(reason)) => {
+              return Err(failure::format_err!(
+                "an evicted record is invalid ({})",
+                reason
+              ))
+            }
+            None => break,
+          }
+        }
+      }
+    }
+    Ok(records)
+  }
+}
+
+impl<T: Serialize + Columns + Debug> FileStorage<T> {
+  /// Makes sure that the file contains all records. Pushed records are
+  /// already in the file, so it is rewritten only if it is dirty, otherwise
+  /// just the records which haven't been synchronized yet are flushed.
+  ///
+  /// The records are written into a temporary file which then replaces the
+  /// database file, so a crash in the middle of a rewrite leaves the old file
+  /// intact. The evicted records are copied from the old file as they are.
+  fn write_file(&mut self) -> Fallible<()> {
+    if self.read_only {
+      return Err(failure::err_msg("the storage is opened read-only"));
+    }
+    if !self.dirty {
+      if self.unsynced_records > 0 {
+        if let SyncMode::Always | SyncMode::Batched(_) = self.sync_mode {
+          self.file.sync_data().context("failed to synchronize the file")?;
+          self.unsynced_records = 0;
+        }
+      }
+      info!("no changes since the last write");
+      return Ok(());
+    }
+
+    let mut temp_path = self.path.clone().into_os_string();
+    temp_path.push(".tmp");
+    let temp_path = PathBuf::from(temp_path);
+
+    info!("writing to the temporary file '{}'", temp_path.display());
+    let temp_file =
+      File::create(&temp_path).context("failed to create temporary file")?;
+    let mut writer = BufWriter::new(&temp_file);
+
+    let mut offset = 0;
+    let mut encoder = None;
+    if self.evicted > 0 {
+      let resident_entry = &self.index[self.evicted / INDEX_INTERVAL];
+      offset = resident_entry.offset;
+      encoder = resident_entry.encoder.clone();
+      let copied = io::copy(
+        &mut io::Read::take(File::open(&self.path)?, offset),
+        &mut writer,
+      )?;
+      if copied < offset {
+        return Err(failure::err_msg("the evicted records are incomplete"));
+      }
+    } else if let Encoding::Binary(_) = self.encoding {
+      writer.write_all(binary::HEADER)?;
+      offset = binary::HEADER.len() as u64;
+      encoder = Some(binary::Encoder::default());
+    }
+
+    // the offsets of the records in memory are written anew
+    self.index.truncate(self.evicted / INDEX_INTERVAL);
+    let mut bytes = vec![];
+    for (index, record) in self.records.iter().enumerate() {
+      if (self.evicted + index).is_multiple_of(INDEX_INTERVAL) {
+        self.index.push(IndexEntry {
+          timestamp: record.timestamp.as_secs(),
+          offset,
+          encoder: encoder.clone(),
+        });
+      }
+
+      bytes.clear();
+      match &mut encoder {
+        None => {
+          serde_json::to_writer(&mut bytes, &record).with_context(|_| {
+            format!("failed to serialize record {:?}", record)
+          })?;
+          bytes.push(b'\n');
+        }
+        Some(encoder) => encoder.encode(record, &mut bytes),
+      }
+      writer.write_all(&bytes)?;
+      offset += bytes.len() as u64;
+    }
+    writer.flush()?;
+    drop(writer);
+    temp_file.sync_all().context("failed to synchronize temporary file")?;
+
+    std::fs::rename(&temp_path, &self.path)
+      .context("failed to replace the database file")?;
+    sync_parent_dir(&self.path)?;
+
+    self.file = open_file(&self.path)?;
+    if let Some(encoder) = encoder {
+      self.encoding = Encoding::Binary(encoder);
+    }
+    self.dirty = false;
+    self.unsynced_records = 0;
+
+    info!("written {} records", self.len());
+    Ok(())
+  }
+
+  fn sync_pushed(&mut self) -> Fallible<()> {
+    self.unsynced_records += 1;
+    let needs_sync = match self.sync_mode {
+      SyncMode::Always => true,
+      SyncMode::Batched(records) => self.unsynced_records >= records,
+      SyncMode::Never => false,
+    };
+    if needs_sync {
+      self.file.sync_data().context("failed to synchronize the file")?;
+      self.unsynced_records = 0;
+    }
+    Ok(())
+  }
+}
+
+impl<T> Storage<T> for FileStorage<T>
+where
+  T: DeserializeOwned + Serialize + Columns + Clone + Debug + Send + Sync,
+{
+  fn push(&mut self, record: Record<T>) -> Fallible<()> {
+    let mut bytes = vec![];
+    // the state of the encoder is updated only after the block is written
+    let mut next_encoder = None;
+    match &self.encoding {
+      Encoding::Json => {
+        serde_json::to_writer(&mut bytes, &record).with_context(|_| {
+          format!("failed to serialize record {:?}", record)
+        })?;
+        bytes.push(b'\n');
+      }
+      Encoding::Binary(encoder) => {
+        let mut encoder = encoder.clone();
+        encoder.encode(&record, &mut bytes);
+        next_encoder = Some(encoder);
+      }
+    }
+
+    let end = self.file.seek(SeekFrom::End(0))?;
+    if let Err(error) = self.file.write_all(&bytes) {
+      // a partially written record would corrupt the ones pushed after it, the
+      // file is rewritten by the next `write` if it can't be truncated
+      self.dirty = true;
+      self.file.set_len(end)?;
+      self.dirty = false;
+      return Err(error.into());
+    }
+    let previous_encoder = match next_encoder {
+      Some(encoder) => {
+        match std::mem::replace(&mut self.encoding, Encoding::Binary(encoder)) {
+          Encoding::Binary(previous) => Some(previous),
+          Encoding::Json => None,
+        }
+      }
+      None => None,
+    };
+    self.load(record, end, || previous_encoder);
+    self.sync_pushed()?;
+    Ok(())
+  }
+
+  /// Records are pushed in chronological order, so the bounds are found with
+  /// a binary search.
+  fn range(
+    &self,
+    from: Option<&Timestamp>,
+    to: Option<&Timestamp>,
+    reversed: bool,
+    limit: Option<usize>,
+  ) -> Fallible<Vec<Record<T>>> {
+    let start = from.map_or(0, |from| {
+      self.records.partition_point(|r| r.timestamp.as_secs() < from.as_secs())
+    });
+    let end = to.map_or(self.records.len(), |to| {
+      self.records.partition_point(|r| r.timestamp.as_secs() <= to.as_secs())
+    });
+    let resident = &self.records[start..end.max(start)];
+    // the evicted records are older than all resident ones
+    let evicted_in_range = start == 0;
+
+    let limit = limit.unwrap_or(usize::MAX);
+    let mut records = vec![];
+    if reversed {
+      records.extend(resident.iter().rev().take(limit).cloned());
+      if evicted_in_range {
+        self.read_evicted(from, to, true, limit, &mut records)?;
+      }
+    } else {
+      if evicted_in_range {
+        self.read_evicted(from, to, false, limit, &mut records)?;
+      }
+      let rest = limit - records.len();
+      records.extend(resident.iter().take(rest).cloned());
+    }
+    Ok(records)
+  }
+
+  fn latest(&self) -> Fallible<Option<Record<T>>> {
+    Ok(self.records.last().cloned())
+  }
+
+  fn count(&self) -> Fallible<usize> {
+    Ok(self.len())
+  }
+
+  fn move_to(&mut self, path: &Path, sync_mode: SyncMode) -> Fallible<()> {
+    self.write_file()?;
+    std::fs::rename(&self.path, path)
+      .context("failed to move the database file")?;
+    sync_parent_dir(path)?;
+    self.path = path.to_owned();
+    self.sync_mode = sync_mode;
+    Ok(())
+  }
+
+  fn write(&mut self) -> Fallible<()> {
+    self.write_file()
+  }
+}
+
+fn open_file(path: &Path) -> Fallible<File> {
+  info!("opening file '{}'", path.display());
+  let file = OpenOptions::new()
+    .read(true)
+    .write(true)
+    .create(true)
+    .truncate(false)
+    .open(path)
+    .context("failed to open file")?;
+  Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::record::RecordValue;
+  use crate::testing::TempPath;
+
+  fn record(timestamp: i64, value: u64) -> Record<u64> {
+    Record {
+      timestamp: Timestamp::new(timestamp),
+      value: RecordValue::Data(value),
+    }
+  }
+
+  #[test]
+  fn torn_last_line_is_truncated() {
+    let path = TempPath::new("torn.json");
+    std::fs::write(
+      &*path,
+      "{\"timestamp\":1,\"data\":1}\n{\"timestamp\":2,\"da",
+    )
+    .unwrap();
+
+    let mut storage = FileStorage::<u64>::open(
+      &path,
+      StorageFormat::Json,
+      SyncMode::Always,
+      ResidentRecords::All,
+    )
+    .unwrap();
+    assert_eq!(storage.count().unwrap(), 1);
+    storage.push(record(3, 3)).unwrap();
+    drop(storage);
+
+    assert_eq!(
+      std::fs::read_to_string(&*path).unwrap(),
+      "{\"timestamp\":1,\"data\":1}\n{\"timestamp\":3,\"data\":3}\n"
+    );
+  }
+
+  #[test]
+  fn complete_last_line_gets_a_newline() {
+    let path = TempPath::new("newline.json");
+    std::fs::write(&*path, "{\"timestamp\":1,\"data\":1}").unwrap();
+
+    let mut storage = FileStorage::<u64>::open(
+      &path,
+      StorageFormat::Json,
+      SyncMode::Batched(2),
+      ResidentRecords::All,
+    )
+    .unwrap();
+    assert_eq!(storage.count().unwrap(), 1);
+    storage.push(record(2, 2)).unwrap();
+    drop(storage);
+
+    assert_eq!(
+      std::fs::read_to_string(&*path).unwrap(),
+      "{\"timestamp\":1,\"data\":1}\n{\"timestamp\":2,\"data\":2}\n"
+    );
+  }
+
+  #[test]
+  fn write_rewrites_only_dirty_files() {
+    use std::os::unix::fs::MetadataExt;
+    let inode = |path: &Path| std::fs::metadata(path).unwrap().ino();
+
+    let path = TempPath::new("rewrite.json");
+    let mut storage = FileStorage::<u64>::open(
+      &path,
+      StorageFormat::Json,
+      SyncMode::Never,
+      ResidentRecords::All,
+    )
+    .unwrap();
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    assert!(!Path::new(&temp_path).exists());
+
+    let original_inode = inode(&path);
+    storage.push(record(1, 1)).unwrap();
+    storage.write().unwrap();
+    assert_eq!(inode(&path), original_inode);
+
+    storage.dirty = true;
+    storage.write().unwrap();
+    assert_ne!(inode(&path), original_inode);
+    storage.push(record(2, 2)).unwrap();
+    drop(storage);
+
+    assert_eq!(
+      std::fs::read_to_string(&*path).unwrap(),
+      "{\"timestamp\":1,\"data\":1}\n{\"timestamp\":2,\"data\":2}\n"
+    );
+  }
+
+  #[test]
+  fn invalid_line_in_the_middle_is_an_error() {
+    let path = TempPath::new("invalid.json");
+    let contents =
+      "{\"timestamp\":1,\"data\":1}\n{\"timest\n{\"timestamp\":3,\"data\":3}\n";
+    std::fs::write(&*path, contents).unwrap();
+
+    assert!(FileStorage::<u64>::open(
+      &path,
+      StorageFormat::Json,
+      SyncMode::Never,
+      ResidentRecords::All,
+    )
+    .is_err());
+    assert_eq!(std::fs::read_to_string(&*path).unwrap(), contents);
+  }
+
+  #[test]
+  fn binary_torn_block_is_truncated() {
+    let path = TempPath::new("binary.bin");
+    let open = || {
+      FileStorage::<u64>::open(
+        &path,
+        StorageFormat::Binary,
+        SyncMode::Always,
+        ResidentRecords::All,
+      )
+      .unwrap()
+    };
+
+    let mut storage = open();
+    for timestamp in 1..=3 {
+      storage.push(record(timestamp * 300, timestamp as u64 * 10)).unwrap();
+    }
+    drop(storage);
+    let len = std::fs::metadata(&*path).unwrap().len();
+
+    let mut file = OpenOptions::new().append(true).open(&*path).unwrap();
+    file.write_all(&[5, 0, 0, 0, 1, 2]).unwrap();
+    drop(file);
+
+    let storage = open();
+    assert_eq!(storage.count().unwrap(), 3);
+    assert_eq!(std::fs::metadata(&*path).unwrap().len(), len);
+    drop(storage);
+
+    // a file system may leave zeros in place of the appended data
+    let mut file = OpenOptions::new().append(true).open(&*path).unwrap();
+    file.write_all(&[0; 4096]).unwrap();
+    drop(file);
+
+    let mut storage = open();
+    assert_eq!(storage.count().unwrap(), 3);
+    assert_eq!(std::fs::metadata(&*path).unwrap().len(), len);
+    storage.push(record(1200, 5)).unwrap();
+    drop(storage);
+
+    let storage = open();
+    let values: Vec<(i64, u64)> = storage
+      .range(None, None, false, None)
+      .unwrap()
+      .iter()
+      .map(|record| (record.timestamp.as_secs(), *record.data().unwrap()))
+      .collect();
+    assert_eq!(values, vec![(300, 10), (600, 20), (900, 30), (1200, 5)]);
+  }
+
+  #[test]
+  fn evicted_records_are_read_from_the_file() {
+    let records: Vec<Record<u64>> =
+      (0..1000).map(|index| record(index * 300, index as u64 % 7)).collect();
+    let timestamps = |records: Vec<Record<u64>>| -> Vec<i64> {
+      records.iter().map(|record| record.timestamp.as_secs()).collect()
+    };
+
+    for &format in &[StorageFormat::Json, StorageFormat::Binary] {
+      let path = TempPath::new(&format!("evicted.{}", format.extension()));
+      let open = || {
+        FileStorage::<u64>::open(
+          &path,
+          format,
+          SyncMode::Never,
+          ResidentRecords::Newest(100),
+        )
+        .unwrap()
+      };
+
+      let mut storage = open();
+      for record in &records[..600] {
+        storage.push(record.clone()).unwrap();
+      }
+      drop(storage);
+      let mut storage = open();
+      for record in &records[600..] {
+        storage.push(record.clone()).unwrap();
+      }
+      // the file is rewritten with the evicted records copied from the old one
+      storage.dirty = true;
+      storage.write().unwrap();
+
+      for storage in [storage, open()] {
+        assert_eq!(storage.count().unwrap(), records.len());
+        assert!(storage.records.len() < 100 + INDEX_INTERVAL);
+        assert_eq!(storage.evicted + storage.records.len(), records.len());
+
+        let all = storage.range(None, None, false, None).unwrap();
+        assert_eq!(all.len(), records.len());
+        for (read, record) in all.iter().zip(&records) {
+          assert_eq!(read.timestamp.as_secs(), record.timestamp.as_secs());
+          assert_eq!(read.value, record.value);
+        }
+
+        for &(from, to) in &[(0, 299_700), (100, 600), (76_800, 153_600)] {
+          let (from, to) = (Timestamp::new(from), Timestamp::new(to));
+          let expected: Vec<i64> = (0..1000)
+            .map(|index| index * 300)
+            .filter(|&t| t >= from.as_secs() && t <= to.as_secs())
+            .collect();
+          for &limit in &[None, Some(1), Some(300)] {
+            let limit_len = limit.unwrap_or(usize::MAX).min(expected.len());
+            let range = |reversed| {
+              timestamps(
+                storage.range(Some(&from), Some(&to), reversed, limit).unwrap(),
+              )
+            };
+            assert_eq!(range(false), expected[..limit_len].to_vec());
+            let mut newest = expected[expected.len() - limit_len..].to_vec();
+            newest.reverse();
+            assert_eq!(range(true), newest);
+          }
+        }
+      }
+    }
+  }
+}
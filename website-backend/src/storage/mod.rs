@@ -0,0 +1,334 @@
+mod file;
+mod sqlite;
+
+pub use self::file::FileStorage;
+pub use self::sqlite::SqliteStorage;
+
+use failure::{Fallible, ResultExt};
+
+use serde::de::DeserializeOwned;
+use serde::ser::Serialize;
+use std::fmt::Debug;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::config::{ResidentRecords, StorageFormat, SyncMode};
+use crate::record::{Record, Timestamp};
+use crate::trackers::Columns;
+
+/// Persistent storage of the records of a tracker. Records are pushed in
+/// chronological order.
+pub trait Storage<T>: Send + Sync {
+  fn push(&mut self, record: Record<T>) -> Fallible<()>;
+
+  /// Returns records with timestamps in the inclusive range, the newest ones
+  /// first if `reversed` is set. At most `limit` records are returned, those
+  /// closest to the start of the scan.
+  fn range(
+    &self,
+    from: Option<&Timestamp>,
+    to: Option<&Timestamp>,
+    reversed: bool,
+    limit: Option<usize>,
+  ) -> Fallible<Vec<Record<T>>>;
+
+  fn latest(&self) -> Fallible<Option<Record<T>>>;
+
+  fn count(&self) -> Fallible<usize>;
+
+  /// Persists the storage and moves it into `path`, replacing the file there,
+  /// the pushed records are then synchronized according to `sync_mode`. Used
+  /// to put a `Rewrite` in place of the original storage.
+  fn move_to(&mut self, path: &Path, sync_mode: SyncMode) -> Fallible<()>;
+
+  /// Makes sure that all pushed records have been persisted.
+  fn write(&mut self) -> Fallible<()>;
+}
+
+/// Opens the storage at `path`, it is created if it doesn't exist.
+/// `resident_records` applies only to the formats stored in files.
+pub fn open<T>(
+  path: &Path,
+  format: StorageFormat,
+  sync_mode: SyncMode,
+  resident_records: ResidentRecords,
+) -> Fallible<Box<dyn Storage<T>>>
+where
+  T: DeserializeOwned + Serialize + Columns + Clone + Debug + Send + Sync,
+  T: 'static,
+{
+  Ok(match format {
+    StorageFormat::Json | StorageFormat::Binary => {
+      Box::new(FileStorage::open(path, format, sync_mode, resident_records)?)
+    }
+    StorageFormat::Sqlite => Box::new(SqliteStorage::open(path, sync_mode)?),
+  })
+}
+
+/// Opens an existing storage without modifying it in any way, e.g. to read
+/// the records of a tracker while the server isn't running. Pushing records
+/// into it fails.
+pub fn open_read_only<T>(
+  path: &Path,
+  format: StorageFormat,
+  resident_records: ResidentRecords,
+) -> Fallible<Box<dyn Storage<T>>>
+where
+  T: DeserializeOwned + Serialize + Columns + Clone + Debug + Send + Sync,
+  T: 'static,
+{
+  Ok(match format {
+    StorageFormat::Json | StorageFormat::Binary => {
+      Box::new(FileStorage::open_read_only(path, format, resident_records)?)
+    }
+    StorageFormat::Sqlite => Box::new(SqliteStorage::open_read_only(path)?),
+  })
+}
+
+/// Copy of a storage which is written next to it, e.g. with some of the
+/// records replaced, and then put in its place. The records can be pushed
+/// into the copy while the original one is still in use.
+pub struct Rewrite<T> {
+  path: PathBuf,
+  format: StorageFormat,
+  sync_mode: SyncMode,
+  resident_records: ResidentRecords,
+  copy: Box<dyn Storage<T>>,
+}
+
+impl<T> Rewrite<T>
+where
+  T: DeserializeOwned + Serialize + Columns + Clone + Debug + Send + Sync,
+  T: 'static,
+{
+  /// Creates an empty copy of the storage at `path`, the parameters must be
+  /// the ones which the storage has been opened with.
+  pub fn start(
+    path: &Path,
+    format: StorageFormat,
+    sync_mode: SyncMode,
+    resident_records: ResidentRecords,
+  ) -> Fallible<Self> {
+    let mut copy_path = path.to_owned().into_os_string();
+    copy_path.push(".rewrite");
+    let copy_path = PathBuf::from(copy_path);
+    // a copy may be left over if the previous rewrite has crashed
+    match std::fs::remove_file(&copy_path) {
+      Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+      result => result.context("failed to remove the previous copy")?,
+    }
+
+    // the copy is synchronized only once it is complete
+    let copy_sync_mode = SyncMode::Batched(u32::MAX);
+    Ok(Self {
+      path: path.to_owned(),
+      format,
+      sync_mode,
+      resident_records,
+      copy: open(&copy_path, format, copy_sync_mode, resident_records)?,
+    })
+  }
+
+  pub fn push(&mut self, record: Record<T>) -> Fallible<()> {
+    self.copy.push(record)
+  }
+
+  /// Puts the copy in place of `storage`, which must be the original one. If
+  /// this fails, `storage` is the original one again.
+  pub fn finish(self, storage: &mut Box<dyn Storage<T>>) -> Fallible<()> {
+    storage.write()?;
+    let original = std::mem::replace(storage, self.copy);
+    // the original storage is closed before its file is replaced
+    drop(original);
+
+    if let Err(error) = storage.move_to(&self.path, self.sync_mode) {
+      *storage =
+        open(&self.path, self.format, self.sync_mode, self.resident_records)
+          .context("failed to reopen the original storage")?;
+      return Err(error);
+    }
+    Ok(())
+  }
+}
+
+/// The renames and new files in the directory are persisted only after it is
+/// synchronized.
+fn sync_parent_dir(path: &Path) -> Fallible<()> {
+  let dir = match path.parent() {
+    Some(dir) if dir != Path::new("") => dir,
+    _ => Path::new("."),
+  };
+  File::open(dir)
+    .and_then(|dir| dir.sync_all())
+    .context("failed to synchronize the database directory")?;
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::record::{Gap, RecordValue};
+  use crate::testing::TempPath;
+
+  fn timestamps(records: Vec<Record<u64>>) -> Vec<i64> {
+    records.iter().map(|record| record.timestamp.as_secs()).collect()
+  }
+
+  #[test]
+  fn formats_return_the_same_ranges() {
+    for &format in
+      &[StorageFormat::Json, StorageFormat::Binary, StorageFormat::Sqlite]
+    {
+      let path =
+        TempPath::new(&format!("storage-range.{}", format.extension()));
+
+      let mut storage =
+        open::<u64>(&path, format, SyncMode::Never, ResidentRecords::All)
+          .unwrap();
+      assert!(storage.latest().unwrap().is_none());
+      for timestamp in (300..=1500).step_by(300) {
+        let value = if timestamp == 900 {
+          RecordValue::Gap(Gap { reason: "timeout".to_owned() })
+        } else {
+          RecordValue::Data(timestamp as u64 / 100)
+        };
+        storage
+          .push(Record { timestamp: Timestamp::new(timestamp), value })
+          .unwrap();
+      }
+      storage.write().unwrap();
+      drop(storage);
+
+      let storage =
+        open::<u64>(&path, format, SyncMode::Never, ResidentRecords::All)
+          .unwrap();
+      assert_eq!(storage.count().unwrap(), 5);
+      let latest = storage.latest().unwrap().unwrap();
+      assert_eq!(
+        (latest.timestamp.as_secs(), latest.data()),
+        (1500, Some(&15))
+      );
+
+      let (from, to) = (Timestamp::new(600), Timestamp::new(1200));
+      let range = |reversed, limit| {
+        timestamps(
+          storage.range(Some(&from), Some(&to), reversed, limit).unwrap(),
+        )
+      };
+      assert_eq!(range(false, None), vec![600, 900, 1200]);
+      assert_eq!(range(true, None), vec![1200, 900, 600]);
+      assert_eq!(range(false, Some(2)), vec![600, 900]);
+      assert_eq!(range(true, Some(2)), vec![1200, 900]);
+      assert_eq!(
+        storage.range(None, Some(&from), false, None).unwrap()[0].value,
+        RecordValue::Data(3)
+      );
+      assert_eq!(
+        storage.range(Some(&to), Some(&from), false, None).unwrap().len(),
+        0
+      );
+
+      let gap = storage
+        .range(
+          Some(&Timestamp::new(900)),
+          Some(&Timestamp::new(900)),
+          false,
+          None,
+        )
+        .unwrap();
+      assert_eq!(
+        gap[0].value,
+        RecordValue::Gap(Gap { reason: "timeout".to_owned() })
+      );
+    }
+  }
+
+  #[test]
+  fn read_only_storages_are_not_modified() {
+    for &format in
+      &[StorageFormat::Json, StorageFormat::Binary, StorageFormat::Sqlite]
+    {
+      let path =
+        TempPath::new(&format!("storage-read-only.{}", format.extension()));
+      let mut storage =
+        open::<u64>(&path, format, SyncMode::Never, ResidentRecords::All)
+          .unwrap();
+      for timestamp in 0..3 {
+        let value = RecordValue::Data(timestamp as u64);
+        storage
+          .push(Record { timestamp: Timestamp::new(timestamp), value })
+          .unwrap();
+      }
+      storage.write().unwrap();
+      drop(storage);
+      let bytes = std::fs::read(&*path).unwrap();
+
+      let mut storage =
+        open_read_only::<u64>(&path, format, ResidentRecords::All).unwrap();
+      assert_eq!(storage.count().unwrap(), 3);
+      let records = storage.range(None, None, false, None).unwrap();
+      assert_eq!(timestamps(records), vec![0, 1, 2]);
+      let record =
+        Record { timestamp: Timestamp::new(3), value: RecordValue::Data(3) };
+      assert!(storage.push(record).is_err());
+      drop(storage);
+      assert_eq!(std::fs::read(&*path).unwrap(), bytes, "{:?}", format);
+    }
+  }
+
+  #[test]
+  fn failed_rewrite_keeps_the_original() {
+    for &format in
+      &[StorageFormat::Json, StorageFormat::Binary, StorageFormat::Sqlite]
+    {
+      let path =
+        TempPath::new(&format!("storage-rewrite.{}", format.extension()));
+      let copy_path = PathBuf::from(format!("{}.rewrite", path.display()));
+      let open = || {
+        open::<u64>(&path, format, SyncMode::Never, ResidentRecords::Newest(2))
+      };
+      let start =
+        || Rewrite::start(&path, format, SyncMode::Never, ResidentRecords::All);
+      let record = |timestamp| Record {
+        timestamp: Timestamp::new(timestamp),
+        value: RecordValue::Data(timestamp as u64),
+      };
+
+      let mut storage = open().unwrap();
+      for timestamp in 1..=5 {
+        storage.push(record(timestamp)).unwrap();
+      }
+
+      // the copy can't be created
+      std::fs::create_dir(&copy_path).unwrap();
+      assert!(start().is_err());
+      std::fs::remove_dir(&copy_path).unwrap();
+
+      // the copy disappears before it is moved
+      let mut rewrite = start().unwrap();
+      rewrite.push(record(1)).unwrap();
+      std::fs::remove_file(&copy_path).unwrap();
+      assert!(rewrite.finish(&mut storage).is_err());
+      storage.push(record(6)).unwrap();
+      assert_eq!(
+        timestamps(storage.range(None, None, false, None).unwrap()),
+        vec![1, 2, 3, 4, 5, 6]
+      );
+
+      let mut rewrite = start().unwrap();
+      rewrite.push(record(3)).unwrap();
+      rewrite.finish(&mut storage).unwrap();
+      storage.push(record(7)).unwrap();
+      storage.write().unwrap();
+      drop(storage);
+      assert!(!copy_path.exists());
+
+      let storage = open().unwrap();
+      assert_eq!(
+        timestamps(storage.range(None, None, false, None).unwrap()),
+        vec![3, 7]
+      );
+    }
+  }
+}
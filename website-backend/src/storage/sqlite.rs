@@ -0,0 +1,204 @@
+use failure::{Fail, Fallible, ResultExt};
+use log::info;
+
+use rusqlite::{params, Connection, OpenFlags};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use serde::de::DeserializeOwned;
+use serde::ser::Serialize;
+use std::fmt::Debug;
+
+use super::{sync_parent_dir, Storage};
+use crate::config::SyncMode;
+use crate::record::{Gap, Record, RecordValue, Timestamp};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS records (
+  timestamp INTEGER NOT NULL,
+  -- JSON of the data point, NULL for gaps
+  data TEXT,
+  gap TEXT
+);
+CREATE INDEX IF NOT EXISTS records_timestamp ON records (timestamp);
+";
+
+/// Stores the records in an SQLite database, so only the ones returned by
+/// range scans are loaded into memory. The database is in the WAL mode, the
+/// batched synchronization is approximated by synchronizing the log only at
+/// checkpoints.
+pub struct SqliteStorage<T> {
+  path: PathBuf,
+  /// `Connection` can't be shared between threads.
+  connection: Mutex<Connection>,
+  /// Cached, because SQLite counts the rows by scanning the whole index.
+  count: usize,
+  data_points: PhantomData<fn() -> T>,
+}
+
+impl<T> SqliteStorage<T> {
+  pub fn open(path: &Path, sync_mode: SyncMode) -> Fallible<Self> {
+    let connection = connect(path, sync_mode)?;
+    connection
+      .execute_batch(SCHEMA)
+      .context("failed to create the table of records")?;
+
+    Self::new(path, connection)
+  }
+
+  /// Opens an existing database without writing to it, its journal mode is
+  /// left as it is too.
+  pub fn open_read_only(path: &Path) -> Fallible<Self> {
+    info!("opening SQLite database '{}' read-only", path.display());
+    let connection =
+      Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .context("failed to open SQLite database")?;
+    Self::new(path, connection)
+  }
+
+  fn new(path: &Path, connection: Connection) -> Fallible<Self> {
+    let count: i64 = connection.query_row(
+      "SELECT COUNT(*) FROM records",
+      params![],
+      |row| row.get(0),
+    )?;
+    info!("found {} records", count);
+
+    Ok(Self {
+      path: path.to_owned(),
+      connection: Mutex::new(connection),
+      count: count as usize,
+      data_points: PhantomData,
+    })
+  }
+}
+
+fn connect(path: &Path, sync_mode: SyncMode) -> Fallible<Connection> {
+  info!("opening SQLite database '{}'", path.display());
+  let connection =
+    Connection::open(path).context("failed to open SQLite database")?;
+
+  let synchronous = match sync_mode {
+    SyncMode::Always => "FULL",
+    SyncMode::Batched(_) => "NORMAL",
+    SyncMode::Never => "OFF",
+  };
+  connection.execute_batch(&format!(
+    "PRAGMA journal_mode = WAL; PRAGMA synchronous = {};",
+    synchronous
+  ))?;
+  Ok(connection)
+}
+
+impl<T> Storage<T> for SqliteStorage<T>
+where
+  T: DeserializeOwned + Serialize + Debug,
+{
+  fn push(&mut self, record: Record<T>) -> Fallible<()> {
+    let (data, gap) = match &record.value {
+      RecordValue::Data(data) => (
+        Some(serde_json::to_string(data).with_context(|_| {
+          format!("failed to serialize record {:?}", record)
+        })?),
+        None,
+      ),
+      RecordValue::Gap(gap) => (None, Some(gap.reason.as_str())),
+    };
+
+    let connection = self.connection.lock().unwrap();
+    connection
+      .prepare_cached(
+        "INSERT INTO records (timestamp, data, gap) VALUES (?1, ?2, ?3)",
+      )?
+      .execute(params![record.timestamp.as_secs(), data, gap])?;
+    self.count += 1;
+    Ok(())
+  }
+
+  fn range(
+    &self,
+    from: Option<&Timestamp>,
+    to: Option<&Timestamp>,
+    reversed: bool,
+    limit: Option<usize>,
+  ) -> Fallible<Vec<Record<T>>> {
+    // the rowid keeps records with equal timestamps in the pushed order
+    let sql = if reversed {
+      "SELECT timestamp, data, gap FROM records \
+       WHERE timestamp BETWEEN ?1 AND ?2 \
+       ORDER BY timestamp DESC, rowid DESC LIMIT ?3"
+    } else {
+      "SELECT timestamp, data, gap FROM records \
+       WHERE timestamp BETWEEN ?1 AND ?2 \
+       ORDER BY timestamp, rowid LIMIT ?3"
+    };
+    let from = from.map_or(i64::MIN, Timestamp::as_secs);
+    let to = to.map_or(i64::MAX, Timestamp::as_secs);
+    // a negative limit means no limit
+    let limit = limit.map_or(-1, |limit| limit as i64);
+
+    let connection = self.connection.lock().unwrap();
+    let mut statement = connection.prepare_cached(sql)?;
+    let rows = statement.query_map(params![from, to, limit], |row| {
+      Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+    })?;
+
+    let mut records = vec![];
+    for row in rows {
+      let (timestamp, data, gap): (i64, Option<String>, Option<String>) = row?;
+      let value = match data {
+        Some(data) => {
+          RecordValue::Data(serde_json::from_str(&data).with_context(|_| {
+            format!("failed to deserialize the record at {}", timestamp)
+          })?)
+        }
+        None => RecordValue::Gap(Gap { reason: gap.unwrap_or_default() }),
+      };
+      records.push(Record { timestamp: Timestamp::new(timestamp), value });
+    }
+    Ok(records)
+  }
+
+  fn latest(&self) -> Fallible<Option<Record<T>>> {
+    Ok(self.range(None, None, true, Some(1))?.pop())
+  }
+
+  fn count(&self) -> Fallible<usize> {
+    Ok(self.count)
+  }
+
+  /// SQLite keeps the log next to the database under its name, so the
+  /// connection is closed while the file is moved.
+  fn move_to(&mut self, path: &Path, sync_mode: SyncMode) -> Fallible<()> {
+    let connection = self.connection.get_mut().unwrap();
+    connection
+      .execute_batch("PRAGMA wal_checkpoint(TRUNCATE);")
+      .context("failed to checkpoint the SQLite database")?;
+    let closed = std::mem::replace(connection, Connection::open_in_memory()?);
+    if let Err((closed, error)) = closed.close() {
+      *connection = closed;
+      return Err(error.context("failed to close the SQLite database").into());
+    }
+
+    let moved = std::fs::rename(&self.path, path)
+      .context("failed to move the SQLite database");
+    let path = if moved.is_ok() { path } else { &self.path };
+    *connection = connect(path, sync_mode)?;
+    moved?;
+    sync_parent_dir(path)?;
+    self.path = path.to_owned();
+    Ok(())
+  }
+
+  /// The records are committed by every push, so the log is just merged into
+  /// the database file.
+  fn write(&mut self) -> Fallible<()> {
+    let connection = self.connection.lock().unwrap();
+    connection
+      .execute_batch("PRAGMA wal_checkpoint(TRUNCATE);")
+      .context("failed to checkpoint the SQLite database")?;
+    info!("checkpointed {} records", self.count);
+    Ok(())
+  }
+}
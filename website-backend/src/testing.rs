@@ -0,0 +1,81 @@
+//! Fixtures shared by the tests of several modules.
+
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+use crate::config::{ResidentRecords, StorageFormat, SyncMode};
+use crate::database::Database;
+use crate::trackers::Columns;
+
+/// Data points of the test databases, with a single column called `value`.
+impl Columns for u64 {
+  fn column_names(&self) -> Vec<String> {
+    vec!["value".to_owned()]
+  }
+
+  fn column_values(&self, names: &[String], values: &mut Vec<Option<u64>>) {
+    values.extend(names.iter().map(|_| Some(*self)));
+  }
+
+  fn from_column_values(_: &[String], values: &[Option<u64>]) -> Option<Self> {
+    values[0]
+  }
+}
+
+/// Path of a file in the temporary directory which is unique to the test and
+/// is removed when dropped, together with the files which the storages create
+/// next to it. A file left over by a previous run is removed right away.
+pub struct TempPath(PathBuf);
+
+impl TempPath {
+  pub fn new(name: &str) -> Self {
+    let file_name = format!("backend-test-{}-{}", std::process::id(), name);
+    let path = TempPath(std::env::temp_dir().join(file_name));
+    path.remove();
+    path
+  }
+
+  fn remove(&self) {
+    // the temporary files of rewrites and the journal files of SQLite
+    for file in &["", ".tmp", ".rewrite"] {
+      for journal in &["", "-wal", "-shm"] {
+        let mut path = self.0.clone().into_os_string();
+        path.push(file);
+        path.push(journal);
+        let _ = std::fs::remove_file(path);
+      }
+    }
+  }
+}
+
+impl Deref for TempPath {
+  type Target = Path;
+
+  fn deref(&self) -> &Path {
+    &self.0
+  }
+}
+
+impl Drop for TempPath {
+  fn drop(&mut self) {
+    self.remove();
+  }
+}
+
+/// Creates an empty database in a temporary file, the database must be
+/// dropped before the path.
+pub fn temp_database<T>(name: &str) -> (TempPath, Database<T>)
+where
+  T: serde::de::DeserializeOwned + serde::Serialize + std::fmt::Debug,
+  T: Columns + Clone + Send + Sync + 'static,
+{
+  let path = TempPath::new(&format!("{}.json", name));
+  let db = Database::init(
+    &path,
+    StorageFormat::Json,
+    SyncMode::Always,
+    ResidentRecords::All,
+  )
+  .unwrap();
+  (path, db)
+}
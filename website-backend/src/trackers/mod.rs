@@ -1,62 +1,420 @@
 pub mod ranker;
+pub mod reddit;
 
-use failure::{Error, Fail, Fallible};
-use log::info;
+use failure::{Error, Fail, Fallible, ResultExt};
+use log::{info, warn};
 
 use std::sync::{Arc, RwLock};
 use tokio::prelude::*;
 
+use rand::Rng;
+use serde::de::DeserializeOwned;
+use serde::ser::Serialize;
+use std::fmt::Debug;
+use std::path::Path;
 use std::time::{Duration, Instant};
+use tokio::timer::{timeout, Delay};
 
+use crate::alerts::{AlertRules, AlertSender};
+use crate::broadcast::{Broadcast, Subscription};
+use crate::config::{AlertRule, DatabaseConfig, RetentionRule, TrackerConfig};
 use crate::database::Database;
-use crate::http::HttpClient;
-use crate::record::{Record, Timestamp};
+use crate::http::{HttpClient, JsonMap};
+use crate::migrate::MigrateArgs;
+use crate::record::{Gap, Record, RecordValue, Timestamp};
 use crate::shutdown::Shutdown;
+use crate::stats::StatsDatabase;
 
-pub trait Tracker {
-  type DataPoint;
+pub trait Tracker: Send + Sync + Sized + 'static {
+  type Options: DeserializeOwned;
+  type DataPoint: DeserializeOwned
+    + Serialize
+    + Columns
+    + Clone
+    + Eq
+    + Debug
+    + Send
+    + Sync
+    + 'static;
+
+  fn new(options: Self::Options) -> Fallible<Self>;
 
   fn describe(&self) -> String;
 
+  /// Names of all columns which the data points of this tracker can have,
+  /// see `Columns::column_names`. Alert rules may refer only to them.
+  fn column_names(&self) -> Vec<String>;
+
   fn fetch_data_point(
     &self,
     http_client: &HttpClient,
   ) -> Box<dyn Future<Item = Self::DataPoint, Error = Error> + Send>;
 }
 
-pub fn start<D: serde::ser::Serialize + std::fmt::Debug>(
-  tracker: Box<dyn Tracker<DataPoint = D> + Send>,
+/// Data points which can be written as rows of a table, e.g. to serve them as
+/// JSON or CSV.
+pub trait Columns {
+  /// Names of the columns which this data point has values for. Tables have
+  /// the columns of all data points in them, in the order of the newest one.
+  fn column_names(&self) -> Vec<String>;
+
+  /// Pushes a value of every column in `names`, `None` if the data point
+  /// doesn't have one.
+  fn column_values(&self, names: &[String], values: &mut Vec<Option<u64>>);
+
+  /// Creates a data point from the values of the columns, the inverse of
+  /// `column_values`. Returns `None` if a required value is missing.
+  fn from_column_values(
+    names: &[String],
+    values: &[Option<u64>],
+  ) -> Option<Self>
+  where
+    Self: Sized;
+
+  /// Stats derived from the data point which are served next to it by the
+  /// `latest.json` endpoint, e.g. the percentages shown on the ranker page.
+  fn derived_stats(&self) -> JsonMap {
+    JsonMap::new()
+  }
+}
+
+struct TrackerType {
+  name: &'static str,
+  init:
+    fn(TrackerConfig, &Path, &DatabaseConfig) -> Fallible<Box<dyn AnyTracker>>,
+  migrate: fn(&MigrateArgs) -> Fallible<()>,
+}
+
+const TRACKER_TYPES: &[TrackerType] = &[
+  TrackerType {
+    name: "ranker",
+    init: TrackerInstance::<ranker::RankerTracker>::init,
+    migrate: crate::migrate::run::<ranker::DataPoint>,
+  },
+  TrackerType {
+    name: "reddit",
+    init: TrackerInstance::<reddit::RedditTracker>::init,
+    migrate: crate::migrate::run::<reddit::DataPoint>,
+  },
+];
+
+/// Converts a database of a tracker of the given type, see the `migrate`
+/// module.
+pub fn migrate(args: &MigrateArgs) -> Fallible<()> {
+  let tracker_type = TRACKER_TYPES
+    .iter()
+    .find(|tracker_type| tracker_type.name == args.tracker_type)
+    .ok_or_else(|| {
+      failure::format_err!("unknown tracker type: {}", args.tracker_type)
+    })?;
+  (tracker_type.migrate)(args)
+}
+
+pub struct Trackers {
+  instances: Vec<Box<dyn AnyTracker>>,
+}
+
+impl Trackers {
+  pub fn init(
+    configs: Vec<TrackerConfig>,
+    database_config: &DatabaseConfig,
+  ) -> Fallible<Self> {
+    let trackers_database_dir = database_config.dir.join("trackers");
+    info!("creating directory '{}'", trackers_database_dir.display());
+    std::fs::create_dir_all(&trackers_database_dir)
+      .context("failed to create the database directory")?;
+
+    let mut instances: Vec<Box<dyn AnyTracker>> = vec![];
+    for (index, config) in configs.into_iter().enumerate() {
+      info!(
+        "initializing tracker #{} '{}' of type '{}'",
+        index, config.id, config.type_name
+      );
+
+      if instances.iter().any(|instance| instance.id() == config.id) {
+        return Err(failure::format_err!(
+          "duplicate tracker id: {}",
+          config.id
+        ));
+      }
+
+      let tracker_type = TRACKER_TYPES
+        .iter()
+        .find(|tracker_type| tracker_type.name == config.type_name)
+        .ok_or_else(|| {
+          failure::format_err!("unknown tracker type: {}", config.type_name)
+        })?;
+
+      let id = config.id.clone();
+      let instance =
+        (tracker_type.init)(config, &trackers_database_dir, database_config)
+          .with_context(|_| format!("failed to initialize tracker '{}'", id))?;
+      instances.push(instance);
+    }
+
+    Ok(Self { instances })
+  }
+
+  pub fn start(
+    &self,
+    http_client: &HttpClient,
+    alerts: &AlertSender,
+    shutdown: &Shutdown,
+  ) -> Vec<Box<dyn Future<Item = (), Error = ()> + Send>> {
+    self
+      .instances
+      .iter()
+      .map(|instance| {
+        instance.start(http_client.clone(), alerts.clone(), shutdown.another())
+      })
+      .collect()
+  }
+
+  /// Returns descriptions of all trackers in the order of the config.
+  pub fn info(&self) -> Vec<TrackerInfo> {
+    self.instances.iter().map(|instance| instance.info()).collect()
+  }
+
+  pub fn write_databases(&self) -> Fallible<()> {
+    for instance in &self.instances {
+      info!("synchronizing database of tracker '{}'", instance.id());
+      instance.write_database().with_context(|_| {
+        format!("failed to write database of tracker '{}'", instance.id())
+      })?;
+    }
+    Ok(())
+  }
+}
+
+/// Description of a tracker for the HTTP server.
+pub struct TrackerInfo {
+  pub id: String,
+  pub type_name: String,
+  pub request_interval: Duration,
+  pub database: Arc<dyn StatsDatabase>,
+  pub broadcast: Broadcast,
+}
+
+impl TrackerInfo {
+  /// Subscribes to the pushed records, the ones newer than `since` are read
+  /// from the database first.
+  pub fn subscribe(&self, since: Option<i64>) -> Fallible<Subscription> {
+    let records = self.broadcast.subscribe();
+    let backfill = match since {
+      Some(timestamp) => self.database.serialize_records_since(timestamp)?,
+      None => vec![],
+    };
+    Ok(Subscription::new(records, backfill))
+  }
+}
+
+trait AnyTracker: Send + Sync {
+  fn id(&self) -> &str;
+
+  fn start(
+    &self,
+    http_client: HttpClient,
+    alerts: AlertSender,
+    shutdown: Shutdown,
+  ) -> Box<dyn Future<Item = (), Error = ()> + Send>;
+
+  fn info(&self) -> TrackerInfo;
+
+  fn write_database(&self) -> Fallible<()>;
+}
+
+struct TrackerInstance<T: Tracker> {
+  id: String,
+  type_name: String,
+  request_interval: Duration,
+  retry_policy: RetryPolicy,
+  alert_rules: Arc<Vec<AlertRule>>,
+  retention_rules: Arc<Vec<RetentionRule>>,
+  compaction_interval: Duration,
+  tracker: Arc<T>,
+  shared_db: Arc<RwLock<Database<T::DataPoint>>>,
+}
+
+impl<T: Tracker> TrackerInstance<T> {
+  fn init(
+    config: TrackerConfig,
+    database_dir: &Path,
+    database_config: &DatabaseConfig,
+  ) -> Fallible<Box<dyn AnyTracker>> {
+    let options: T::Options = crate::config::from_value(config.options)
+      .context("failed to parse tracker options")?;
+    let tracker = T::new(options)?;
+
+    let column_names = tracker.column_names();
+    for rule in &config.alerts {
+      if !column_names.iter().any(|name| name == rule.column()) {
+        return Err(failure::format_err!(
+          "alert rule refers to an unknown column: {}",
+          rule.column()
+        ));
+      }
+    }
+
+    let db = Database::init(
+      &database_dir.join(format!(
+        "{}.{}",
+        config.id,
+        config.storage.extension()
+      )),
+      config.storage,
+      database_config.sync,
+      database_config.resident_records,
+    )
+    .context("failed to initialize database")?;
+
+    Ok(Box::new(Self {
+      id: config.id,
+      type_name: config.type_name,
+      request_interval: config.request_interval,
+      retry_policy: RetryPolicy {
+        timeout: config.timeout,
+        retries: config.retries,
+        retry_delay: config.retry_delay,
+      },
+      alert_rules: Arc::new(config.alerts),
+      retention_rules: Arc::new(config.retention),
+      compaction_interval: database_config.compaction_interval,
+      tracker: Arc::new(tracker),
+      shared_db: Arc::new(RwLock::new(db)),
+    }))
+  }
+}
+
+impl<T: Tracker> AnyTracker for TrackerInstance<T> {
+  fn id(&self) -> &str {
+    &self.id
+  }
+
+  fn start(
+    &self,
+    http_client: HttpClient,
+    alerts: AlertSender,
+    shutdown: Shutdown,
+  ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+    let compaction = if self.retention_rules.is_empty() {
+      None
+    } else {
+      Some(crate::retention::start(
+        self.id.clone(),
+        self.shared_db.clone(),
+        self.retention_rules.clone(),
+        self.compaction_interval,
+        shutdown.another(),
+      ))
+    };
+
+    let tracking = start(
+      self.tracker.clone(),
+      self.request_interval,
+      self.retry_policy,
+      self.shared_db.clone(),
+      AlertRules {
+        tracker: self.id.clone(),
+        rules: self.alert_rules.clone(),
+        sender: alerts,
+      },
+      http_client,
+      shutdown,
+    );
+    match compaction {
+      Some(compaction) => Box::new(tracking.join(compaction).map(|_| ())),
+      None => Box::new(tracking),
+    }
+  }
+
+  fn info(&self) -> TrackerInfo {
+    TrackerInfo {
+      id: self.id.clone(),
+      type_name: self.type_name.clone(),
+      request_interval: self.request_interval,
+      database: self.shared_db.clone(),
+      broadcast: self.shared_db.read().unwrap().broadcast().clone(),
+    }
+  }
+
+  fn write_database(&self) -> Fallible<()> {
+    let mut db = self.shared_db.write().unwrap();
+    db.write()
+  }
+}
+
+pub fn start<T: Tracker>(
+  tracker: Arc<T>,
   request_interval: Duration,
-  shared_db: Arc<RwLock<Database<D>>>,
+  retry_policy: RetryPolicy,
+  shared_db: Arc<RwLock<Database<T::DataPoint>>>,
+  alert_rules: AlertRules,
+  http_client: HttpClient,
   shutdown: Shutdown,
 ) -> impl Future<Item = (), Error = ()> {
   info!("starting {}", tracker.describe());
 
-  let http_client = hyper::Client::new();
-
   tokio::timer::Interval::new(Instant::now(), request_interval)
     .map_err(|e: tokio::timer::Error| Error::from(e.context("timer error")))
+    .filter(move |&tick: &Instant| {
+      // The interval fires the ticks missed during a slow fetch back-to-back
+      // once it finishes, and those would all get stamped at about the same
+      // second, so they're skipped instead.
+      let now = Instant::now();
+      let late = now >= tick + request_interval;
+      if late {
+        warn!(
+          "skipping a tick which is {:.1} seconds late",
+          (now - tick).as_millis() as f64 / 1000.0
+        );
+      }
+      !late
+    })
     .and_then(move |_: Instant| {
+      // both data points and misses are stamped with the start of the tick
       let timestamp = Timestamp::now();
 
-      tracker.fetch_data_point(&http_client).then(|r: Result<D, Error>| match r
-      {
-        Ok(data) => Ok(Some(Record { timestamp, data })),
-        Err(e) => {
-          log_error!(log::Level::Warn, &e.context("API request error"));
-          Ok(None)
-        }
-      })
+      fetch_with_retries(tracker.clone(), http_client.clone(), retry_policy)
+        .then(move |r: Result<T::DataPoint, Miss>| match r {
+          Ok(data) => Ok(Record { timestamp, value: RecordValue::Data(data) }),
+          Err(miss) => {
+            let causes: Vec<String> =
+              miss.error.iter_chain().map(|cause| cause.to_string()).collect();
+            let reason = format!(
+              "{} attempt(s) failed: {}",
+              miss.attempts,
+              causes.join(": ")
+            );
+
+            let context =
+              format!("no data point for the tick at {:?}", timestamp);
+            log_error!(log::Level::Warn, &miss.error.context(context));
+
+            Ok(Record { timestamp, value: RecordValue::Gap(Gap { reason }) })
+          }
+        })
     })
-    .for_each(move |record: Option<Record<D>>| -> Fallible<()> {
-      if let Some(record) = record {
-        info!("{:?}", &record);
+    .for_each(move |record: Record<T::DataPoint>| -> Fallible<()> {
+      info!("{:?}", &record);
 
-        let mut db = shared_db.write().unwrap();
-        db.push(record).map_err(|e| {
-          Error::from(e.context("failed to push the record to the database"))
-        })?;
-      }
+      // the rules are checked against the preceding records under a read
+      // lock, this task is the only writer of the database anyway
+      let alerts = alert_rules
+        .check(&shared_db.read().unwrap(), &record)
+        .unwrap_or_else(|error| {
+          log_error!(
+            log::Level::Warn,
+            &error.context("failed to check the alert rules")
+          );
+          vec![]
+        });
+
+      let mut db = shared_db.write().unwrap();
+      db.push(record).map_err(|e| {
+        Error::from(e.context("failed to push the record to the database"))
+      })?;
+      drop(db);
+      alert_rules.send(alerts);
 
       Ok(())
     })
@@ -70,3 +428,220 @@ pub fn start<D: serde::ser::Serialize + std::fmt::Debug>(
       }
     })
 }
+
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+  pub timeout: Duration,
+  pub retries: u32,
+  pub retry_delay: Duration,
+}
+
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(5 * 60);
+
+impl RetryPolicy {
+  /// Exponential backoff with "equal jitter": half of the delay is fixed and
+  /// the other half is random.
+  fn backoff(&self, attempt: u32) -> Duration {
+    let delay = self
+      .retry_delay
+      .checked_mul(2u32.saturating_pow(attempt))
+      .map_or(MAX_RETRY_DELAY, |delay| delay.min(MAX_RETRY_DELAY));
+    let half_millis = delay.as_millis() as u64 / 2;
+    let jitter_millis = rand::thread_rng().gen_range(0, half_millis + 1);
+    Duration::from_millis(half_millis + jitter_millis)
+  }
+}
+
+/// Reason why an action retried with `with_retries` has failed.
+pub struct Miss {
+  pub attempts: u32,
+  pub error: Error,
+}
+
+fn fetch_with_retries<T: Tracker>(
+  tracker: Arc<T>,
+  http_client: HttpClient,
+  policy: RetryPolicy,
+) -> impl Future<Item = T::DataPoint, Error = Miss> {
+  with_retries(policy, move || tracker.fetch_data_point(&http_client))
+}
+
+/// Runs the future returned by `attempt` until it succeeds, with a timeout on
+/// every attempt and delays between them, as described by the `policy`.
+pub fn with_retries<F, R>(
+  policy: RetryPolicy,
+  mut attempt: F,
+) -> impl Future<Item = R::Item, Error = Miss>
+where
+  F: FnMut() -> R,
+  R: Future<Error = Error> + Send + 'static,
+  R::Item: Send + 'static,
+{
+  future::loop_fn(0, move |attempt_index: u32| {
+    attempt().timeout(policy.timeout).then(
+      move |r| -> Box<dyn Future<Item = _, Error = _> + Send> {
+        let error = match r {
+          Ok(data) => return Box::new(future::ok(future::Loop::Break(data))),
+          Err(e) => timeout_error(e, policy.timeout),
+        };
+
+        if attempt_index >= policy.retries {
+          return Box::new(future::err(Miss {
+            attempts: attempt_index + 1,
+            error,
+          }));
+        }
+
+        let delay = policy.backoff(attempt_index);
+        let reason = format!(
+          "attempt #{} failed, retrying in {:.1} seconds",
+          attempt_index + 1,
+          delay.as_millis() as f64 / 1000.0,
+        );
+        log_error!(log::Level::Warn, &error.context(reason));
+
+        Box::new(
+          Delay::new(Instant::now() + delay)
+            .map(move |()| future::Loop::Continue(attempt_index + 1))
+            .map_err(move |e| Miss {
+              attempts: attempt_index + 1,
+              error: e.context("timer error").into(),
+            }),
+        )
+      },
+    )
+  })
+}
+
+fn timeout_error(error: timeout::Error<Error>, timeout: Duration) -> Error {
+  if error.is_elapsed() {
+    failure::format_err!("request timed out after {:?}", timeout)
+  } else if error.is_timer() {
+    error.into_timer().unwrap().context("timer error").into()
+  } else {
+    error.into_inner().unwrap()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::sync::atomic::{AtomicU32, Ordering};
+
+  fn policy(retry_delay: Duration) -> RetryPolicy {
+    RetryPolicy { timeout: Duration::from_secs(1), retries: 10, retry_delay }
+  }
+
+  #[test]
+  fn backoff_doubles_with_equal_jitter() {
+    let policy = policy(Duration::from_millis(1000));
+    for attempt in 0..5 {
+      let delay = 1000 << attempt;
+      let backoffs: Vec<u128> =
+        (0..100).map(|_| policy.backoff(attempt).as_millis()).collect();
+      assert!(
+        backoffs
+          .iter()
+          .all(|&backoff| delay / 2 <= backoff && backoff <= delay),
+        "{:?}",
+        backoffs
+      );
+      // the random half isn't always the same
+      assert!(backoffs.iter().any(|&backoff| backoff != backoffs[0]));
+    }
+  }
+
+  #[test]
+  fn backoff_is_capped() {
+    let max = MAX_RETRY_DELAY.as_millis();
+    let seconds = policy(Duration::from_secs(1));
+    // 2^9 seconds are over the limit, larger exponents would overflow
+    for &attempt in &[9, 40, u32::MAX] {
+      for _ in 0..100 {
+        let backoff = seconds.backoff(attempt).as_millis();
+        assert!(max / 2 <= backoff && backoff <= max, "{}", backoff);
+      }
+    }
+    let backoff = policy(Duration::from_secs(1 << 40)).backoff(0).as_millis();
+    assert!(max / 2 <= backoff && backoff <= max, "{}", backoff);
+  }
+
+  /// Retries an action which hangs on the first attempt and then fails until
+  /// the attempt number `succeed_at`.
+  fn retry(retries: u32, succeed_at: u32) -> Result<u32, Miss> {
+    let policy = RetryPolicy {
+      timeout: Duration::from_millis(50),
+      retries,
+      retry_delay: Duration::from_millis(2),
+    };
+    let attempts = Arc::new(AtomicU32::new(0));
+    let future = with_retries(policy, move || {
+      let attempt = attempts.fetch_add(1, Ordering::SeqCst) + 1;
+      let result: Box<dyn Future<Item = u32, Error = Error> + Send> =
+        if attempt == 1 {
+          Box::new(future::empty())
+        } else if attempt < succeed_at {
+          Box::new(future::err(failure::format_err!("attempt #{}", attempt)))
+        } else {
+          Box::new(future::ok(attempt))
+        };
+      result
+    });
+    tokio::runtime::Runtime::new().unwrap().block_on(future)
+  }
+
+  #[test]
+  fn retries_are_counted_in_misses() {
+    let miss = retry(3, u32::MAX).err().unwrap();
+    assert_eq!(miss.attempts, 4);
+    assert_eq!(miss.error.to_string(), "attempt #4");
+
+    let miss = retry(0, u32::MAX).err().unwrap();
+    assert_eq!(miss.attempts, 1);
+    assert_eq!(miss.error.to_string(), "request timed out after 50ms");
+  }
+
+  #[test]
+  fn retries_stop_at_the_first_success() {
+    assert_eq!(retry(5, 3).ok(), Some(3));
+    assert_eq!(retry(2, 3).ok(), Some(3));
+  }
+
+  fn tracker_config(alerts: serde_json::Value) -> Fallible<TrackerConfig> {
+    crate::config::from_value(serde_json::json!({
+      "type": "reddit",
+      "id": "reddit",
+      "requestInterval": 600,
+      "options": { "subreddits": ["rust"] },
+      "alerts": alerts,
+    }))
+  }
+
+  #[test]
+  fn alert_rules_are_validated() {
+    let config = tracker_config(serde_json::json!([
+      { "type": "changed", "column": "rust_subscribers" },
+      { "type": "changed", "column": "rust_upvotes" },
+    ]))
+    .unwrap();
+    // the database directory isn't touched before the rules are validated
+    let database_config: DatabaseConfig =
+      serde_json::from_value(serde_json::json!({ "dir": "/nonexistent" }))
+        .unwrap();
+    let result = TrackerInstance::<reddit::RedditTracker>::init(
+      config,
+      Path::new("/nonexistent"),
+      &database_config,
+    );
+    assert_eq!(
+      result.err().unwrap().to_string(),
+      "alert rule refers to an unknown column: rust_upvotes"
+    );
+
+    let config = tracker_config(serde_json::json!([
+      { "type": "pageChanged", "pageSize": 0 },
+    ]));
+    assert!(config.is_err());
+  }
+}
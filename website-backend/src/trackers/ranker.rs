@@ -1,56 +1,220 @@
-use super::Tracker;
-use crate::http::{get_json, HttpClient, JsonValue};
-use failure::Error;
+use super::{Columns, Tracker};
+use crate::http::{get_json, HttpClient, JsonMap, JsonValue};
+use failure::{Error, Fallible};
 use hyper::Uri;
+use serde_json::json;
+use std::collections::BTreeSet;
+use std::sync::Arc;
 use tokio::prelude::*;
 
-const RANKER_API_URL: &str = "http://api.ranker.com/lists/298553/items/85372114?include=crowdRankedStats,votes";
+const RANKER_API_URL: &str = "https://api.ranker.com";
+/// Number of items on a single page of a list on the website.
+pub const ITEMS_PER_PAGE: u64 = 25;
+const COLUMNS: [&str; 5] =
+  ["rank", "upvotes", "downvotes", "reranks", "top5_reranks"];
 
-#[derive(Debug, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+/// Fields which are missing from the API response due to the `include`
+/// option are `None`.
+#[derive(Debug, Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct DataPoint {
   pub rank: u64,
-  pub upvotes: u64,
-  pub downvotes: u64,
-  pub reranks: u64,
-  pub top5_reranks: u64,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub upvotes: Option<u64>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub downvotes: Option<u64>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub reranks: Option<u64>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub top5_reranks: Option<u64>,
 }
 
-pub struct RankerTracker {
-  url: Uri,
+impl DataPoint {
+  /// Number of the page of the list which the item is shown on.
+  pub fn page(&self) -> u64 {
+    self.rank.div_ceil(ITEMS_PER_PAGE)
+  }
+}
+
+fn percent(value: Option<u64>, total: Option<u64>) -> Option<f64> {
+  match (value, total) {
+    (Some(value), Some(total)) if total > 0 => {
+      Some(value as f64 / total as f64 * 100.0)
+    }
+    _ => None,
+  }
+}
+
+fn column_names() -> Vec<String> {
+  COLUMNS.iter().map(|&name| name.to_owned()).collect()
+}
+
+impl Columns for DataPoint {
+  fn column_names(&self) -> Vec<String> {
+    column_names()
+  }
+
+  fn column_values(&self, names: &[String], values: &mut Vec<Option<u64>>) {
+    values.extend(names.iter().map(|name| match name.as_str() {
+      "rank" => Some(self.rank),
+      "upvotes" => self.upvotes,
+      "downvotes" => self.downvotes,
+      "reranks" => self.reranks,
+      "top5_reranks" => self.top5_reranks,
+      _ => None,
+    }));
+  }
+
+  fn from_column_values(
+    names: &[String],
+    values: &[Option<u64>],
+  ) -> Option<Self> {
+    let value = |column: &str| {
+      let index = names.iter().position(|name| name == column)?;
+      values[index]
+    };
+    Some(Self {
+      rank: value("rank")?,
+      upvotes: value("upvotes"),
+      downvotes: value("downvotes"),
+      reranks: value("reranks"),
+      top5_reranks: value("top5_reranks"),
+    })
+  }
+
+  /// The page number and the percentages shown on the ranker page.
+  fn derived_stats(&self) -> JsonMap {
+    let votes = self.upvotes.and_then(|up| Some(up + self.downvotes?));
+    let mut stats = JsonMap::new();
+    stats.insert("page".to_owned(), json!(self.page()));
+    stats.insert(
+      "upvotes_percent".to_owned(),
+      json!(percent(self.upvotes, votes)),
+    );
+    stats.insert(
+      "downvotes_percent".to_owned(),
+      json!(percent(self.downvotes, votes)),
+    );
+    stats.insert(
+      "top5_reranks_percent".to_owned(),
+      json!(percent(self.top5_reranks, self.reranks)),
+    );
+    stats
+  }
 }
 
-impl RankerTracker {
-  pub fn new() -> Self {
-    Self { url: Uri::from_static(RANKER_API_URL) }
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct Options {
+  pub list_id: String,
+  pub item_id: String,
+  /// Additional sets of stats requested from the API, all of them by default.
+  /// `crowdRankedStats` must be excluded for lists which aren't crowd-ranked.
+  #[serde(default = "default_include")]
+  pub include: BTreeSet<Include>,
+}
+
+#[derive(
+  Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, serde::Deserialize,
+)]
+#[serde(rename_all = "camelCase")]
+pub enum Include {
+  Votes,
+  CrowdRankedStats,
+}
+
+impl Include {
+  fn as_str(self) -> &'static str {
+    match self {
+      Include::Votes => "votes",
+      Include::CrowdRankedStats => "crowdRankedStats",
+    }
   }
 }
 
+fn default_include() -> BTreeSet<Include> {
+  [Include::Votes, Include::CrowdRankedStats].iter().cloned().collect()
+}
+
+pub struct RankerTracker {
+  url: Uri,
+  list_id: String,
+  item_id: String,
+  include: Arc<BTreeSet<Include>>,
+}
+
 impl Tracker for RankerTracker {
+  type Options = Options;
   type DataPoint = DataPoint;
 
+  fn new(options: Self::Options) -> Fallible<Self> {
+    let mut url = format!(
+      "{}/lists/{}/items/{}",
+      RANKER_API_URL, options.list_id, options.item_id
+    );
+    if !options.include.is_empty() {
+      let include: Vec<&str> =
+        options.include.iter().map(|include| include.as_str()).collect();
+      url.push_str("?include=");
+      url.push_str(&include.join(","));
+    }
+
+    Ok(Self {
+      url: url.parse()?,
+      list_id: options.list_id,
+      item_id: options.item_id,
+      include: Arc::new(options.include),
+    })
+  }
+
   fn describe(&self) -> String {
-    "ranker".to_owned()
+    format!("ranker (list {}, item {})", self.list_id, self.item_id)
+  }
+
+  fn column_names(&self) -> Vec<String> {
+    column_names()
   }
 
   fn fetch_data_point(
     &self,
     http_client: &HttpClient,
   ) -> Box<dyn Future<Item = Self::DataPoint, Error = Error> + Send> {
-    Box::new(get_json(&http_client, self.url.clone()).and_then(
-      |json: JsonValue| {
-        json_to_data_point(json)
+    let include = self.include.clone();
+    Box::new(get_json(http_client, self.url.clone()).and_then(
+      move |json: JsonValue| {
+        json_to_data_point(json, &include)
           .ok_or_else(|| failure::err_msg("malformed JSON response from API"))
       },
     ))
   }
 }
 
-fn json_to_data_point(json: JsonValue) -> Option<DataPoint> {
+fn json_to_data_point(
+  json: JsonValue,
+  include: &BTreeSet<Include>,
+) -> Option<DataPoint> {
+  let (upvotes, downvotes) = if include.contains(&Include::Votes) {
+    let votes = &json["votes"];
+    (Some(votes["upVotes"].as_u64()?), Some(votes["downVotes"].as_u64()?))
+  } else {
+    (None, None)
+  };
+
+  let (reranks, top5_reranks) = if include.contains(&Include::CrowdRankedStats)
+  {
+    let stats = &json["crowdRankedStats"];
+    (
+      Some(stats["totalContributingListCount"].as_u64()?),
+      Some(stats["top5ListCount"].as_u64()?),
+    )
+  } else {
+    (None, None)
+  };
+
   Some(DataPoint {
     rank: json["rank"].as_u64()?,
-    upvotes: json["votes"]["upVotes"].as_u64()?,
-    downvotes: json["votes"]["downVotes"].as_u64()?,
-    reranks: json["crowdRankedStats"]["totalContributingListCount"].as_u64()?,
-    top5_reranks: json["crowdRankedStats"]["top5ListCount"].as_u64()?,
+    upvotes,
+    downvotes,
+    reranks,
+    top5_reranks,
   })
 }
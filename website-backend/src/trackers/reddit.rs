@@ -0,0 +1,183 @@
+use super::{Columns, Tracker};
+use crate::http::{request_json, HttpClient, JsonValue};
+use failure::{Error, Fallible};
+use hyper::header::{self, HeaderValue};
+use hyper::{Body, Request, Uri};
+use tokio::prelude::*;
+
+const REDDIT_API_URL: &str = "https://api.reddit.com";
+const USER_AGENT: &str =
+  "subreddit subscriber count tracker v3.0 (by /u/dmitmel)";
+
+#[derive(Debug, Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct DataPoint(pub Vec<SubredditStats>);
+
+#[derive(Debug, Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct SubredditStats {
+  pub name: String,
+  pub subscribers: u64,
+  pub accounts_active: u64,
+}
+
+impl DataPoint {
+  fn subreddit(&self, name: &str) -> Option<&SubredditStats> {
+    self.0.iter().find(|stats| stats.name == name)
+  }
+}
+
+/// Subscriber counts of all subreddits go before their active user counts,
+/// like in the CSV files written by the old Python script.
+fn column_names<'a>(
+  subreddits: impl Iterator<Item = &'a str> + Clone,
+) -> Vec<String> {
+  let subscribers =
+    subreddits.clone().map(|name| format!("{}_subscribers", name));
+  let accounts_active =
+    subreddits.map(|name| format!("{}_accounts_active", name));
+  subscribers.chain(accounts_active).collect()
+}
+
+impl Columns for DataPoint {
+  fn column_names(&self) -> Vec<String> {
+    column_names(self.0.iter().map(|stats| stats.name.as_str()))
+  }
+
+  fn column_values(&self, names: &[String], values: &mut Vec<Option<u64>>) {
+    values.extend(names.iter().map(|name| {
+      if let Some(subreddit) = name.strip_suffix("_subscribers") {
+        self.subreddit(subreddit).map(|stats| stats.subscribers)
+      } else if let Some(subreddit) = name.strip_suffix("_accounts_active") {
+        self.subreddit(subreddit).map(|stats| stats.accounts_active)
+      } else {
+        None
+      }
+    }));
+  }
+
+  fn from_column_values(
+    names: &[String],
+    values: &[Option<u64>],
+  ) -> Option<Self> {
+    let value = |column: String| {
+      let index = names.iter().position(|name| *name == column)?;
+      values[index]
+    };
+    names
+      .iter()
+      .filter_map(|name| name.strip_suffix("_subscribers"))
+      // subreddits without both values weren't tracked at the time
+      .filter_map(|subreddit| {
+        let subscribers = value(format!("{}_subscribers", subreddit));
+        let accounts_active = value(format!("{}_accounts_active", subreddit));
+        match (subscribers, accounts_active) {
+          (Some(subscribers), Some(accounts_active)) => {
+            Some(Some(SubredditStats {
+              name: subreddit.to_owned(),
+              subscribers,
+              accounts_active,
+            }))
+          }
+          (None, None) => None,
+          _ => Some(None),
+        }
+      })
+      .collect::<Option<_>>()
+      .map(DataPoint)
+  }
+}
+
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Options {
+  pub subreddits: Vec<String>,
+}
+
+pub struct RedditTracker {
+  subreddits: Vec<(String, Uri)>,
+}
+
+impl Tracker for RedditTracker {
+  type Options = Options;
+  type DataPoint = DataPoint;
+
+  fn new(options: Self::Options) -> Fallible<Self> {
+    let subreddits = options
+      .subreddits
+      .into_iter()
+      .map(|name| {
+        let url = format!("{}/r/{}/about", REDDIT_API_URL, name).parse()?;
+        Ok((name, url))
+      })
+      .collect::<Fallible<_>>()?;
+    Ok(Self { subreddits })
+  }
+
+  fn describe(&self) -> String {
+    "reddit".to_owned()
+  }
+
+  fn column_names(&self) -> Vec<String> {
+    column_names(self.subreddits.iter().map(|(name, _)| name.as_str()))
+  }
+
+  fn fetch_data_point(
+    &self,
+    http_client: &HttpClient,
+  ) -> Box<dyn Future<Item = Self::DataPoint, Error = Error> + Send> {
+    let requests: Vec<_> = self
+      .subreddits
+      .iter()
+      .map(|(name, url)| {
+        let name = name.clone();
+
+        let mut req = Request::new(Body::default());
+        *req.uri_mut() = url.clone();
+        req
+          .headers_mut()
+          .insert(header::USER_AGENT, HeaderValue::from_static(USER_AGENT));
+
+        let context = format!("failed to fetch r/{}", name);
+        request_json(http_client, req)
+          .and_then(move |json: JsonValue| {
+            json_to_subreddit_stats(name, json).ok_or_else(|| {
+              failure::err_msg("malformed JSON response from API")
+            })
+          })
+          .map_err(|e| Error::from(e.context(context)))
+          .then(Ok::<_, Error>)
+      })
+      .collect();
+
+    // the subreddits are fetched independently, so one of them failing
+    // doesn't lose the stats of the rest
+    Box::new(future::join_all(requests).and_then(|results| {
+      let mut stats = vec![];
+      let mut errors = vec![];
+      for result in results {
+        match result {
+          Ok(subreddit) => stats.push(subreddit),
+          Err(error) => errors.push(error),
+        }
+      }
+      if stats.is_empty() && !errors.is_empty() {
+        return Err(errors.swap_remove(0));
+      }
+      for error in &errors {
+        log_error!(log::Level::Warn, error.as_fail());
+      }
+      Ok(DataPoint(stats))
+    }))
+  }
+}
+
+fn json_to_subreddit_stats(
+  name: String,
+  json: JsonValue,
+) -> Option<SubredditStats> {
+  let data = &json["data"];
+  Some(SubredditStats {
+    subscribers: data["subscribers"].as_u64()?,
+    accounts_active: data["accounts_active"].as_u64()?,
+    name,
+  })
+}
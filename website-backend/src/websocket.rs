@@ -0,0 +1,720 @@
+use bytes::{BufMut, BytesMut};
+use futures::future::Shared;
+use futures::sync::{mpsc, oneshot};
+use hyper::header::{self, HeaderMap};
+use hyper::upgrade::Upgraded;
+use log::info;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::codec::{Decoder, Encoder};
+use tokio::prelude::*;
+
+use crate::shutdown::Shutdown;
+use crate::trackers::TrackerInfo;
+
+/// Appended to the key of the client in the handshake, see RFC 6455.
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// Messages from clients are small, so anything larger is rejected.
+const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+/// Number of frames which can wait to be written to a client. Once they
+/// fill up, the messages of the client aren't read and its subscriptions stop
+/// forwarding records, which eventually unsubscribes them.
+const FRAME_BUFFER: usize = 16;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+const CLOSE_NORMAL: u16 = 1000;
+const CLOSE_GOING_AWAY: u16 = 1001;
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+const CLOSE_UNSUPPORTED_DATA: u16 = 1003;
+const CLOSE_TOO_BIG: u16 = 1009;
+
+/// Checks the headers of a WebSocket handshake request and returns the value
+/// of the `Sec-WebSocket-Accept` header for the response.
+pub fn accept_key(headers: &HeaderMap) -> Option<String> {
+  let has_token = |name: header::HeaderName, token: &str| {
+    headers.get_all(name).iter().any(|value| {
+      value.to_str().ok().is_some_and(|value| {
+        value.split(',').any(|t| t.trim().eq_ignore_ascii_case(token))
+      })
+    })
+  };
+  if !has_token(header::UPGRADE, "websocket")
+    || !has_token(header::CONNECTION, "upgrade")
+    || headers.get(header::SEC_WEBSOCKET_VERSION)? != "13"
+  {
+    return None;
+  }
+
+  let key = headers.get(header::SEC_WEBSOCKET_KEY)?;
+  let mut sha1 = sha1::Sha1::new();
+  sha1.update(key.as_bytes());
+  sha1.update(HANDSHAKE_GUID.as_bytes());
+  Some(base64::encode(&sha1.digest().bytes()))
+}
+
+#[derive(Debug)]
+struct Frame {
+  fin: bool,
+  opcode: u8,
+  payload: Vec<u8>,
+}
+
+impl Frame {
+  fn text(text: String) -> Self {
+    Self { fin: true, opcode: OPCODE_TEXT, payload: text.into_bytes() }
+  }
+
+  fn close(code: u16, reason: &str) -> Self {
+    let mut payload = code.to_be_bytes().to_vec();
+    payload.extend_from_slice(reason.as_bytes());
+    Self { fin: true, opcode: OPCODE_CLOSE, payload }
+  }
+}
+
+/// Violation of the protocol by a client, which is reported by closing the
+/// connection with `code`.
+#[derive(Debug)]
+struct ProtocolError {
+  code: u16,
+  reason: &'static str,
+}
+
+impl ProtocolError {
+  fn new(code: u16, reason: &'static str) -> Self {
+    Self { code, reason }
+  }
+
+  /// The close frame sent after reading from the connection has failed.
+  fn close_frame(error: &io::Error) -> Frame {
+    match error.get_ref().and_then(|error| error.downcast_ref::<Self>()) {
+      Some(error) => Frame::close(error.code, error.reason),
+      None => Frame::close(CLOSE_NORMAL, ""),
+    }
+  }
+}
+
+impl fmt::Display for ProtocolError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(self.reason)
+  }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl From<ProtocolError> for io::Error {
+  fn from(error: ProtocolError) -> Self {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+  }
+}
+
+/// Decodes frames sent by clients, which are always masked, and encodes
+/// unmasked frames sent by the server.
+struct FrameCodec;
+
+impl Decoder for FrameCodec {
+  type Item = Frame;
+  type Error = io::Error;
+
+  fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Frame>> {
+    let protocol_error =
+      |reason| Err(ProtocolError::new(CLOSE_PROTOCOL_ERROR, reason).into());
+
+    if src.len() < 2 {
+      return Ok(None);
+    }
+    if src[0] & 0x70 != 0 {
+      return protocol_error("reserved bits are set");
+    }
+    if src[1] & 0x80 == 0 {
+      return protocol_error("frame from the client isn't masked");
+    }
+
+    let (len, header_len) = match src[1] & 0x7F {
+      126 if src.len() >= 4 => {
+        (u64::from(u16::from_be_bytes([src[2], src[3]])), 4)
+      }
+      127 if src.len() >= 10 => {
+        let mut len = [0; 8];
+        len.copy_from_slice(&src[2..10]);
+        (u64::from_be_bytes(len), 10)
+      }
+      126 | 127 => return Ok(None),
+      len => (u64::from(len), 2),
+    };
+    let fin = src[0] & 0x80 != 0;
+    let opcode = src[0] & 0x0F;
+    // control frames can be sent in the middle of a fragmented message, so
+    // they must fit into a single frame
+    if opcode & 0x08 != 0 {
+      if !fin {
+        return protocol_error("control frame is fragmented");
+      }
+      if len > 125 {
+        return protocol_error("control frame is too big");
+      }
+    }
+    if len > MAX_MESSAGE_SIZE as u64 {
+      return Err(ProtocolError::new(CLOSE_TOO_BIG, "frame is too big").into());
+    }
+    let len = len as usize;
+    if src.len() < header_len + 4 + len {
+      return Ok(None);
+    }
+
+    let frame = src.split_to(header_len + 4 + len);
+    let mask = &frame[header_len..header_len + 4];
+    let payload = frame[header_len + 4..]
+      .iter()
+      .enumerate()
+      .map(|(index, byte)| byte ^ mask[index % 4])
+      .collect();
+    Ok(Some(Frame { fin, opcode, payload }))
+  }
+}
+
+impl Encoder for FrameCodec {
+  type Item = Frame;
+  type Error = io::Error;
+
+  fn encode(&mut self, frame: Frame, dst: &mut BytesMut) -> io::Result<()> {
+    let len = frame.payload.len();
+    dst.reserve(10 + len);
+    dst.put_u8(if frame.fin { 0x80 } else { 0 } | frame.opcode);
+    if len < 126 {
+      dst.put_u8(len as u8);
+    } else if len <= usize::from(u16::MAX) {
+      dst.put_u8(126);
+      dst.put_u16_be(len as u16);
+    } else {
+      dst.put_u8(127);
+      dst.put_u64_be(len as u64);
+    }
+    dst.put_slice(&frame.payload);
+    Ok(())
+  }
+}
+
+/// Messages sent by clients, tracker updates are sent as
+/// `{"type":"record","tracker":"<id>","record":{...}}`. A client which falls
+/// behind the updates is unsubscribed and should subscribe again with `since`.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum ClientMessage {
+  /// Records newer than `since` are sent before the live ones.
+  Subscribe {
+    trackers: Vec<String>,
+    #[serde(default)]
+    since: Option<i64>,
+  },
+  Unsubscribe {
+    trackers: Vec<String>,
+  },
+}
+
+/// Serves a connection upgraded to the WebSocket protocol until either side
+/// closes it or the server is shut down.
+pub fn serve(
+  upgraded: Upgraded,
+  remote_addr: SocketAddr,
+  trackers: Arc<Vec<TrackerInfo>>,
+  shutdown: Shared<Shutdown>,
+) -> impl Future<Item = (), Error = ()> {
+  info!("{} opened a WebSocket connection", remote_addr);
+
+  let (sink, frames) = FrameCodec.framed(upgraded).split();
+  let (sender, receiver) = mpsc::channel::<Frame>(FRAME_BUFFER);
+
+  // frames are sent through a channel, because the subscriptions send them
+  // from their own tasks
+  let writer = receiver
+    .map_err(|()| io::Error::other("channel error"))
+    .forward(sink)
+    .map(|_| ());
+
+  // the close frame always fits, every sender has a slot in the channel
+  let mut close_sender = sender.clone();
+  let connection = Connection {
+    frames,
+    sender,
+    trackers,
+    subscriptions: HashMap::new(),
+    message: None,
+  };
+  let reader = connection.select2(shutdown).then(move |result| {
+    match result {
+      Ok(future::Either::A(_)) => {}
+      Ok(future::Either::B(_)) | Err(future::Either::B(_)) => {
+        let frame = Frame::close(CLOSE_GOING_AWAY, "server is shutting down");
+        let _ = close_sender.try_send(frame);
+      }
+      Err(future::Either::A((error, _))) => {
+        let _ = close_sender.try_send(ProtocolError::close_frame(&error));
+      }
+    }
+    Ok(())
+  });
+
+  reader.join(writer).then(move |result: io::Result<((), ())>| {
+    if let Err(error) = result {
+      log_error!(log::Level::Warn, &error);
+    }
+    info!("{} closed the WebSocket connection", remote_addr);
+    Ok(())
+  })
+}
+
+/// Handles the frames received from a client.
+struct Connection<S> {
+  frames: S,
+  sender: mpsc::Sender<Frame>,
+  trackers: Arc<Vec<TrackerInfo>>,
+  /// Dropping a sender cancels the subscription.
+  subscriptions: HashMap<String, oneshot::Sender<()>>,
+  /// Payload of a fragmented message which hasn't been received completely.
+  message: Option<(u8, Vec<u8>)>,
+}
+
+impl<S> Connection<S> {
+  /// Only a single frame is sent after the sender has been polled ready.
+  fn send(&mut self, frame: Frame) {
+    // the writer stops only after the connection has been closed
+    let _ = self.sender.try_send(frame);
+  }
+
+  fn send_json(&mut self, json: serde_json::Value) {
+    self.send(Frame::text(json.to_string()));
+  }
+
+  fn handle_message(&mut self, text: &str) {
+    let message: ClientMessage = match serde_json::from_str(text) {
+      Ok(message) => message,
+      Err(error) => {
+        return self.send_json(serde_json::json!({
+          "type": "error",
+          "message": format!("invalid message: {}", error),
+        }));
+      }
+    };
+
+    match message {
+      ClientMessage::Subscribe { trackers, since } => {
+        if let Some(id) = self.find_unknown_tracker(&trackers) {
+          return self.send_json(serde_json::json!({
+            "type": "error",
+            "message": format!("unknown tracker: {}", id),
+          }));
+        }
+        self.send_json(serde_json::json!({
+          "type": "subscribed",
+          "trackers": trackers,
+        }));
+        for id in trackers {
+          self.subscribe(id, since);
+        }
+      }
+
+      ClientMessage::Unsubscribe { trackers } => {
+        for id in &trackers {
+          self.subscriptions.remove(id);
+        }
+        self.send_json(serde_json::json!({
+          "type": "unsubscribed",
+          "trackers": trackers,
+        }));
+      }
+    }
+  }
+
+  fn find_unknown_tracker<'a>(&self, ids: &'a [String]) -> Option<&'a str> {
+    ids
+      .iter()
+      .find(|id| !self.trackers.iter().any(|tracker| &tracker.id == *id))
+      .map(String::as_str)
+  }
+
+  /// Subscribing to a tracker again replaces the previous subscription.
+  fn subscribe(&mut self, id: String, since: Option<i64>) {
+    let tracker =
+      self.trackers.iter().find(|tracker| tracker.id == id).unwrap();
+    let id_json = serde_json::Value::String(id.clone()).to_string();
+    let subscription = match tracker.subscribe(since) {
+      Ok(subscription) => subscription,
+      Err(error) => {
+        log_error!(log::Level::Error, error.as_fail());
+        return self.send_json(serde_json::json!({
+          "type": "error",
+          "message": format!("failed to subscribe to {}", id),
+        }));
+      }
+    };
+    // the subscription ends if the client doesn't keep up with the records
+    let ended = serde_json::json!({
+      "type": "unsubscribed",
+      "trackers": [&id],
+    });
+    let frames = subscription
+      .map(move |record| {
+        Frame::text(format!(
+          r#"{{"type":"record","tracker":{},"record":{}}}"#,
+          id_json, record.json
+        ))
+      })
+      .chain(stream::once(Ok(Frame::text(ended.to_string()))));
+
+    let (cancel_sender, cancel_receiver) = oneshot::channel::<()>();
+    self.subscriptions.insert(id, cancel_sender);
+    let sender = self.sender.clone().sink_map_err(|_| ());
+    tokio::spawn(
+      frames
+        .forward(sender)
+        .map(|_| ())
+        .select(cancel_receiver.then(|_| Ok(())))
+        .then(|_| Ok(())),
+    );
+  }
+}
+
+impl<S: Stream<Item = Frame, Error = io::Error>> Future for Connection<S> {
+  type Item = ();
+  type Error = io::Error;
+
+  fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+    loop {
+      // every frame sends at most one frame back, so the next one is read
+      // only once that can be sent
+      match self.sender.poll_ready() {
+        Ok(Async::Ready(())) => {}
+        Ok(Async::NotReady) => return Ok(Async::NotReady),
+        // the writer has stopped, so nothing can be sent anymore
+        Err(_) => return Ok(Async::Ready(())),
+      }
+      let frame = match futures::try_ready!(self.frames.poll()) {
+        Some(frame) => frame,
+        None => return Ok(Async::Ready(())),
+      };
+
+      match frame.opcode {
+        OPCODE_PING => self.send(Frame { opcode: OPCODE_PONG, ..frame }),
+        OPCODE_PONG => {}
+        OPCODE_CLOSE => {
+          // the close frame is echoed back, after which the writer stops
+          self.send(Frame { fin: true, ..frame });
+          return Ok(Async::Ready(()));
+        }
+
+        OPCODE_TEXT | OPCODE_BINARY | OPCODE_CONTINUATION => {
+          let (opcode, payload) = match (self.message.take(), frame.opcode) {
+            (None, OPCODE_CONTINUATION)
+            | (Some(_), OPCODE_TEXT)
+            | (Some(_), OPCODE_BINARY) => {
+              self.send(Frame::close(CLOSE_PROTOCOL_ERROR, "unexpected frame"));
+              return Ok(Async::Ready(()));
+            }
+            (None, opcode) => (opcode, frame.payload),
+            (Some((opcode, mut payload)), _) => {
+              payload.extend_from_slice(&frame.payload);
+              (opcode, payload)
+            }
+          };
+
+          if payload.len() > MAX_MESSAGE_SIZE {
+            self.send(Frame::close(CLOSE_TOO_BIG, "message is too big"));
+            return Ok(Async::Ready(()));
+          }
+          if !frame.fin {
+            self.message = Some((opcode, payload));
+            continue;
+          }
+
+          match (opcode, String::from_utf8(payload)) {
+            (OPCODE_TEXT, Ok(text)) => self.handle_message(&text),
+            _ => {
+              let reason = "only text messages are supported";
+              self.send(Frame::close(CLOSE_UNSUPPORTED_DATA, reason));
+              return Ok(Async::Ready(()));
+            }
+          }
+        }
+
+        _ => {
+          self.send(Frame::close(CLOSE_PROTOCOL_ERROR, "unknown opcode"));
+          return Ok(Async::Ready(()));
+        }
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::record::{Record, RecordValue, Timestamp};
+  use crate::testing::temp_database;
+  use std::sync::RwLock;
+  use std::time::Duration;
+  use tokio::runtime::current_thread::Runtime;
+
+  /// Encodes a frame as a client would, with a mask.
+  fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> BytesMut {
+    let mut frame = BytesMut::new();
+    let mut codec = FrameCodec;
+    let payload = payload.to_vec();
+    codec.encode(Frame { fin, opcode, payload }, &mut frame).unwrap();
+
+    let mask = [0x12, 0x34, 0x56, 0x78];
+    let header_len = match frame[1] {
+      126 => 4,
+      127 => 10,
+      _ => 2,
+    };
+    let mut masked = BytesMut::from(&frame[..header_len]);
+    masked[1] |= 0x80;
+    masked.extend_from_slice(&mask);
+    let payload = frame[header_len..].iter().enumerate();
+    masked.extend(payload.map(|(index, byte)| byte ^ mask[index % 4]));
+    masked
+  }
+
+  fn decode(bytes: &[u8]) -> io::Result<Option<Frame>> {
+    FrameCodec.decode(&mut BytesMut::from(bytes))
+  }
+
+  fn close_code(error: io::Error) -> u16 {
+    let frame = ProtocolError::close_frame(&error);
+    u16::from_be_bytes([frame.payload[0], frame.payload[1]])
+  }
+
+  /// Feeds the frames to a connection and returns the opcodes and payloads
+  /// of the frames sent back.
+  fn converse(frames: Vec<BytesMut>) -> Vec<(u8, String)> {
+    let (sender, receiver) = mpsc::channel(FRAME_BUFFER);
+    let frames = frames.into_iter().map(|mut bytes| {
+      let frame = FrameCodec.decode(&mut bytes).unwrap().unwrap();
+      assert!(bytes.is_empty());
+      frame
+    });
+    let connection = Connection {
+      frames: stream::iter_ok(frames),
+      sender,
+      trackers: Arc::new(vec![]),
+      subscriptions: HashMap::new(),
+      message: None,
+    };
+    connection.wait().unwrap();
+
+    let sent = receiver.collect().wait().unwrap();
+    sent
+      .into_iter()
+      .map(|frame| {
+        assert!(frame.fin);
+        let payload = match frame.opcode {
+          OPCODE_CLOSE => {
+            let code = u16::from_be_bytes([frame.payload[0], frame.payload[1]]);
+            code.to_string()
+          }
+          _ => String::from_utf8(frame.payload).unwrap(),
+        };
+        (frame.opcode, payload)
+      })
+      .collect()
+  }
+
+  #[test]
+  fn masked_frames_round_trip() {
+    for &len in &[0, 125, 126, 300, MAX_MESSAGE_SIZE] {
+      let payload: Vec<u8> = (0..len).map(|index| index as u8).collect();
+      let bytes = client_frame(false, OPCODE_BINARY, &payload);
+
+      // the frame is decoded only once all of its bytes are available
+      let mut src = BytesMut::new();
+      for (index, &byte) in bytes.iter().enumerate() {
+        src.extend_from_slice(&[byte]);
+        let frame = FrameCodec.decode(&mut src).unwrap();
+        if index + 1 < bytes.len() {
+          assert!(frame.is_none());
+        } else {
+          let frame = frame.unwrap();
+          assert_eq!((frame.fin, frame.opcode), (false, OPCODE_BINARY));
+          assert_eq!(frame.payload, payload);
+        }
+      }
+      assert!(src.is_empty());
+    }
+  }
+
+  #[test]
+  fn server_frames_are_unmasked() {
+    let mut dst = BytesMut::new();
+    let frame = Frame::text("hello".to_owned());
+    FrameCodec.encode(frame, &mut dst).unwrap();
+    assert_eq!(&dst[..], b"\x81\x05hello");
+
+    for &(len, header) in
+      &[(300, &[0x82, 126, 0x01, 0x2C][..]), (70_000, &[0x82, 127, 0, 0][..])]
+    {
+      let mut dst = BytesMut::new();
+      let payload = vec![0; len];
+      let frame = Frame { fin: true, opcode: OPCODE_BINARY, payload };
+      FrameCodec.encode(frame, &mut dst).unwrap();
+      assert!(dst.starts_with(header));
+      let header_len = if len > 0xFFFF { 10 } else { 4 };
+      assert_eq!(dst.len(), header_len + len);
+    }
+  }
+
+  #[test]
+  fn invalid_frames_are_rejected() {
+    let mut unmasked = BytesMut::new();
+    FrameCodec.encode(Frame::text("hi".to_owned()), &mut unmasked).unwrap();
+    assert_eq!(close_code(decode(&unmasked).unwrap_err()), 1002);
+
+    let mut reserved = client_frame(true, OPCODE_TEXT, b"hi");
+    reserved[0] |= 0x40;
+    assert_eq!(close_code(decode(&reserved).unwrap_err()), 1002);
+
+    // the frame is rejected as soon as its length is known
+    let too_big = client_frame(true, OPCODE_TEXT, &[0; MAX_MESSAGE_SIZE + 1]);
+    assert_eq!(close_code(decode(&too_big[..10]).unwrap_err()), 1009);
+  }
+
+  #[test]
+  fn control_frames_must_be_small_and_whole() {
+    assert!(decode(&client_frame(true, OPCODE_PING, &[0; 125])).is_ok());
+    let too_big = client_frame(true, OPCODE_PING, &[0; 126]);
+    assert_eq!(close_code(decode(&too_big).unwrap_err()), 1002);
+    let fragmented = client_frame(false, OPCODE_CLOSE, &[0x03, 0xE8]);
+    assert_eq!(close_code(decode(&fragmented).unwrap_err()), 1002);
+  }
+
+  #[test]
+  fn fragmented_messages_are_joined() {
+    let message = br#"{"type":"unsubscribe","trackers":["a"]}"#;
+    let frames = vec![
+      client_frame(false, OPCODE_TEXT, &message[..10]),
+      client_frame(true, OPCODE_PING, b"ping"),
+      client_frame(false, OPCODE_CONTINUATION, &message[10..20]),
+      client_frame(true, OPCODE_CONTINUATION, &message[20..]),
+      client_frame(true, OPCODE_CLOSE, &[0x03, 0xE8]),
+    ];
+    let response = r#"{"trackers":["a"],"type":"unsubscribed"}"#;
+    assert_eq!(
+      converse(frames),
+      vec![
+        (OPCODE_PONG, "ping".to_owned()),
+        (OPCODE_TEXT, response.to_owned()),
+        (OPCODE_CLOSE, "1000".to_owned()),
+      ]
+    );
+  }
+
+  #[test]
+  fn invalid_messages_close_the_connection() {
+    let closed = |frames| converse(frames).pop().unwrap();
+    let continuation = client_frame(true, OPCODE_CONTINUATION, b"}");
+    assert_eq!(closed(vec![continuation]), (OPCODE_CLOSE, "1002".to_owned()));
+
+    let interrupted = vec![
+      client_frame(false, OPCODE_TEXT, b"{"),
+      client_frame(true, OPCODE_TEXT, b"{}"),
+    ];
+    assert_eq!(closed(interrupted), (OPCODE_CLOSE, "1002".to_owned()));
+
+    let half = vec![0; MAX_MESSAGE_SIZE / 2 + 1];
+    let too_big = vec![
+      client_frame(false, OPCODE_TEXT, &half),
+      client_frame(true, OPCODE_CONTINUATION, &half),
+    ];
+    assert_eq!(closed(too_big), (OPCODE_CLOSE, "1009".to_owned()));
+
+    let binary = vec![client_frame(true, OPCODE_BINARY, b"\x00")];
+    assert_eq!(closed(binary), (OPCODE_CLOSE, "1003".to_owned()));
+  }
+
+  /// Lets the spawned tasks run until they wait for something.
+  fn run_tasks(runtime: &mut Runtime) {
+    let mut yielded = false;
+    let yield_once = future::poll_fn(move || -> Poll<(), ()> {
+      if yielded {
+        return Ok(Async::Ready(()));
+      }
+      yielded = true;
+      futures::task::current().notify();
+      Ok(Async::NotReady)
+    });
+    runtime.block_on(yield_once).unwrap();
+  }
+
+  #[test]
+  fn lagging_clients_are_unsubscribed() {
+    let mut runtime = Runtime::new().unwrap();
+    let (_path, db) = temp_database::<u64>("websocket-lagging");
+    let broadcast = db.broadcast().clone();
+    let shared_db = Arc::new(RwLock::new(db));
+    let tracker = TrackerInfo {
+      id: "test".to_owned(),
+      type_name: "test".to_owned(),
+      request_interval: Duration::from_secs(60),
+      broadcast,
+      database: shared_db.clone(),
+    };
+
+    // the client sends a subscription and then never reads the frames
+    let subscribe = br#"{"type":"subscribe","trackers":["test"]}"#;
+    let mut subscribe = client_frame(true, OPCODE_TEXT, subscribe);
+    let subscribe = FrameCodec.decode(&mut subscribe).unwrap().unwrap();
+    let (sender, mut receiver) = mpsc::channel(FRAME_BUFFER);
+    let connection = Connection {
+      frames: stream::iter_ok(vec![subscribe]).chain(stream::poll_fn(
+        || -> Poll<Option<Frame>, io::Error> { Ok(Async::NotReady) },
+      )),
+      sender,
+      trackers: Arc::new(vec![tracker]),
+      subscriptions: HashMap::new(),
+      message: None,
+    };
+    runtime.spawn(connection.map_err(|_| ()));
+    run_tasks(&mut runtime);
+
+    let count = 1000;
+    for timestamp in 0..count {
+      let value = RecordValue::Data(timestamp as u64);
+      let record = Record { timestamp: Timestamp::new(timestamp), value };
+      shared_db.write().unwrap().push(record).unwrap();
+      run_tasks(&mut runtime);
+    }
+
+    let mut read = || {
+      let frames = runtime.block_on(receiver.by_ref().take(1).collect());
+      let frame = frames.unwrap().pop().unwrap();
+      serde_json::from_slice::<serde_json::Value>(&frame.payload).unwrap()
+    };
+    assert_eq!(read()["type"], "subscribed");
+    let mut timestamps = vec![];
+    let ended = loop {
+      let frame = read();
+      match frame["record"]["timestamp"].as_i64() {
+        Some(timestamp) => timestamps.push(timestamp),
+        None => break frame,
+      }
+    };
+    assert_eq!(
+      ended,
+      serde_json::json!({
+        "type": "unsubscribed",
+        "trackers": ["test"],
+      })
+    );
+    assert!(timestamps.len() < count as usize);
+    assert_eq!(timestamps, (0..timestamps.len() as i64).collect::<Vec<_>>());
+  }
+}